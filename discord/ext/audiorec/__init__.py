@@ -6,7 +6,17 @@ Discord Audio Record Extension
 :license: MIT
 """
 
-from .voice_client import NativeVoiceClient
+from .voice_client import (
+    EncryptionMode,
+    NativeVoiceClient,
+    active_player_count,
+    active_recorder_count,
+    channel_encoder_settings,
+    media_connection,
+    probe,
+    set_max_players,
+    supported_encryption_modes,
+)
 
 __title__ = 'discord-ext-audiorec'
 __author__ = 'Tomoya Ishii'
@@ -1,5 +1,5 @@
 import asyncio
-from typing import Callable, Dict
+from typing import Any, Callable, Dict, List, Optional, Tuple, Union
 
 class MissingFieldError(Exception):
     pass
@@ -19,40 +19,298 @@ class GatewayError(Exception):
 class TryReconnect(Exception):
     pass
 
+class SessionInvalidated(TryReconnect):
+    pass
+
+class EncryptionFailed(Exception):
+    pass
+
+class ConnectionCancelled(Exception):
+    pass
+
+class TooManyPlayers(Exception):
+    pass
+
+class UdpDiscoveryError(Exception):
+    pass
+
+class UdpDiscoveryTimeout(UdpDiscoveryError):
+    pass
+
+def active_player_count() -> int: ...
+
+def active_recorder_count() -> int: ...
+
+def set_max_players(limit: Optional[int]) -> None: ...
+
+def channel_encoder_settings(channel_bitrate: int) -> Dict[str, Any]: ...
+
+def probe(input: str) -> Dict[str, Any]: ...
+
+def supported_encryption_modes() -> List[str]: ...
+
+class RecordStream:
+    def __aiter__(self) -> RecordStream: ...
+
+    async def __anext__(self) -> bytes: ...
+
 class VoiceConnection:
-    async def run(self, loop_: asyncio.AbstractEventLoop) -> None: ...
+    @staticmethod
+    def from_media(state: Dict) -> VoiceConnection: ...
+
+    def __repr__(self) -> str: ...
+
+    def __enter__(self) -> VoiceConnection: ...
+
+    def __exit__(self, exc_type: Any, exc_value: Any, traceback: Any) -> bool: ...
+
+    async def __aenter__(self) -> VoiceConnection: ...
+
+    async def __aexit__(self, exc_type: Any, exc_value: Any, traceback: Any) -> bool: ...
+
+    async def run(self, loop_: asyncio.AbstractEventLoop) -> int: ...
 
     def disconnect(self) -> None: ...
 
+    async def shutdown(self, loop_: asyncio.AbstractEventLoop) -> None: ...
+
+    async def reconnect(
+        self,
+        loop_: asyncio.AbstractEventLoop,
+        timeout: Optional[float] = ...,
+    ) -> None: ...
+
     def stop(self) -> None: ...
 
     def pause(self) -> None: ...
 
     def resume(self) -> None: ...
 
+    def start_loopback(self) -> None: ...
+
+    async def stop_loopback(self, loop_: asyncio.AbstractEventLoop) -> Optional[bytes]: ...
+
+    def mute(self) -> None: ...
+
+    def unmute(self) -> None: ...
+
+    def set_fec(self, enabled: bool) -> None: ...
+
     def is_playing(self) -> bool: ...
     
     def is_recording(self) -> bool: ...
 
-    def send_playing(self) -> None: ...
+    def send_playing(self, delay: int = ...) -> None: ...
+
+    def play(
+        self,
+        input: str,
+        after: Callable[[Exception], None],
+        bandwidth: Optional[str] = ...,
+        max_bandwidth: Optional[str] = ...,
+        bitrate: Optional[Union[int, str]] = ...,
+        profile: Optional[str] = ...,
+        complexity: Optional[int] = ...,
+        disable_prediction: Optional[bool] = ...,
+        bitrate_ramp_ms: Optional[int] = ...,
+        signal: Optional[str] = ...,
+        channel_bitrate: Optional[int] = ...,
+        vbr: Optional[bool] = ...,
+        vbr_constraint: Optional[bool] = ...,
+    ) -> None: ...
+
+    def play_file(
+        self,
+        path: str,
+        after: Callable[[Exception], None],
+        bandwidth: Optional[str] = ...,
+        max_bandwidth: Optional[str] = ...,
+        bitrate: Optional[Union[int, str]] = ...,
+        profile: Optional[str] = ...,
+        complexity: Optional[int] = ...,
+        disable_prediction: Optional[bool] = ...,
+        bitrate_ramp_ms: Optional[int] = ...,
+        signal: Optional[str] = ...,
+        channel_bitrate: Optional[int] = ...,
+        vbr: Optional[bool] = ...,
+        vbr_constraint: Optional[bool] = ...,
+    ) -> None: ...
+
+    def play_opus(self, path: str, after: Callable[[Exception], None]) -> None: ...
+
+    def play_stream(
+        self,
+        after: Callable[[Exception], None],
+        bandwidth: Optional[str] = ...,
+        max_bandwidth: Optional[str] = ...,
+        bitrate: Optional[Union[int, str]] = ...,
+        profile: Optional[str] = ...,
+        complexity: Optional[int] = ...,
+        disable_prediction: Optional[bool] = ...,
+        bitrate_ramp_ms: Optional[int] = ...,
+        signal: Optional[str] = ...,
+        channel_bitrate: Optional[int] = ...,
+        vbr: Optional[bool] = ...,
+        vbr_constraint: Optional[bool] = ...,
+    ) -> None: ...
+
+    def feed_pcm(self, data: bytes) -> None: ...
+
+    def finish_stream(self) -> None: ...
 
-    def play(self, input: str, after: Callable[[Exception], None]) -> None: ...
+    def record(
+        self,
+        after: Callable[[Exception], None],
+        mix: str = ...,
+        channels: int = ...,
+        sample_rate: int = ...,
+        pause_mode: str = ...,
+        loss_mode: str = ...,
+        layout: str = ...,
+        rtp_callback: Optional[Callable[[List[Dict]], None]] = ...,
+        rtp_batch_size: int = ...,
+        recv_batch_size: int = ...,
+        decoder_gain_db: float = ...,
+        writer: Optional[Any] = ...,
+        output_rate: Optional[int] = ...,
+        max_reorder_ms: Optional[int] = ...,
+        format: str = ...,
+        bitrate: int = ...,
+        comfort_noise: bool = ...,
+        comfort_noise_db: float = ...,
+        max_speakers: Optional[int] = ...,
+        preroll_secs: Optional[float] = ...,
+    ) -> None: ...
+
+    def start_preroll(self, seconds: float) -> None: ...
+
+    def stop_preroll(self) -> None: ...
+
+    def pause_record(self) -> None: ...
+
+    def resume_record(self) -> None: ...
+
+    def is_record_paused(self) -> bool: ...
+
+    def flush(self) -> None: ...
+
+    def set_record_gain(self, speaker: Union[int, str], gain: float) -> None: ...
+
+    def try_stop_record(self) -> bool: ...
+
+    async def stop_record(
+        self,
+        loop_: asyncio.AbstractEventLoop,
+        split_on_silence: Optional[float] = ...,
+        checksum: bool = ...,
+        layout: Optional[str] = ...,
+        vtt: bool = ...,
+        speakers: bool = ...,
+    ) -> Union[
+        bytes,
+        Tuple[bytes, str],
+        Tuple[Tuple[bytes, str], str],
+        int,
+        Tuple[int, str],
+        Tuple[Tuple[int, str], str],
+        List[Tuple[float, bytes]],
+        List[Tuple[float, bytes, str]],
+        Tuple[Any, List[Dict]],
+        Tuple[Any, str, List[Dict]],
+    ]: ...
 
-    def record(self, after: Callable[[Exception], None]) -> None: ...
+    def start_live_record(
+        self,
+        callback: Callable[[bytes], None],
+        interval_ms: int = ...,
+        mix: str = ...,
+        channels: int = ...,
+        sample_rate: int = ...,
+        max_reorder_ms: Optional[int] = ...,
+    ) -> None: ...
+
+    def stream_record(
+        self,
+        loop_: asyncio.AbstractEventLoop,
+        interval_ms: int = ...,
+        mix: str = ...,
+        channels: int = ...,
+        sample_rate: int = ...,
+        max_reorder_ms: Optional[int] = ...,
+    ) -> RecordStream: ...
+
+    def stop_live_record(self) -> None: ...
+
+    def start_level_meter(
+        self,
+        callback: Callable[[Dict[int, Dict[str, Any]]], Any],
+        interval_ms: int = ...,
+    ) -> None: ...
+
+    def stop_level_meter(self) -> None: ...
+
+    async def checkpoint_record(
+        self,
+        loop_: asyncio.AbstractEventLoop,
+        layout: Optional[str] = ...,
+    ) -> bytes: ...
+
+    async def snapshot_record(
+        self,
+        loop_: asyncio.AbstractEventLoop,
+        window: Optional[float] = ...,
+        layout: Optional[str] = ...,
+    ) -> bytes: ...
 
-    async def stop_record(self, loop_: asyncio.AbstractEventLoop) -> bytes: ...
+    def recorded_speakers(self) -> List[Dict]: ...
+
+    def decrypt_packet(self, packet: bytes) -> Optional[Tuple[Dict, bytes]]: ...
+
+    def active_speakers(self, window_ms: int = ...) -> List[Dict]: ...
+
+    def recorded_ssrcs(self) -> List[int]: ...
+
+    def record_stats(self) -> Dict: ...
+
+    def play_stats(self) -> Dict: ...
 
     def get_state(self) -> Dict: ...
 
+    def health(self) -> Dict: ...
+
+    def session_state(self) -> Dict: ...
+
     @property
     def latency(self) -> float: ...
 
     @property
     def average_latency(self) -> float: ...
 
+    def send_heartbeat(self) -> None: ...
+
+    def connection_id(self) -> int: ...
+
+    def encryption_mode(self) -> int: ...
+
+    def set_send_timeout(self, timeout_ms: int) -> None: ...
+
+    def on_latency_spike(
+        self,
+        threshold_ms: float,
+        callback: Optional[Callable[[float], Any]],
+    ) -> None: ...
+
+    def on_media_timeout(
+        self,
+        timeout_ms: int,
+        callback: Optional[Callable[[float], Any]],
+    ) -> None: ...
+
 class VoiceConnector:
     session_id: str
     user_id: str
+    proxy: Optional[str]
+    dscp: Optional[int]
 
     @property
     def server_id(self) -> str: ...
@@ -70,6 +328,28 @@ class VoiceConnector:
     ) -> None:
         ...
 
-    async def connect(self, loop_: asyncio.AbstractEventLoop) -> VoiceConnection: ...
+    async def connect(
+        self,
+        loop_: asyncio.AbstractEventLoop,
+        progress: Optional[Callable[[str], None]] = ...,
+        resume_from: Optional[Dict] = ...,
+        timeout: Optional[float] = ...,
+        retries: int = ...,
+        backoff_ms: Optional[int] = ...,
+    ) -> VoiceConnection: ...
+
+    def set_backoff(
+        self,
+        base_ms: int,
+        max_ms: int,
+        multiplier: float,
+        jitter: float,
+    ) -> None: ...
+
+    def next_backoff(self) -> float: ...
+
+    def reset_backoff(self) -> None: ...
+
+    def cancel_connect(self) -> None: ...
 
     async def disconnect(self) -> None: ...
\ No newline at end of file
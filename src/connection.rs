@@ -1,56 +1,131 @@
-use std::{sync::Arc, thread};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{atomic::Ordering, Arc},
+    thread, time,
+};
 
 use parking_lot::Mutex;
 use pyo3::{
+    exceptions::PyStopAsyncIteration,
     prelude::*,
-    types::{PyBytes, PyDict, PyTuple},
+    types::{PyBytes, PyDict, PyList, PyTuple},
+    PyAsyncProtocol, PyObjectProtocol,
 };
+use sha2::{Digest, Sha256};
 
 use crate::{
-    error::DiscordError,
+    error::{DiscordError, CLEAN_CLOSE_CODES, SESSION_INVALID_CODES},
     futures,
-    payload::SpeakingType,
-    player::{AudioPlayer, FFmpegAudio},
-    recorder::{AudioDecoder, AudioRecorder, SsrcPacketQueue},
-    state::ConnectionState,
-    ws::{VoiceGateway, VoiceGatewayBuilder},
+    live::{LevelMeter, LiveTap, SpeakerLevel, LEVEL_INTERVAL_MS, MAX_PENDING_CHUNKS},
+    loopback::{Loopback, SharedLoopback},
+    mp3::RecordFormat,
+    opus::{DcaAudio, OggOpusAudio},
+    payload::{EncryptionMode, SpeakingType},
+    player::{
+        AudioInput, AudioPlayer, AudioSource, Bitrate, ChannelSettings, EncoderCommand,
+        EncoderOptions, EncoderProfile, FFmpegAudio, PcmStream, WavFileAudio,
+        FRAME_HISTOGRAM_BUCKET, FRAME_LENGTH,
+    },
+    recorder::{
+        decrypt_packet, sha256_hex, wav_stream_header, AudioDecoder, AudioRecorder, Layout,
+        MediaWatch, RecordGains, RecordOptions, RecordStats, RtpHeader, RtpHeaderSink, Speaker,
+        SsrcPacketQueue, MAX_PREROLL_SECS,
+    },
+    state::{ConnectionState, ACTIVE_PLAYERS, ACTIVE_RECORDERS, MAX_PLAYERS},
+    ws::{gateway_host, ConnectCancel, SessionState, VoiceGateway, VoiceGatewayBuilder},
 };
 
+const JOIN_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
 #[pyclass]
 pub(crate) struct VoiceConnection {
     gateway: Arc<Mutex<VoiceGateway>>,
     queue: Arc<Mutex<SsrcPacketQueue>>,
     player: Option<AudioPlayer>,
+    /// The feeding end of the source started by `play_stream`.
+    stream: Option<PcmStream>,
     recorder: Arc<Mutex<Option<AudioRecorder>>>,
+    gains: Arc<Mutex<RecordGains>>,
+    live_tap: Option<LiveTap>,
+    /// Whether the recorder was started by the live tap, which stops it
+    /// along with itself.
+    live_recorder: bool,
+    /// File-like object given to `record` that `stop_record` writes into.
+    writer: Option<PyObject>,
+    /// Called by `run` when a heartbeat exceeds the latency threshold.
+    latency_callback: Arc<Mutex<Option<PyObject>>>,
+    media_watch: Arc<Mutex<MediaWatch>>,
+    /// Called by `run` when the recorder reports a media timeout.
+    media_callback: Arc<Mutex<Option<PyObject>>>,
+    /// Seconds kept by the pre-roll buffer, if `start_preroll` was called.
+    preroll: Option<f64>,
+    /// Whether the recorder only fills the pre-roll buffer, as opposed to
+    /// a recording started by `record`.
+    prerolling: bool,
+    /// Frames sent by the players since `start_loopback`.
+    loopback: SharedLoopback,
+    /// Callback and interval given to `start_level_meter`, kept so that the
+    /// meter follows the queue of a new recorder.
+    level_callback: Option<(PyObject, time::Duration)>,
+    level_meter: Option<LevelMeter>,
 }
 
 #[pymethods]
 impl VoiceConnection {
+    /// Makes a connection for a media session negotiated elsewhere, from its
+    /// `endpoint_ip`, `port`, `ssrc`, `secret_key` and `encryption_mode`.
+    /// Playing and recording work as usual, but nothing is sent on the
+    /// websocket, so speaking updates are up to the caller.
+    #[staticmethod]
+    #[text_signature = "(state, /)"]
+    fn from_media(state: &PyDict) -> PyResult<VoiceConnection> {
+        let session = media_from_dict(state)?;
+        Ok(VoiceConnection::new(VoiceGateway::detached(&session)?))
+    }
+
     #[text_signature = "(loop, /)"]
     fn run(&mut self, py: Python, loop_: PyObject) -> PyResult<PyObject> {
-        let (ftr, res): (PyObject, PyObject) = {
-            let ftr = loop_.call_method0(py, "create_future")?;
-            (ftr.clone_ref(py), ftr)
-        };
+        let (ftr, res) = futures::create_future(py, &loop_)?;
 
         let gateway = Arc::clone(&self.gateway);
+        let latency_callback = Arc::clone(&self.latency_callback);
+        let media_watch = Arc::clone(&self.media_watch);
+        let media_callback = Arc::clone(&self.media_callback);
         thread::spawn(move || loop {
-            let result = {
+            // The callback needs the GIL, which must not be acquired while
+            // the gateway is locked, so the spike is only taken here.
+            let (result, spike) = {
                 let mut lock = gateway.lock();
-                lock.poll()
+                let result = lock.poll();
+                (result, lock.take_latency_spike())
             };
+            let silence = media_watch.lock().take_timeout();
             let gil = Python::acquire_gil();
             let py = gil.python();
+            if let (Some(rtt), Some(callback)) = (spike, &*latency_callback.lock()) {
+                if let Err(e) = futures::call_soon(py, &loop_, callback, rtt.to_object(py)) {
+                    error!("Failed to schedule the latency callback: {}", e);
+                }
+            }
+            if let (Some(silence), Some(callback)) = (silence, &*media_callback.lock()) {
+                let silence = silence.as_secs_f64().to_object(py);
+                if let Err(e) = futures::call_soon(py, &loop_, callback, silence) {
+                    error!("Failed to schedule the media timeout callback: {}", e);
+                }
+            }
             if let Err(e) = py.check_signals() {
                 error!("Python Signal Error: {}", e);
                 let _ = futures::set_exception(py, loop_, ftr, e);
                 break;
             } else if let Err(e) = result {
                 match e {
+                    // Resolved with the code so that the caller can tell a
+                    // normal close (1000) from being disconnected from the
+                    // channel (4014) or a crash of the voice server (4015).
                     DiscordError::ConnectionClosed(code)
-                        if code != 1000 && code != 4014 && code != 4015 =>
+                        if !SESSION_INVALID_CODES.contains(&code) =>
                     {
-                        let _ = futures::set_result(py, loop_, ftr, py.None());
+                        let _ = futures::set_result(py, loop_, ftr, code.to_object(py));
                         break;
                     }
                     _ => {
@@ -64,12 +139,91 @@ impl VoiceConnection {
         Ok(res)
     }
 
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        py: Python,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> bool {
+        let workers = self.take_workers();
+        let gateway = Arc::clone(&self.gateway);
+        py.allow_threads(|| workers.shut_down(&gateway));
+        false
+    }
+
+    fn __aenter__(slf: PyRef<Self>, py: Python) -> PyResult<PyObject> {
+        let ftr = completed_future(py, slf.into_py(py))?;
+        Ok(ftr)
+    }
+
+    fn __aexit__(
+        &mut self,
+        py: Python,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<PyObject> {
+        // Joining the threads must not block the event loop.
+        let loop_ = py.import("asyncio")?.call0("get_running_loop")?;
+        self.spawn_shutdown(py, loop_.into(), false.into_py(py))
+    }
+
     fn disconnect(&mut self) -> PyResult<()> {
         let mut lock = self.gateway.lock();
         lock.close(1000)?;
         Ok(())
     }
 
+    /// Stops the player, discards the recording, clears speaking and closes
+    /// the gateway, in that order, so that no thread is left using a closed
+    /// connection. Resolves once every thread has exited.
+    #[text_signature = "(loop, /)"]
+    fn shutdown(&mut self, py: Python, loop_: PyObject) -> PyResult<PyObject> {
+        self.spawn_shutdown(py, loop_, py.None())
+    }
+
+    /// Replaces the gateway connection and UDP socket with new ones,
+    /// resuming the session if possible. The player and the recorder keep
+    /// running on the new connection.
+    #[text_signature = "(loop, timeout=None, /)"]
+    #[args(timeout = "None")]
+    fn reconnect(
+        &mut self,
+        py: Python,
+        loop_: PyObject,
+        timeout: Option<f64>,
+    ) -> PyResult<PyObject> {
+        let timeout = connect_timeout(timeout)?;
+        let (ftr, res) = futures::create_future(py, &loop_)?;
+
+        let gateway = Arc::clone(&self.gateway);
+        thread::spawn(move || {
+            // The lock is held throughout so that `run` does not poll the
+            // connection being replaced.
+            let result = gateway.lock().reconnect(timeout);
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            if let Err(e) = py.check_signals() {
+                let _ = futures::set_exception(py, loop_, ftr, e);
+                return;
+            }
+            match result {
+                Ok(()) => {
+                    let _ = futures::set_result(py, loop_, ftr, py.None());
+                }
+                Err(e) => {
+                    let _ = futures::set_exception(py, loop_, ftr, PyErr::from(e));
+                }
+            }
+        });
+        Ok(res)
+    }
+
     fn stop(&mut self) {
         if let Some(player) = &self.player {
             player.stop();
@@ -88,6 +242,53 @@ impl VoiceConnection {
         }
     }
 
+    /// Starts capturing the frames the players send, dropping any captured
+    /// before.
+    fn start_loopback(&self) {
+        *self.loopback.lock() = Some(Loopback::default());
+    }
+
+    /// Stops capturing and resolves to the WAV file of the frames sent since
+    /// `start_loopback`, or `None` if it was not called.
+    #[text_signature = "(loop, /)"]
+    fn stop_loopback(&self, py: Python, loop_: PyObject) -> PyResult<PyObject> {
+        let (ftr, res) = futures::create_future(py, &loop_)?;
+        let loopback = self.loopback.lock().take();
+        thread::spawn(move || {
+            let result = loopback.map(|loopback| loopback.decode()).transpose();
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            match result {
+                Ok(data) => {
+                    let data = data.map(|data| PyBytes::new(py, &data).to_object(py));
+                    let _ = futures::set_result(py, loop_, ftr, data.to_object(py));
+                }
+                Err(e) => {
+                    let _ = futures::set_exception(py, loop_, ftr, PyErr::from(e));
+                }
+            }
+        });
+        Ok(res)
+    }
+
+    fn mute(&self) {
+        if let Some(player) = &self.player {
+            player.mute();
+        }
+    }
+
+    fn unmute(&self) {
+        if let Some(player) = &self.player {
+            player.unmute();
+        }
+    }
+
+    fn set_fec(&self, enabled: bool) {
+        if let Some(player) = &self.player {
+            player.send_command(EncoderCommand::Fec(enabled));
+        }
+    }
+
     fn is_playing(&self) -> bool {
         if let Some(player) = &self.player {
             player.is_playing()
@@ -97,6 +298,9 @@ impl VoiceConnection {
     }
 
     fn is_recording(&self) -> bool {
+        if self.prerolling {
+            return false;
+        }
         if let Some(recoder) = &*self.recorder.lock() {
             recoder.is_recording()
         } else {
@@ -104,57 +308,678 @@ impl VoiceConnection {
         }
     }
 
-    fn send_playing(&self) -> PyResult<()> {
+    #[args(delay = "0")]
+    fn send_playing(&self, delay: u32) -> PyResult<()> {
         let mut lock = self.gateway.lock();
-        lock.speaking(SpeakingType::MICROPHONE)?;
+        lock.speaking(SpeakingType::MICROPHONE, delay)?;
         Ok(())
     }
 
-    fn play(&mut self, input: String, after: PyObject) -> PyResult<()> {
-        if let Some(player) = &self.player {
-            player.stop();
+    #[args(
+        bandwidth = "None",
+        max_bandwidth = "None",
+        bitrate = "None",
+        profile = "None",
+        complexity = "None",
+        disable_prediction = "None",
+        bitrate_ramp_ms = "None",
+        signal = "None",
+        channel_bitrate = "None",
+        vbr = "None",
+        vbr_constraint = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn play(
+        &mut self,
+        py: Python,
+        input: String,
+        after: PyObject,
+        bandwidth: Option<&str>,
+        max_bandwidth: Option<&str>,
+        bitrate: Option<&PyAny>,
+        profile: Option<&str>,
+        complexity: Option<u8>,
+        disable_prediction: Option<bool>,
+        bitrate_ramp_ms: Option<u32>,
+        signal: Option<&str>,
+        channel_bitrate: Option<u32>,
+        vbr: Option<bool>,
+        vbr_constraint: Option<bool>,
+    ) -> PyResult<()> {
+        let options = encoder_options(
+            profile,
+            bandwidth,
+            max_bandwidth,
+            bitrate,
+            complexity,
+            disable_prediction,
+            bitrate_ramp_ms,
+            signal,
+            channel_bitrate,
+            vbr,
+            vbr_constraint,
+        )?;
+        self.check_ready()?;
+        self.check_player_limit()?;
+        let source = AudioSource::Pcm(Box::new(FFmpegAudio::new(&input)?));
+        self.start_player(py, source, after, options);
+        Ok(())
+    }
+
+    /// Plays `.wav` files directly and anything else through ffmpeg.
+    #[args(
+        bandwidth = "None",
+        max_bandwidth = "None",
+        bitrate = "None",
+        profile = "None",
+        complexity = "None",
+        disable_prediction = "None",
+        bitrate_ramp_ms = "None",
+        signal = "None",
+        channel_bitrate = "None",
+        vbr = "None",
+        vbr_constraint = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn play_file(
+        &mut self,
+        py: Python,
+        path: String,
+        after: PyObject,
+        bandwidth: Option<&str>,
+        max_bandwidth: Option<&str>,
+        bitrate: Option<&PyAny>,
+        profile: Option<&str>,
+        complexity: Option<u8>,
+        disable_prediction: Option<bool>,
+        bitrate_ramp_ms: Option<u32>,
+        signal: Option<&str>,
+        channel_bitrate: Option<u32>,
+        vbr: Option<bool>,
+        vbr_constraint: Option<bool>,
+    ) -> PyResult<()> {
+        let options = encoder_options(
+            profile,
+            bandwidth,
+            max_bandwidth,
+            bitrate,
+            complexity,
+            disable_prediction,
+            bitrate_ramp_ms,
+            signal,
+            channel_bitrate,
+            vbr,
+            vbr_constraint,
+        )?;
+        self.check_ready()?;
+        self.check_player_limit()?;
+        let is_wav = std::path::Path::new(&path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"));
+        let source: Box<dyn AudioInput> = if is_wav {
+            Box::new(WavFileAudio::new(&path)?)
+        } else {
+            Box::new(FFmpegAudio::new(&path)?)
+        };
+        self.start_player(py, source.into(), after, options);
+        Ok(())
+    }
+
+    /// Plays an Opus file as it is, without decoding and encoding it again.
+    /// DCA files (`.dca`) and Ogg Opus files (`.opus`, `.ogg`) are supported.
+    #[text_signature = "(path, after, /)"]
+    fn play_opus(&mut self, py: Python, path: String, after: PyObject) -> PyResult<()> {
+        let extension = std::path::Path::new(&path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        self.check_ready()?;
+        self.check_player_limit()?;
+        let source = match extension.as_deref() {
+            Some("dca") => AudioSource::Opus(Box::new(DcaAudio::new(&path)?)),
+            Some("opus") | Some("ogg") => AudioSource::Opus(Box::new(OggOpusAudio::new(&path)?)),
+            _ => {
+                return Err(DiscordError::InvalidArgument(format!(
+                    "Unsupported Opus file: {} (expected .dca, .opus or .ogg)",
+                    path
+                ))
+                .into())
+            }
+        };
+        self.start_player(py, source, after, EncoderOptions::default());
+        Ok(())
+    }
+
+    /// Plays 48kHz stereo 16-bit PCM fed with `feed_pcm`. Silence is played
+    /// while nothing has been fed; the player only finishes once the stream
+    /// is ended with `finish_stream` or by feeding empty bytes.
+    #[args(
+        bandwidth = "None",
+        max_bandwidth = "None",
+        bitrate = "None",
+        profile = "None",
+        complexity = "None",
+        disable_prediction = "None",
+        bitrate_ramp_ms = "None",
+        signal = "None",
+        channel_bitrate = "None",
+        vbr = "None",
+        vbr_constraint = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn play_stream(
+        &mut self,
+        py: Python,
+        after: PyObject,
+        bandwidth: Option<&str>,
+        max_bandwidth: Option<&str>,
+        bitrate: Option<&PyAny>,
+        profile: Option<&str>,
+        complexity: Option<u8>,
+        disable_prediction: Option<bool>,
+        bitrate_ramp_ms: Option<u32>,
+        signal: Option<&str>,
+        channel_bitrate: Option<u32>,
+        vbr: Option<bool>,
+        vbr_constraint: Option<bool>,
+    ) -> PyResult<()> {
+        let options = encoder_options(
+            profile,
+            bandwidth,
+            max_bandwidth,
+            bitrate,
+            complexity,
+            disable_prediction,
+            bitrate_ramp_ms,
+            signal,
+            channel_bitrate,
+            vbr,
+            vbr_constraint,
+        )?;
+        self.check_ready()?;
+        self.check_player_limit()?;
+        let stream = PcmStream::default();
+        let source = AudioSource::Pcm(Box::new(stream.source()));
+        self.start_player(py, source, after, options);
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    #[text_signature = "(data, /)"]
+    fn feed_pcm(&self, data: &[u8]) -> PyResult<()> {
+        match &self.stream {
+            Some(stream) => Ok(stream.feed(data)?),
+            None => {
+                Err(DiscordError::InvalidArgument("No stream is being played".to_string()).into())
+            }
+        }
+    }
+
+    /// Ends the stream of `play_stream` once the audio fed so far is played.
+    fn finish_stream(&self) {
+        if let Some(stream) = &self.stream {
+            stream.end();
         }
+    }
 
-        let source = Box::new(FFmpegAudio::new(&input)?);
-        let player = AudioPlayer::new(
+    #[args(
+        mix = "\"constant_power\"",
+        channels = "2",
+        sample_rate = "48000",
+        pause_mode = "\"elide\"",
+        loss_mode = "\"fec\"",
+        layout = "\"mixed\"",
+        rtp_callback = "None",
+        rtp_batch_size = "50",
+        recv_batch_size = "1",
+        decoder_gain_db = "0.0",
+        writer = "None",
+        output_rate = "None",
+        max_reorder_ms = "None",
+        format = "\"wav\"",
+        bitrate = "128",
+        comfort_noise = "false",
+        comfort_noise_db = "-60.0",
+        max_speakers = "None",
+        preroll_secs = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn record(
+        &mut self,
+        py: Python,
+        after: PyObject,
+        mix: &str,
+        channels: u16,
+        sample_rate: u32,
+        pause_mode: &str,
+        loss_mode: &str,
+        layout: &str,
+        rtp_callback: Option<PyObject>,
+        rtp_batch_size: usize,
+        recv_batch_size: usize,
+        decoder_gain_db: f32,
+        writer: Option<PyObject>,
+        output_rate: Option<u32>,
+        max_reorder_ms: Option<u32>,
+        format: &str,
+        bitrate: u32,
+        comfort_noise: bool,
+        comfort_noise_db: f32,
+        max_speakers: Option<usize>,
+        preroll_secs: Option<f64>,
+    ) -> PyResult<()> {
+        let options = RecordOptions {
+            mix: mix.parse()?,
+            pause_mode: pause_mode.parse()?,
+            loss_mode: loss_mode.parse()?,
+            layout: layout.parse()?,
+            channels,
+            sample_rate,
+            recv_batch_size,
+            decoder_gain_db,
+            output_rate,
+            max_reorder_ms,
+            format: format.parse()?,
+            mp3_bitrate: bitrate,
+            comfort_noise_db: if comfort_noise {
+                Some(comfort_noise_db)
+            } else {
+                None
+            },
+            max_speakers,
+        };
+        options.validate()?;
+        if let Some(secs) = preroll_secs {
+            let buffered = match self.preroll {
+                Some(buffered) if self.prerolling => buffered,
+                _ => {
+                    return Err(DiscordError::InvalidArgument(
+                        "preroll_secs requires start_preroll to be called first".to_string(),
+                    )
+                    .into())
+                }
+            };
+            if !(secs.is_finite() && secs > 0.0 && secs <= buffered) {
+                return Err(DiscordError::InvalidArgument(format!(
+                    "preroll_secs must be between 0 and the {} seconds buffered: {}",
+                    buffered, secs
+                ))
+                .into());
+            }
+        }
+        if let Some(writer) = &writer {
+            if !writer.as_ref(py).hasattr("write")? {
+                return Err(DiscordError::InvalidArgument(
+                    "writer must have a write method".to_string(),
+                )
+                .into());
+            }
+        }
+        if self.live_tap.is_some() {
+            return Err(DiscordError::InvalidArgument(
+                "record cannot start while a live record runs; call stop_live_record first"
+                    .to_string(),
+            )
+            .into());
+        }
+        self.check_ready()?;
+        self.writer = writer;
+        // The callback runs on the event loop, not on the recorder thread.
+        let loop_: PyObject = match rtp_callback {
+            Some(_) => py.import("asyncio")?.call0("get_event_loop")?.into(),
+            None => py.None(),
+        };
+        let rtp_sink = rtp_callback.map(|callback| {
+            RtpHeaderSink::new(
+                move |headers: Vec<RtpHeader>| {
+                    let gil = Python::acquire_gil();
+                    let py = gil.python();
+                    let result = rtp_headers_to_list(py, &headers).and_then(|list| {
+                        futures::call_soon(py, &loop_, &callback, list.to_object(py))
+                    });
+                    if let Err(e) = result {
+                        e.print(py);
+                    }
+                },
+                rtp_batch_size,
+            )
+        });
+        self.start_recorder(
+            py,
             move |err| {
                 let gil = Python::acquire_gil();
                 let py = gil.python();
                 let _ = after.call1(py, PyTuple::new(py, [err].iter()));
             },
-            Arc::clone(&self.gateway),
-            Arc::new(Mutex::new(source)),
+            options,
+            rtp_sink,
+            preroll_secs,
         );
-        self.player = Some(player);
         Ok(())
     }
 
-    fn record(&mut self, after: PyObject) {
-        if let Some(recorder) = &*self.recorder.lock() {
-            recorder.stop();
+    /// Keeps receiving voice while nothing is recorded, holding the last
+    /// `seconds` of it so that `record(preroll_secs=...)` can start from
+    /// before it was called. The buffer is only filled while no recording
+    /// runs; it is refilled by calling this again after `stop_record`.
+    #[text_signature = "(seconds, /)"]
+    fn start_preroll(&mut self, py: Python, seconds: f64) -> PyResult<()> {
+        if !(seconds.is_finite() && seconds > 0.0 && seconds <= MAX_PREROLL_SECS) {
+            return Err(DiscordError::InvalidArgument(format!(
+                "seconds must be between 0 and {}: {}",
+                MAX_PREROLL_SECS, seconds
+            ))
+            .into());
+        }
+        self.check_ready()?;
+        self.preroll = Some(seconds);
+        if self.prerolling {
+            self.queue.lock().set_window(Some(seconds));
+        } else if !self.is_recording() {
+            self.start_recorder(
+                py,
+                |err| {
+                    if let Some(e) = err {
+                        error!("Pre-roll stopped: {}", e);
+                    }
+                },
+                RecordOptions::default(),
+                None,
+                None,
+            );
+            self.queue.lock().set_window(Some(seconds));
+            self.prerolling = true;
+        }
+        Ok(())
+    }
+
+    /// Stops filling the pre-roll buffer and discards it. A recording is
+    /// left running.
+    fn stop_preroll(&mut self, py: Python) {
+        self.preroll = None;
+        if !self.prerolling {
+            return;
+        }
+        self.prerolling = false;
+        let recorder = self.recorder.lock().take();
+        if let Some(recorder) = recorder {
+            py.allow_threads(|| recorder.stop_and_join(JOIN_TIMEOUT));
         }
         self.queue = Arc::new(Mutex::new(SsrcPacketQueue::new()));
-        let recorder = AudioRecorder::new(
-            move |err| {
+        self.restart_level_meter(py);
+    }
+
+    #[args(
+        interval_ms = "200",
+        mix = "\"constant_power\"",
+        channels = "2",
+        sample_rate = "48000",
+        max_reorder_ms = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn start_live_record(
+        &mut self,
+        py: Python,
+        callback: PyObject,
+        interval_ms: u64,
+        mix: &str,
+        channels: u16,
+        sample_rate: u32,
+        max_reorder_ms: Option<u32>,
+    ) -> PyResult<()> {
+        let options = RecordOptions {
+            mix: mix.parse()?,
+            channels,
+            sample_rate,
+            max_reorder_ms,
+            ..Default::default()
+        };
+        options.validate()?;
+        self.check_ready()?;
+        self.check_no_record()?;
+        self.start_tap(
+            py,
+            move |pcm: Vec<i16>| {
                 let gil = Python::acquire_gil();
                 let py = gil.python();
-                let _ = after.call1(py, PyTuple::new(py, [err].iter()));
+                let _ = callback.call1(py, (PyBytes::new(py, &pcm_bytes(&pcm)),));
             },
-            Arc::clone(&self.gateway),
-            Arc::clone(&self.queue),
+            || {},
+            options,
+            interval_ms,
         );
-        self.recorder = Arc::new(Mutex::new(Some(recorder)));
+        Ok(())
+    }
+
+    /// Like `start_live_record`, but returns an async iterator that yields
+    /// each chunk from `loop`, and stops once `stop_live_record` is called.
+    #[text_signature = "(loop, interval_ms=200, mix=\"constant_power\", channels=2, sample_rate=48000, max_reorder_ms=None, /)"]
+    #[args(
+        interval_ms = "200",
+        mix = "\"constant_power\"",
+        channels = "2",
+        sample_rate = "48000",
+        max_reorder_ms = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn stream_record(
+        &mut self,
+        py: Python,
+        loop_: PyObject,
+        interval_ms: u64,
+        mix: &str,
+        channels: u16,
+        sample_rate: u32,
+        max_reorder_ms: Option<u32>,
+    ) -> PyResult<RecordStream> {
+        futures::validate_loop(py, &loop_)?;
+        let options = RecordOptions {
+            mix: mix.parse()?,
+            channels,
+            sample_rate,
+            max_reorder_ms,
+            ..Default::default()
+        };
+        options.validate()?;
+        self.check_ready()?;
+        self.check_no_record()?;
+        let stream = RecordStream {
+            loop_,
+            chunks: Arc::new(Mutex::new(StreamChunks::default())),
+        };
+        let (loop_, chunks) = (stream.loop_.clone_ref(py), Arc::clone(&stream.chunks));
+        let (end_loop, end_chunks) = (stream.loop_.clone_ref(py), Arc::clone(&stream.chunks));
+        self.start_tap(
+            py,
+            move |pcm: Vec<i16>| {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                StreamChunks::deliver(&chunks, py, &loop_, pcm_bytes(&pcm));
+            },
+            move || {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                end_chunks.lock().finish(py, &end_loop);
+            },
+            options,
+            interval_ms,
+        );
+        Ok(stream)
+    }
+
+    fn pause_record(&self) {
+        if self.prerolling {
+            return;
+        }
+        if let Some(recorder) = &*self.recorder.lock() {
+            recorder.pause();
+        }
+    }
+
+    fn resume_record(&self) {
+        if self.prerolling {
+            return;
+        }
+        if let Some(recorder) = &*self.recorder.lock() {
+            recorder.resume();
+        }
+    }
+
+    fn is_record_paused(&self) -> bool {
+        if let Some(recorder) = &*self.recorder.lock() {
+            recorder.is_paused()
+        } else {
+            false
+        }
     }
 
-    fn stop_record(&mut self, py: Python, loop_: PyObject) -> PyResult<PyObject> {
-        let (ftr, res): (PyObject, PyObject) = {
-            let ftr = loop_.call_method0(py, "create_future")?;
-            (ftr.clone_ref(py), ftr)
+    /// Sets the gain applied to a speaker when the recording is mixed.
+    /// `speaker` is an SSRC (`int`) or a user ID (`str`).
+    fn set_record_gain(&self, speaker: &PyAny, gain: f32) -> PyResult<()> {
+        let speaker = match speaker.extract::<u32>() {
+            Ok(ssrc) => Speaker::Ssrc(ssrc),
+            Err(_) => Speaker::User(speaker.extract::<String>()?),
         };
+        self.gains.lock().set(speaker, gain)?;
+        Ok(())
+    }
+
+    /// Discards the packets received by the recorder so far. The player
+    /// reads its source one frame at a time, so it has nothing to discard.
+    fn flush(&self) {
+        // The receiver and `checkpoint_record` only touch the queue under its
+        // lock, so a checkpoint gets either all of these packets or none.
+        self.queue.lock().clear();
+    }
+
+    /// Stops the reception of an active recording, without decoding it.
+    /// Returns `false` if nothing was being recorded, e.g. because another
+    /// caller stopped it first.
+    fn try_stop_record(&self, py: Python) -> bool {
+        if self.prerolling {
+            return false;
+        }
+        let recorder = Arc::clone(&self.recorder);
+        // `stop_record` decodes with the recorder locked.
+        py.allow_threads(|| match &*recorder.lock() {
+            Some(recorder) => recorder.try_stop(),
+            None => false,
+        })
+    }
+
+    /// Stops the live tap, and the recorder it started along with it.
+    fn stop_live_record(&mut self, py: Python) {
+        if let Some(tap) = self.live_tap.take() {
+            py.allow_threads(|| tap.stop_and_join(JOIN_TIMEOUT));
+        }
+        if !self.live_recorder {
+            return;
+        }
+        self.live_recorder = false;
+        let recorder = self.recorder.lock().take();
+        if let Some(recorder) = recorder {
+            py.allow_threads(|| recorder.stop_and_join(JOIN_TIMEOUT));
+        }
+        self.queue = Arc::new(Mutex::new(SsrcPacketQueue::new()));
+        self.restart_level_meter(py);
+    }
+
+    /// Calls `callback` every `interval_ms` from a background thread with
+    /// the RMS and peak level of each speaker over that interval, as a dict
+    /// of SSRC to `{"user_id", "rms", "peak"}`, while recording or filling
+    /// the pre-roll buffer. Only the busiest speakers are measured. Nothing
+    /// is taken from the recording, but the packets a live record has
+    /// already taken are not measured.
+    #[text_signature = "(callback, interval_ms=100, /)"]
+    #[args(interval_ms = "100")]
+    fn start_level_meter(
+        &mut self,
+        py: Python,
+        callback: PyObject,
+        interval_ms: u64,
+    ) -> PyResult<()> {
+        if !LEVEL_INTERVAL_MS.contains(&interval_ms) {
+            return Err(DiscordError::InvalidArgument(format!(
+                "interval_ms must be between {} and {}: {}",
+                LEVEL_INTERVAL_MS.start(),
+                LEVEL_INTERVAL_MS.end(),
+                interval_ms
+            ))
+            .into());
+        }
+        self.check_ready()?;
+        self.level_callback = Some((callback, time::Duration::from_millis(interval_ms)));
+        self.restart_level_meter(py);
+        Ok(())
+    }
+
+    fn stop_level_meter(&mut self, py: Python) {
+        self.level_callback = None;
+        if let Some(meter) = self.level_meter.take() {
+            py.allow_threads(|| meter.stop_and_join(JOIN_TIMEOUT));
+        }
+    }
+
+    /// Stops recording and resolves to the WAV file, or with
+    /// `split_on_silence` to a list of `(start_offset, wav)` utterances
+    /// separated by more than that many seconds of silence.
+    /// With `checksum` the SHA-256 digest of each WAV file is added after it.
+    /// `layout` replaces the layout given to `record`.
+    /// If `record` was given a writer, the WAV file is written into it instead
+    /// and the number of bytes written takes its place in the result.
+    /// With `vtt` the result is paired with a WebVTT file of its utterances.
+    /// With `speakers` the speakers of `recorded_speakers` are added after
+    /// them.
+    #[text_signature = "(loop, split_on_silence=None, checksum=False, layout=None, vtt=False, speakers=False, /)"]
+    #[args(
+        split_on_silence = "None",
+        checksum = "false",
+        layout = "None",
+        vtt = "false",
+        speakers = "false"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn stop_record(
+        &mut self,
+        py: Python,
+        loop_: PyObject,
+        split_on_silence: Option<f64>,
+        checksum: bool,
+        layout: Option<&str>,
+        vtt: bool,
+        speakers: bool,
+    ) -> PyResult<PyObject> {
+        let layout = layout.map(str::parse::<Layout>).transpose()?;
+        if let Some(min_gap) = split_on_silence {
+            if !(min_gap.is_finite() && min_gap > 0.0) {
+                return Err(DiscordError::InvalidArgument(format!(
+                    "split_on_silence must be a positive number of seconds, got {}",
+                    min_gap
+                ))
+                .into());
+            }
+            if self.writer.is_some() {
+                return Err(DiscordError::InvalidArgument(
+                    "split_on_silence cannot be used with a writer".to_string(),
+                )
+                .into());
+            }
+            if vtt {
+                return Err(DiscordError::InvalidArgument(
+                    "split_on_silence cannot be used with vtt".to_string(),
+                )
+                .into());
+            }
+        }
+        let (ftr, res) = futures::create_future(py, &loop_)?;
 
         let gateway = Arc::clone(&self.gateway);
         let queue = Arc::clone(&self.queue);
         let recorder = Arc::clone(&self.recorder);
+        let gains = self.gains.lock().clone();
+        let writer = self.writer.take();
+        // Without a recording, the pre-roll buffer is what gets decoded.
+        self.prerolling = false;
 
         let state = {
             let gateway = gateway.lock();
@@ -169,12 +994,21 @@ impl VoiceConnection {
                 let _ = futures::set_exception(py, loop_, ftr, e);
                 return;
             }
-            let data = if let Some(recorder) = &*recorder.lock() {
-                recorder.stop();
+            let mut cues = None;
+            let mut tracked = None;
+            let stopped = recorder.lock().take();
+            let data = if let Some(recorder) = stopped {
+                let options = recorder.options.with_layout(layout);
+                // The receiver pushes the packets it batched as it exits, so
+                // it is joined before the queue is decoded.
+                py.allow_threads(|| recorder.stop_and_join(JOIN_TIMEOUT));
                 let mut decoder = {
                     let gateway = gateway.lock();
-                    match AudioDecoder::from_gateway(&*gateway) {
-                        Ok(decoder) => decoder,
+                    match AudioDecoder::from_gateway(&*gateway, &options) {
+                        Ok(mut decoder) => {
+                            decoder.set_gains(gains);
+                            decoder
+                        }
                         Err(e) => {
                             let _ = futures::set_exception(py, loop_, ftr, PyErr::from(e));
                             return;
@@ -182,61 +1016,1327 @@ impl VoiceConnection {
                     }
                 };
 
-                let mut queue = queue.lock();
-                let data = match queue.decode(&mut decoder) {
+                // Only decoding needs the queue. The writer calls back into
+                // Python for every chunk, so it is written to once unlocked.
+                let decoded = {
+                    let mut queue = queue.lock();
+                    let decoded = match (split_on_silence, &writer) {
+                        (Some(min_gap), _) => queue
+                            .decode_segments(&mut decoder, &options, min_gap)
+                            .map(Decoded::Segments),
+                        (None, Some(writer)) if options.format == RecordFormat::Wav => queue
+                            .decode_samples(&mut decoder, &options)
+                            .map(|samples| Decoded::Stream(writer, samples)),
+                        (None, _) => queue.decode(&mut decoder, &options).map(Decoded::File),
+                    };
+                    if vtt && decoded.is_ok() {
+                        cues = Some(queue.stats().webvtt(decoder.users()));
+                    }
+                    if speakers && decoded.is_ok() {
+                        tracked = Some((queue.speakers(), decoder.users().clone()));
+                    }
+                    decoded
+                };
+                let data = decoded
+                    .map_err(PyErr::from)
+                    .and_then(|decoded| match decoded {
+                        Decoded::Segments(segments) => Ok(segments_to_list(py, segments, checksum)),
+                        Decoded::Stream(writer, Some((channels, samples))) => stream_wav(
+                            py,
+                            writer,
+                            channels,
+                            options.output_sample_rate(),
+                            &samples,
+                            checksum,
+                        ),
+                        Decoded::Stream(writer, None) => write_file(py, writer, &[], checksum),
+                        Decoded::File(data) => {
+                            let data = data.unwrap_or_default();
+                            match &writer {
+                                Some(writer) => write_file(py, writer, &data, checksum),
+                                None => Ok(wav_to_object(py, &data, checksum)),
+                            }
+                        }
+                    });
+                match data {
                     Ok(data) => data,
                     Err(e) => {
-                        let _ = futures::set_exception(py, loop_, ftr, PyErr::from(e));
+                        let _ = futures::set_exception(py, loop_, ftr, e);
                         return;
                     }
-                };
-                data.unwrap_or_default()
+                }
+            } else if split_on_silence.is_some() {
+                segments_to_list(py, vec![], checksum)
+            } else if let Some(writer) = &writer {
+                match write_file(py, writer, &[], checksum) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        let _ = futures::set_exception(py, loop_, ftr, e);
+                        return;
+                    }
+                }
+            } else {
+                wav_to_object(py, &[], checksum)
+            };
+            // Without a recorder nothing was decoded, so there are no cues
+            // and no speakers.
+            let mut result = vec![data];
+            if vtt {
+                let cues = cues.unwrap_or_else(|| RecordStats::default().webvtt(&HashMap::new()));
+                result.push(cues.to_object(py));
+            }
+            if speakers {
+                let (ssrcs, users) = tracked.unwrap_or_default();
+                match speakers_list(py, &ssrcs, &users) {
+                    Ok(speakers) => result.push(speakers.to_object(py)),
+                    Err(e) => {
+                        let _ = futures::set_exception(py, loop_, ftr, e);
+                        return;
+                    }
+                }
+            }
+            let data = if result.len() == 1 {
+                result.remove(0)
             } else {
-                vec![]
+                PyTuple::new(py, result).to_object(py)
             };
-            let _ = futures::set_result(py, loop_, ftr, PyBytes::new(py, &data).to_object(py));
+            let _ = futures::set_result(py, loop_, ftr, data);
         });
         Ok(res)
     }
 
-    fn get_state<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
-        let result = PyDict::new(py);
-        let gateway = self.gateway.lock();
-        result.set_item("secret_key", Vec::<u8>::from(gateway.secret_key))?;
-        result.set_item("encryption_mode", Into::<String>::into(gateway.encryption))?;
-        result.set_item("endpoint", gateway.endpoint.clone())?;
-        result.set_item("endpoint_ip", gateway.endpoint_ip.clone())?;
-        result.set_item("port", gateway.port)?;
-        result.set_item("token", gateway.token.clone())?;
-        result.set_item("ssrc", gateway.ssrc)?;
-        result.set_item(
-            "last_heartbeat",
-            gateway.last_heartbeat.elapsed().as_secs_f32(),
-        )?;
-        result.set_item("player_connected", self.player.is_some())?;
-        Ok(result)
-    }
+    /// Decodes a copy of the packets received so far, or of those received
+    /// in the last `window` seconds, while recording goes on untouched.
+    #[text_signature = "(loop, window=None, layout=None, /)"]
+    #[args(window = "None", layout = "None")]
+    fn snapshot_record(
+        &self,
+        py: Python,
+        loop_: PyObject,
+        window: Option<f64>,
+        layout: Option<&str>,
+    ) -> PyResult<PyObject> {
+        let layout = layout.map(str::parse::<Layout>).transpose()?;
+        if let Some(window) = window {
+            if !(window.is_finite() && window > 0.0) {
+                return Err(DiscordError::InvalidArgument(format!(
+                    "window must be a positive number of seconds, got {}",
+                    window
+                ))
+                .into());
+            }
+        }
+        let (ftr, res) = futures::create_future(py, &loop_)?;
 
-    fn latency(&self) -> f64 {
-        self.gateway.lock().latency()
-    }
+        let gateway = Arc::clone(&self.gateway);
+        let queue = Arc::clone(&self.queue);
+        let recorder = Arc::clone(&self.recorder);
+        let gains = self.gains.lock().clone();
 
-    fn average_latency(&self) -> f64 {
-        self.gateway.lock().average_latency()
+        thread::spawn(move || {
+            let options = recorder
+                .lock()
+                .as_ref()
+                .map(|recorder| recorder.options.with_layout(layout));
+            // Only the copy is made under the lock, so the receiver is held
+            // up for as short as possible.
+            let result = options.map_or(Ok(None), |options| {
+                let mut snapshot = queue.lock().snapshot(window);
+                let mut decoder = AudioDecoder::from_gateway(&*gateway.lock(), &options)?;
+                decoder.set_gains(gains);
+                snapshot.decode(&mut decoder, &options)
+            });
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            if let Err(e) = py.check_signals() {
+                let _ = futures::set_exception(py, loop_, ftr, e);
+                return;
+            }
+            match result {
+                Ok(data) => {
+                    let data = PyBytes::new(py, &data.unwrap_or_default()).to_object(py);
+                    let _ = futures::set_result(py, loop_, ftr, data);
+                }
+                Err(e) => {
+                    let _ = futures::set_exception(py, loop_, ftr, PyErr::from(e));
+                }
+            }
+        });
+        Ok(res)
     }
-}
 
-#[pyclass]
-pub(crate) struct VoiceConnector {
-    #[pyo3(get, set)]
-    session_id: String,
-    #[pyo3(get, set)]
-    user_id: String,
-    #[pyo3(get)]
-    server_id: String,
-    #[pyo3(get)]
+    #[text_signature = "(loop, layout=None, /)"]
+    #[args(layout = "None")]
+    fn checkpoint_record(
+        &mut self,
+        py: Python,
+        loop_: PyObject,
+        layout: Option<&str>,
+    ) -> PyResult<PyObject> {
+        let layout = layout.map(str::parse::<Layout>).transpose()?;
+        let (ftr, res) = futures::create_future(py, &loop_)?;
+
+        let gateway = Arc::clone(&self.gateway);
+        let queue = Arc::clone(&self.queue);
+        let recorder = Arc::clone(&self.recorder);
+        let gains = self.gains.lock().clone();
+
+        thread::spawn(move || {
+            let options = recorder
+                .lock()
+                .as_ref()
+                .map(|recorder| recorder.options.with_layout(layout));
+            // The receiver keeps pushing into the shared queue, so the packets
+            // received so far are swapped out under the lock and decoded here.
+            let result = options.map_or(Ok(None), |options| -> Result<_, DiscordError> {
+                let mut decoder = AudioDecoder::from_gateway(&*gateway.lock(), &options)?;
+                decoder.set_gains(gains);
+                let mut taken = queue.lock().take();
+                let data = taken.decode(&mut decoder, &options)?;
+                queue.lock().set_stats(taken.stats().clone());
+                Ok(data)
+            });
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            if let Err(e) = py.check_signals() {
+                let _ = futures::set_exception(py, loop_, ftr, e);
+                return;
+            }
+            match result {
+                Ok(data) => {
+                    let data = PyBytes::new(py, &data.unwrap_or_default()).to_object(py);
+                    let _ = futures::set_result(py, loop_, ftr, data);
+                }
+                Err(e) => {
+                    let _ = futures::set_exception(py, loop_, ftr, PyErr::from(e));
+                }
+            }
+        });
+        Ok(res)
+    }
+
+    fn recorded_speakers<'py>(&self, py: Python<'py>) -> PyResult<&'py PyList> {
+        let gateway = self.gateway.lock();
+        let queue = self.queue.lock();
+        speakers_list(py, &queue.speakers(), &gateway.ssrc_map)
+    }
+
+    /// Speakers whose voice was received in the last `window_ms` milliseconds
+    /// of the current recording.
+    #[text_signature = "(window_ms=500, /)"]
+    #[args(window_ms = "500")]
+    fn active_speakers<'py>(&self, py: Python<'py>, window_ms: u64) -> PyResult<&'py PyList> {
+        let result = PyList::empty(py);
+        let gateway = self.gateway.lock();
+        let queue = self.queue.lock();
+        for ssrc in queue.active_speakers(window_ms as f64 / 1000.0) {
+            let speaker = PyDict::new(py);
+            speaker.set_item("ssrc", ssrc)?;
+            speaker.set_item("user_id", gateway.ssrc_map.get(&ssrc).cloned())?;
+            result.append(speaker)?;
+        }
+        Ok(result)
+    }
+
+    /// SSRCs with packets in the queue of the current recording.
+    fn recorded_ssrcs(&self) -> Vec<u32> {
+        let queue = self.queue.lock();
+        // Decoding drains the streams but keeps their entries.
+        queue
+            .iter()
+            .filter(|(_, packets)| !packets.is_empty())
+            .map(|(&ssrc, _)| ssrc)
+            .collect()
+    }
+
+    fn record_stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let result = PyDict::new(py);
+        let queue = self.queue.lock();
+        let stats = queue.stats();
+        let streams = PyDict::new(py);
+        for (ssrc, stream) in &stats.streams {
+            let item = PyDict::new(py);
+            item.set_item("decoded_samples", stream.decoded)?;
+            item.set_item("concealed_samples", stream.concealed)?;
+            item.set_item("concealment_ratio", stream.concealment_ratio())?;
+            streams.set_item(ssrc, item)?;
+        }
+        result.set_item("streams", streams)?;
+        result.set_item("clipped_samples", stats.clipped)?;
+        result.set_item("clipping_ratio", stats.clipping_ratio())?;
+        result.set_item("tracks", stats.tracks.clone())?;
+        result.set_item("skipped_speakers", stats.skipped.clone())?;
+        Ok(result)
+    }
+
+    /// Sizes of the Opus frames sent by the current or last player.
+    fn play_stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let result = PyDict::new(py);
+        let stats = match &self.player {
+            Some(player) => player.frame_stats(),
+            None => return Ok(result),
+        };
+        result.set_item("frames", stats.frames)?;
+        result.set_item("bytes", stats.bytes)?;
+        result.set_item("min_bytes", stats.min)?;
+        result.set_item("max_bytes", stats.max)?;
+        result.set_item("mean_bytes", stats.mean())?;
+        result.set_item("bitrate", stats.bitrate())?;
+        result.set_item("histogram_bucket", FRAME_HISTOGRAM_BUCKET)?;
+        result.set_item("histogram", stats.histogram.to_vec())?;
+        result.set_item("dropped_frames", stats.dropped)?;
+        Ok(result)
+    }
+
+    fn get_state<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let result = PyDict::new(py);
+        let gateway = self.gateway.lock();
+        result.set_item("connection_id", gateway.id)?;
+        result.set_item("secret_key", Vec::<u8>::from(gateway.secret_key))?;
+        result.set_item("encryption_mode", Into::<String>::into(gateway.encryption))?;
+        result.set_item("encryption_mode_id", gateway.encryption as u8)?;
+        result.set_item("endpoint", gateway.endpoint.clone())?;
+        result.set_item("endpoint_ip", gateway.endpoint_ip.clone())?;
+        result.set_item("port", gateway.port)?;
+        result.set_item("token", gateway.token.clone())?;
+        result.set_item("ssrc", gateway.ssrc)?;
+        result.set_item(
+            "last_heartbeat",
+            gateway.last_heartbeat.elapsed().as_secs_f32(),
+        )?;
+        result.set_item("player_connected", self.player.is_some())?;
+        let recorder = self.recorder.lock();
+        result.set_item("recorder_connected", recorder.is_some())?;
+        result.set_item(
+            "record_elapsed",
+            recorder.as_ref().map(|r| r.elapsed().as_secs_f32()),
+        )?;
+        Ok(result)
+    }
+
+    /// Decrypts a voice packet with the key of this session and returns its
+    /// RTP header and Opus payload, or `None` for an RTCP packet.
+    #[text_signature = "(packet, /)"]
+    fn decrypt_packet(&self, py: Python, packet: &[u8]) -> PyResult<PyObject> {
+        self.check_ready()?;
+        let gateway = self.gateway.lock();
+        match decrypt_packet(&*gateway, packet)? {
+            Some((header, payload)) => {
+                Ok((rtp_header_to_dict(py, &header)?, PyBytes::new(py, &payload)).to_object(py))
+            }
+            None => Ok(py.None()),
+        }
+    }
+
+    /// Returns what is needed to resume this session with
+    /// `VoiceConnector.connect(loop, resume_from=...)`.
+    fn session_state<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let result = PyDict::new(py);
+        let gateway = self.gateway.lock();
+        let session = gateway.session();
+        result.set_item("endpoint", session.endpoint)?;
+        result.set_item("server_id", session.server_id)?;
+        result.set_item("user_id", session.user_id)?;
+        result.set_item("session_id", session.session_id)?;
+        result.set_item("token", session.token)?;
+        result.set_item("ssrc", session.ssrc)?;
+        result.set_item("endpoint_ip", session.endpoint_ip)?;
+        result.set_item("port", session.port)?;
+        result.set_item("encryption_mode", Into::<String>::into(session.encryption))?;
+        result.set_item("secret_key", Vec::<u8>::from(session.secret_key))?;
+        result.set_item("close_code", gateway.close_code)?;
+        result.set_item("can_resume", gateway.can_resume())?;
+        Ok(result)
+    }
+
+    fn health<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let result = PyDict::new(py);
+        let gateway = self.gateway.lock();
+        result.set_item("state", gateway.state.get_state().as_str())?;
+        result.set_item("latency", gateway.latency())?;
+        result.set_item("average_latency", gateway.average_latency())?;
+        result.set_item(
+            "last_ack",
+            gateway.last_ack.map(|ack| ack.elapsed().as_secs_f64()),
+        )?;
+        result.set_item("socket_connected", gateway.has_socket())?;
+        result.set_item(
+            "player_alive",
+            self.player.as_ref().is_some_and(|p| p.is_alive()),
+        )?;
+        result.set_item(
+            "recorder_alive",
+            self.recorder.lock().as_ref().is_some_and(|r| r.is_alive()),
+        )?;
+        Ok(result)
+    }
+
+    fn latency(&self) -> f64 {
+        self.gateway.lock().latency()
+    }
+
+    /// Sends a heartbeat at once. The automatic heartbeats go on an interval
+    /// after it, and `latency` is updated when it is acknowledged.
+    fn send_heartbeat(&self, py: Python) -> PyResult<()> {
+        let gateway = Arc::clone(&self.gateway);
+        // `run` holds the gateway while it waits for a message.
+        py.allow_threads(|| gateway.lock().send_heartbeat())?;
+        Ok(())
+    }
+
+    /// Sets how long sending a voice packet may block before the frame is
+    /// dropped, from 1 to `FRAME_LENGTH` milliseconds.
+    #[text_signature = "(timeout_ms, /)"]
+    fn set_send_timeout(&self, py: Python, timeout_ms: u64) -> PyResult<()> {
+        if !(1..=FRAME_LENGTH as u64).contains(&timeout_ms) {
+            return Err(DiscordError::InvalidArgument(format!(
+                "timeout_ms must be between 1 and {}: {}",
+                FRAME_LENGTH, timeout_ms
+            ))
+            .into());
+        }
+        let timeout = time::Duration::from_millis(timeout_ms);
+        py.allow_threads(|| self.gateway.lock().set_send_timeout(timeout))?;
+        Ok(())
+    }
+
+    /// Calls `callback` on the loop given to `run` with the round trip time
+    /// in seconds of each heartbeat slower than `threshold_ms`.
+    /// A `callback` of `None` stops the alerts.
+    #[text_signature = "(threshold_ms, callback, /)"]
+    fn on_latency_spike(&self, threshold_ms: f64, callback: Option<PyObject>) -> PyResult<()> {
+        if !(threshold_ms.is_finite() && threshold_ms > 0.0) {
+            return Err(DiscordError::InvalidArgument(format!(
+                "threshold_ms must be a positive number, got {}",
+                threshold_ms
+            ))
+            .into());
+        }
+        let threshold = callback.as_ref().map(|_| threshold_ms / 1000.0);
+        *self.latency_callback.lock() = callback;
+        self.gateway.lock().latency_threshold = threshold;
+        Ok(())
+    }
+
+    /// Calls `callback` on the loop given to `run` with the silence in
+    /// seconds once no voice packet has arrived for `timeout_ms` while
+    /// recording. A `callback` of `None` stops the alerts.
+    #[text_signature = "(timeout_ms, callback, /)"]
+    fn on_media_timeout(&self, timeout_ms: u64, callback: Option<PyObject>) -> PyResult<()> {
+        if timeout_ms == 0 {
+            return Err(
+                DiscordError::InvalidArgument("timeout_ms must be at least 1".to_string()).into(),
+            );
+        }
+        let timeout = callback
+            .as_ref()
+            .map(|_| time::Duration::from_millis(timeout_ms));
+        *self.media_callback.lock() = callback;
+        self.media_watch.lock().timeout = timeout;
+        Ok(())
+    }
+
+    fn average_latency(&self) -> f64 {
+        self.gateway.lock().average_latency()
+    }
+
+    /// The id that tags the log lines of this connection, unique within
+    /// the process and kept across reconnects.
+    fn connection_id(&self) -> usize {
+        self.gateway.lock().id
+    }
+
+    /// The negotiated encryption mode as the stable discriminant of
+    /// `EncryptionMode`.
+    fn encryption_mode(&self) -> u8 {
+        self.gateway.lock().encryption as u8
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for VoiceConnection {
+    fn __repr__(&self) -> String {
+        let (id, endpoint, ssrc, state, latency) = {
+            let gateway = self.gateway.lock();
+            (
+                gateway.id,
+                gateway.endpoint.clone(),
+                gateway.ssrc,
+                gateway.state.get_state(),
+                gateway.latency(),
+            )
+        };
+        let latency = if latency.is_nan() {
+            "None".to_string()
+        } else {
+            format!("{:.1}ms", latency * 1000.0)
+        };
+        format!(
+            "<VoiceConnection id={} endpoint={:?} ssrc={} state={} playing={} recording={} latency={}>",
+            id,
+            endpoint,
+            ssrc,
+            state.as_str(),
+            python_bool(self.is_playing()),
+            python_bool(self.is_recording()),
+            latency
+        )
+    }
+}
+
+fn python_bool(value: bool) -> &'static str {
+    if value {
+        "True"
+    } else {
+        "False"
+    }
+}
+
+impl VoiceConnection {
+    fn new(gateway: VoiceGateway) -> Self {
+        VoiceConnection {
+            gateway: Arc::new(Mutex::new(gateway)),
+            queue: Arc::new(Mutex::new(SsrcPacketQueue::new())),
+            player: None,
+            stream: None,
+            recorder: Arc::new(Mutex::new(None)),
+            gains: Arc::new(Mutex::new(RecordGains::default())),
+            live_tap: None,
+            live_recorder: false,
+            writer: None,
+            latency_callback: Arc::new(Mutex::new(None)),
+            media_watch: Arc::new(Mutex::new(MediaWatch::default())),
+            media_callback: Arc::new(Mutex::new(None)),
+            preroll: None,
+            prerolling: false,
+            loopback: Arc::new(Mutex::new(None)),
+            level_callback: None,
+            level_meter: None,
+        }
+    }
+
+    /// Fails unless the handshake has completed. Before that the secret key
+    /// is all zeros, so the voice could not be encrypted or decrypted.
+    fn check_ready(&self) -> PyResult<()> {
+        if self.gateway.lock().is_ready() {
+            Ok(())
+        } else {
+            Err(DiscordError::ConnectionNotReady.into())
+        }
+    }
+
+    /// Fails if starting another player would exceed the limit set with
+    /// `set_max_players`. A running player of this connection is replaced,
+    /// so it does not count.
+    fn check_player_limit(&self) -> PyResult<()> {
+        let limit = MAX_PLAYERS.load(Ordering::Acquire);
+        let replaced = self.player.as_ref().is_some_and(|p| p.is_alive());
+        let others = ACTIVE_PLAYERS
+            .load(Ordering::Acquire)
+            .saturating_sub(replaced as usize);
+        if limit != 0 && others >= limit {
+            return Err(DiscordError::PlayerLimitReached(limit).into());
+        }
+        Ok(())
+    }
+
+    fn start_player(
+        &mut self,
+        py: Python,
+        source: AudioSource,
+        after: PyObject,
+        options: EncoderOptions,
+    ) {
+        if let Some(player) = self.player.take() {
+            py.allow_threads(|| player.stop_and_join(JOIN_TIMEOUT));
+        }
+        self.stream = None;
+
+        let player = AudioPlayer::new(
+            move |err| {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                let _ = after.call1(py, PyTuple::new(py, [err].iter()));
+            },
+            Arc::clone(&self.gateway),
+            Arc::new(Mutex::new(source)),
+            options,
+            Arc::clone(&self.loopback),
+        );
+        self.player = Some(player);
+    }
+
+    /// Starts a recorder in place of the running one. With `preroll_secs`,
+    /// the recording starts with that many seconds of the pre-roll buffer.
+    fn start_recorder<After>(
+        &mut self,
+        py: Python,
+        after: After,
+        options: RecordOptions,
+        rtp_sink: Option<RtpHeaderSink>,
+        preroll_secs: Option<f64>,
+    ) where
+        After: FnOnce(Option<DiscordError>) + Send + 'static,
+    {
+        let recorder = self.recorder.lock().take();
+        if let Some(recorder) = recorder {
+            py.allow_threads(|| recorder.stop_and_join(JOIN_TIMEOUT));
+        }
+        // Packets that arrive in between wait in the socket buffer, so the
+        // recording continues the pre-roll without a gap.
+        let queue = match preroll_secs {
+            Some(secs) if self.prerolling => self.queue.lock().snapshot(Some(secs)),
+            _ => SsrcPacketQueue::new(),
+        };
+        self.prerolling = false;
+        self.live_recorder = false;
+        self.queue = Arc::new(Mutex::new(queue));
+        let recorder = AudioRecorder::new(
+            after,
+            Arc::clone(&self.gateway),
+            Arc::clone(&self.queue),
+            options,
+            rtp_sink,
+            Arc::clone(&self.media_watch),
+        );
+        self.recorder = Arc::new(Mutex::new(Some(recorder)));
+        self.restart_level_meter(py);
+    }
+
+    /// Replaces the level meter with one measuring the current queue, if
+    /// `start_level_meter` was called.
+    fn restart_level_meter(&mut self, py: Python) {
+        if let Some(meter) = self.level_meter.take() {
+            py.allow_threads(|| meter.stop_and_join(JOIN_TIMEOUT));
+        }
+        let (callback, interval) = match &self.level_callback {
+            Some((callback, interval)) => (callback.clone_ref(py), *interval),
+            None => return,
+        };
+        let meter = LevelMeter::new(
+            move |levels: Vec<SpeakerLevel>| {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                if let Err(e) =
+                    levels_dict(py, levels).and_then(|levels| callback.call1(py, (levels,)))
+                {
+                    error!("Level meter callback failed: {}", e);
+                }
+            },
+            Arc::clone(&self.gateway),
+            Arc::clone(&self.queue),
+            interval,
+        );
+        self.level_meter = Some(meter);
+    }
+
+    /// Fails while `record` is running. The live tap takes the packets out
+    /// of the queue, so they would be missing from that recording.
+    fn check_no_record(&self) -> PyResult<()> {
+        if self.is_recording() && !self.live_recorder {
+            return Err(DiscordError::InvalidArgument(
+                "a live record cannot run while recording; call stop_record first".to_string(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Replaces the live tap with one that hands its chunks to `consumer`,
+    /// along with a recorder of its own. `check_no_record` must pass first.
+    fn start_tap<Consumer, Finished>(
+        &mut self,
+        py: Python,
+        consumer: Consumer,
+        finished: Finished,
+        options: RecordOptions,
+        interval_ms: u64,
+    ) where
+        Consumer: Fn(Vec<i16>) + Send + 'static,
+        Finished: FnOnce() + Send + 'static,
+    {
+        self.stop_live_record(py);
+        self.start_recorder(
+            py,
+            |err| {
+                if let Some(e) = err {
+                    error!("Live recording stopped: {}", e);
+                }
+            },
+            options,
+            None,
+            None,
+        );
+        self.live_recorder = true;
+        let tap = LiveTap::new(
+            consumer,
+            finished,
+            Arc::clone(&self.gateway),
+            Arc::clone(&self.queue),
+            Arc::clone(&self.gains),
+            options,
+            time::Duration::from_millis(interval_ms.max(20)),
+        );
+        self.live_tap = Some(tap);
+    }
+
+    /// Takes the threads out of the connection, leaving nothing to play or
+    /// decode, so that they can be stopped without holding it.
+    fn take_workers(&mut self) -> Workers {
+        self.stream = None;
+        self.writer = None;
+        self.preroll = None;
+        self.prerolling = false;
+        self.live_recorder = false;
+        self.level_callback = None;
+        let recorder = self.recorder.lock().take();
+        self.queue = Arc::new(Mutex::new(SsrcPacketQueue::new()));
+        Workers {
+            live_tap: self.live_tap.take(),
+            level_meter: self.level_meter.take(),
+            player: self.player.take(),
+            recorder,
+        }
+    }
+
+    /// Shuts the threads down on a thread of their own and resolves the
+    /// returned future with `result` once they have exited.
+    fn spawn_shutdown(
+        &mut self,
+        py: Python,
+        loop_: PyObject,
+        result: PyObject,
+    ) -> PyResult<PyObject> {
+        let (ftr, res) = futures::create_future(py, &loop_)?;
+        let workers = self.take_workers();
+        let gateway = Arc::clone(&self.gateway);
+        thread::spawn(move || {
+            workers.shut_down(&gateway);
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            let _ = futures::set_result(py, loop_, ftr, result);
+        });
+        Ok(res)
+    }
+}
+
+/// The threads of a connection, taken out of it to be stopped.
+struct Workers {
+    live_tap: Option<LiveTap>,
+    level_meter: Option<LevelMeter>,
+    player: Option<AudioPlayer>,
+    recorder: Option<AudioRecorder>,
+}
+
+impl Workers {
+    /// Stops the threads and waits for them, then closes the gateway they
+    /// use. The player sends its silence frames and clears speaking as it
+    /// exits.
+    fn shut_down(self, gateway: &Mutex<VoiceGateway>) {
+        if let Some(tap) = self.live_tap {
+            tap.stop_and_join(JOIN_TIMEOUT);
+        }
+        if let Some(meter) = self.level_meter {
+            meter.stop_and_join(JOIN_TIMEOUT);
+        }
+        let played = self.player.is_some();
+        if let Some(player) = self.player {
+            player.stop_and_join(JOIN_TIMEOUT);
+        }
+        if let Some(recorder) = self.recorder {
+            recorder.stop_and_join(JOIN_TIMEOUT);
+        }
+        let mut gateway = gateway.lock();
+        if !played {
+            if let Err(e) = gateway.speaking(SpeakingType::empty(), 0) {
+                debug!("Failed to clear speaking: {}", e);
+            }
+        }
+        if let Err(e) = gateway.close(1000) {
+            warn!("Failed to close the gateway: {}", e);
+        }
+    }
+}
+
+/// Chunks of `stream_record` that no `__anext__` has taken yet, or the
+/// future of an `__anext__` waiting for the next chunk.
+#[derive(Default)]
+struct StreamChunks {
+    chunks: VecDeque<Vec<u8>>,
+    waiter: Option<PyObject>,
+    finished: bool,
+}
+
+impl StreamChunks {
+    /// Resolves the waiting `__anext__` with `chunk`, or keeps it for the
+    /// next one. The waiter is resolved from the event loop by a
+    /// `ChunkDelivery`, since it can still be cancelled until then.
+    fn deliver(stream: &Arc<Mutex<Self>>, py: Python, loop_: &PyObject, chunk: Vec<u8>) {
+        let mut chunks = stream.lock();
+        match chunks.waiter.take() {
+            Some(waiter) if !is_done(py, &waiter) => {
+                let delivery = ChunkDelivery {
+                    chunks: Arc::clone(stream),
+                    waiter,
+                    chunk: Some(chunk),
+                };
+                let delivery = Py::new(py, delivery).map(|delivery| delivery.to_object(py));
+                if let Err(e) = delivery.and_then(|delivery| {
+                    loop_.call_method1(py, "call_soon_threadsafe", (delivery,))
+                }) {
+                    error!("Failed to deliver a live record chunk: {}", e);
+                }
+            }
+            _ => chunks.keep(chunk),
+        }
+    }
+
+    /// Gives back a chunk whose waiter was cancelled before it got it. Only
+    /// called on the event loop, so a newer waiter can be resolved directly.
+    fn requeue(&mut self, py: Python, chunk: Vec<u8>) -> PyResult<()> {
+        match self.waiter.take() {
+            Some(waiter) if !is_done(py, &waiter) => {
+                waiter.call_method1(py, "set_result", (PyBytes::new(py, &chunk),))?;
+            }
+            _ => self.chunks.push_front(chunk),
+        }
+        Ok(())
+    }
+
+    /// Keeps `chunk` for the next `__anext__`, dropping the oldest one if
+    /// the consumer is too far behind.
+    fn keep(&mut self, chunk: Vec<u8>) {
+        if self.chunks.len() >= MAX_PENDING_CHUNKS {
+            warn!("Live record consumer is too slow; dropping old audio");
+            self.chunks.pop_front();
+        }
+        self.chunks.push_back(chunk);
+    }
+
+    /// Ends the iteration once the chunks kept so far are taken.
+    fn finish(&mut self, py: Python, loop_: &PyObject) {
+        self.finished = true;
+        if let Some(waiter) = self.waiter.take() {
+            let end = PyStopAsyncIteration::new_err(());
+            let _ = futures::set_exception(py, loop_.clone_ref(py), waiter, end);
+        }
+    }
+}
+
+/// Hands a chunk of `stream_record` to the future of an `__anext__`, called
+/// on the event loop.
+#[pyclass]
+struct ChunkDelivery {
+    chunks: Arc<Mutex<StreamChunks>>,
+    waiter: PyObject,
+    chunk: Option<Vec<u8>>,
+}
+
+#[pymethods]
+impl ChunkDelivery {
+    #[call]
+    fn __call__(&mut self, py: Python) -> PyResult<()> {
+        let chunk = match self.chunk.take() {
+            Some(chunk) => chunk,
+            None => return Ok(()),
+        };
+        if is_done(py, &self.waiter) {
+            self.chunks.lock().requeue(py, chunk)
+        } else {
+            self.waiter
+                .call_method1(py, "set_result", (PyBytes::new(py, &chunk),))?;
+            Ok(())
+        }
+    }
+}
+
+/// `[{"ssrc", "user_id"}]` of the SSRCs in `ssrcs`, with the user ID of each
+/// one if it is known.
+fn speakers_list<'py>(
+    py: Python<'py>,
+    ssrcs: &[u32],
+    users: &HashMap<u32, String>,
+) -> PyResult<&'py PyList> {
+    let result = PyList::empty(py);
+    for ssrc in ssrcs {
+        let speaker = PyDict::new(py);
+        speaker.set_item("ssrc", ssrc)?;
+        speaker.set_item("user_id", users.get(ssrc).cloned())?;
+        result.append(speaker)?;
+    }
+    Ok(result)
+}
+
+/// The levels of a `LevelMeter` as a dict of SSRC to
+/// `{"user_id", "rms", "peak"}`.
+fn levels_dict<'py>(py: Python<'py>, levels: Vec<SpeakerLevel>) -> PyResult<&'py PyDict> {
+    let result = PyDict::new(py);
+    for (ssrc, user_id, level) in levels {
+        let item = PyDict::new(py);
+        item.set_item("user_id", user_id)?;
+        item.set_item("rms", level.rms)?;
+        item.set_item("peak", level.peak)?;
+        result.set_item(ssrc, item)?;
+    }
+    Ok(result)
+}
+
+fn is_done(py: Python, future: &PyObject) -> bool {
+    future
+        .call_method0(py, "done")
+        .and_then(|done| done.extract(py))
+        .unwrap_or(true)
+}
+
+/// Async iterator over the PCM chunks of a live record.
+#[pyclass]
+pub(crate) struct RecordStream {
+    loop_: PyObject,
+    chunks: Arc<Mutex<StreamChunks>>,
+}
+
+#[pyproto]
+impl PyAsyncProtocol for RecordStream {
+    fn __aiter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __anext__(slf: PyRef<Self>) -> PyResult<Option<PyObject>> {
+        let py = slf.py();
+        let mut chunks = slf.chunks.lock();
+        if let Some(chunk) = chunks.chunks.pop_front() {
+            let (ftr, res) = futures::create_future(py, &slf.loop_)?;
+            ftr.call_method1(py, "set_result", (PyBytes::new(py, &chunk),))?;
+            return Ok(Some(res));
+        }
+        if chunks.finished {
+            return Ok(None);
+        }
+        // A waiter cancelled by the consumer, e.g. by `asyncio.wait_for`,
+        // is replaced.
+        if chunks
+            .waiter
+            .as_ref()
+            .is_some_and(|waiter| !is_done(py, waiter))
+        {
+            return Err(DiscordError::InvalidArgument(
+                "the previous chunk is still being awaited".to_string(),
+            )
+            .into());
+        }
+        let (ftr, res) = futures::create_future(py, &slf.loop_)?;
+        chunks.waiter = Some(ftr);
+        Ok(Some(res))
+    }
+}
+
+/// Little endian bytes of PCM samples.
+fn pcm_bytes(pcm: &[i16]) -> Vec<u8> {
+    pcm.iter()
+        .flat_map(|sample| sample.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// Number of players running, across every connection.
+#[pyfunction]
+pub(crate) fn active_player_count() -> usize {
+    ACTIVE_PLAYERS.load(Ordering::Acquire)
+}
+
+/// Number of recorders running, across every connection.
+#[pyfunction]
+pub(crate) fn active_recorder_count() -> usize {
+    ACTIVE_RECORDERS.load(Ordering::Acquire)
+}
+
+/// Limits the number of players running at once. `None` removes the limit.
+#[pyfunction]
+#[text_signature = "(limit, /)"]
+pub(crate) fn set_max_players(limit: Option<usize>) -> PyResult<()> {
+    if limit == Some(0) {
+        return Err(DiscordError::InvalidArgument("limit must be at least 1".to_string()).into());
+    }
+    MAX_PLAYERS.store(limit.unwrap_or(0), Ordering::Release);
+    Ok(())
+}
+
+/// Encoder settings suited to a voice channel of `channel_bitrate` bps, as
+/// keyword arguments of `play`.
+#[pyfunction]
+pub(crate) fn channel_encoder_settings(py: Python, channel_bitrate: u32) -> PyResult<PyObject> {
+    let settings = ChannelSettings::for_channel(channel_bitrate)?;
+    let dict = PyDict::new(py);
+    dict.set_item("profile", settings.profile)?;
+    dict.set_item("bitrate", settings.bitrate)?;
+    if let Some(max_bandwidth) = settings.max_bandwidth {
+        dict.set_item("max_bandwidth", max_bandwidth)?;
+    }
+    dict.set_item("channel_bitrate", channel_bitrate)?;
+    Ok(dict.into())
+}
+
+/// Names of the encryption modes this build supports.
+#[pyfunction]
+pub(crate) fn supported_encryption_modes() -> Vec<String> {
+    EncryptionMode::SUPPORTED
+        .iter()
+        .map(|&mode| mode.into())
+        .collect()
+}
+
+/// Runs ffprobe, or ffmpeg if it is missing, on `input` and returns its
+/// `duration`, `format`, `codec`, `sample_rate`, `channels` and `bitrate`.
+#[pyfunction]
+#[text_signature = "(input, /)"]
+pub(crate) fn probe(py: Python, input: &str) -> PyResult<PyObject> {
+    let info = py.allow_threads(|| crate::probe::probe(input))?;
+    let dict = PyDict::new(py);
+    dict.set_item("duration", info.duration)?;
+    dict.set_item("format", info.format)?;
+    dict.set_item("codec", info.codec)?;
+    dict.set_item("sample_rate", info.sample_rate)?;
+    dict.set_item("channels", info.channels)?;
+    dict.set_item("bitrate", info.bitrate)?;
+    Ok(dict.into())
+}
+
+/// Options of `profile`, or the defaults, with the given settings replaced.
+/// The bitrate is then limited to `channel_bitrate`, if given.
+#[allow(clippy::too_many_arguments)]
+fn encoder_options(
+    profile: Option<&str>,
+    bandwidth: Option<&str>,
+    max_bandwidth: Option<&str>,
+    bitrate: Option<&PyAny>,
+    complexity: Option<u8>,
+    disable_prediction: Option<bool>,
+    bitrate_ramp_ms: Option<u32>,
+    signal: Option<&str>,
+    channel_bitrate: Option<u32>,
+    vbr: Option<bool>,
+    vbr_constraint: Option<bool>,
+) -> PyResult<EncoderOptions> {
+    let mut options = match profile {
+        Some(profile) => profile.parse::<EncoderProfile>()?.options(),
+        None => EncoderOptions::default(),
+    };
+    if let Some(bandwidth) = bandwidth {
+        options.bandwidth = bandwidth.parse()?;
+    }
+    if let Some(max_bandwidth) = max_bandwidth {
+        options.max_bandwidth = max_bandwidth.parse()?;
+    }
+    if let Some(bitrate) = bitrate {
+        options.bitrate = match bitrate.extract::<i32>() {
+            Ok(bps) => Bitrate::bits_per_second(bps)?,
+            Err(_) => bitrate.extract::<&str>()?.parse()?,
+        };
+    }
+    if let Some(complexity) = complexity {
+        options.complexity = complexity;
+    }
+    if let Some(disabled) = disable_prediction {
+        options.prediction_disabled = disabled;
+    }
+    if let Some(ramp) = bitrate_ramp_ms {
+        options.bitrate_ramp_ms = ramp;
+    }
+    if let Some(signal) = signal {
+        options.signal = signal.parse()?;
+    }
+    if let Some(vbr) = vbr {
+        options.vbr = vbr;
+    }
+    if let Some(constraint) = vbr_constraint {
+        options.vbr_constraint = constraint;
+    }
+    if let Some(channel_bitrate) = channel_bitrate {
+        options.limit_to_channel(channel_bitrate)?;
+    }
+    options.validate()?;
+    Ok(options)
+}
+
+fn dict_item<'a, T: FromPyObject<'a>>(dict: &'a PyDict, key: &str) -> PyResult<T> {
+    match dict.get_item(key) {
+        Some(value) => value.extract(),
+        None => Err(DiscordError::BuilderMissingRequiredField(key.to_string()).into()),
+    }
+}
+
+fn session_from_dict(dict: &PyDict) -> PyResult<SessionState> {
+    Ok(SessionState {
+        endpoint: dict_item(dict, "endpoint")?,
+        server_id: dict_item(dict, "server_id")?,
+        user_id: dict_item(dict, "user_id")?,
+        session_id: dict_item(dict, "session_id")?,
+        token: dict_item(dict, "token")?,
+        ..media_from_dict(dict)?
+    })
+}
+
+/// The UDP part of a session, which is all a detached gateway needs.
+fn media_from_dict(dict: &PyDict) -> PyResult<SessionState> {
+    let mode: String = dict_item(dict, "encryption_mode")?;
+    let encryption = mode
+        .parse()
+        .map_err(|_| DiscordError::InvalidArgument(format!("Unknown encryption mode: {}", mode)))?;
+    let key: Vec<u8> = dict_item(dict, "secret_key")?;
+    if key.len() != 32 {
+        return Err(DiscordError::InvalidArgument(format!(
+            "secret_key must be 32 bytes long: {}",
+            key.len()
+        ))
+        .into());
+    }
+    let mut secret_key = [0; 32];
+    secret_key.copy_from_slice(&key);
+    Ok(SessionState {
+        ssrc: dict_item(dict, "ssrc")?,
+        endpoint_ip: dict_item(dict, "endpoint_ip")?,
+        port: dict_item(dict, "port")?,
+        encryption,
+        secret_key,
+        ..SessionState::default()
+    })
+}
+
+fn rtp_header_to_dict<'py>(py: Python<'py>, header: &RtpHeader) -> PyResult<&'py PyDict> {
+    let item = PyDict::new(py);
+    item.set_item("version", header.version)?;
+    item.set_item("padding", header.padding)?;
+    item.set_item("extension", header.extension)?;
+    item.set_item("csrc_count", header.csrc_count)?;
+    item.set_item("marker", header.marker)?;
+    item.set_item("payload_type", header.payload_type)?;
+    item.set_item("sequence", header.sequence)?;
+    item.set_item("timestamp", header.timestamp)?;
+    item.set_item("ssrc", header.ssrc)?;
+    Ok(item)
+}
+
+fn rtp_headers_to_list<'py>(py: Python<'py>, headers: &[RtpHeader]) -> PyResult<&'py PyList> {
+    let result = PyList::empty(py);
+    for header in headers {
+        result.append(rtp_header_to_dict(py, header)?)?;
+    }
+    Ok(result)
+}
+
+/// Most retries `VoiceConnector::connect` accepts.
+const MAX_CONNECT_RETRIES: u32 = 10;
+
+/// Exponential backoff between connection attempts, set with
+/// `VoiceConnector::set_backoff`.
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    base_ms: u64,
+    max_ms: u64,
+    multiplier: f64,
+    /// Largest fraction of the delay added at random, so that the bots of a
+    /// process that lost every connection at once do not retry in lockstep.
+    jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            base_ms: 500,
+            max_ms: 30_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    fn new(base_ms: u64, max_ms: u64, multiplier: f64, jitter: f64) -> PyResult<Backoff> {
+        if max_ms < base_ms {
+            return Err(DiscordError::InvalidArgument(format!(
+                "max_ms must be at least base_ms ({}): {}",
+                base_ms, max_ms
+            ))
+            .into());
+        }
+        if !(multiplier.is_finite() && multiplier >= 1.0) {
+            return Err(DiscordError::InvalidArgument(format!(
+                "multiplier must be at least 1: {}",
+                multiplier
+            ))
+            .into());
+        }
+        if !(0.0..=1.0).contains(&jitter) {
+            return Err(DiscordError::InvalidArgument(format!(
+                "jitter must be between 0 and 1: {}",
+                jitter
+            ))
+            .into());
+        }
+        Ok(Backoff {
+            base_ms,
+            max_ms,
+            multiplier,
+            jitter,
+        })
+    }
+
+    /// Wait before retry `attempt` (from 0): `base_ms` times `multiplier` for
+    /// each attempt up to `max_ms`, then lengthened by the jitter.
+    fn delay(&self, attempt: u32) -> time::Duration {
+        let delay_ms = (self.base_ms as f64 * self.multiplier.powi(attempt.min(1024) as i32))
+            .min(self.max_ms as f64);
+        time::Duration::from_secs_f64(delay_ms / 1000.0)
+            .mul_f64(1.0 + self.jitter * rand::random::<f64>())
+    }
+}
+
+/// Whether a connection attempt failing with `err` may succeed when retried.
+/// Mistakes in the arguments, a cancellation and a deliberate disconnect are
+/// final, and so is a session Discord refused to resume.
+fn is_retryable(err: &DiscordError, resume: bool) -> bool {
+    match err {
+        DiscordError::BuilderMissingRequiredField(_)
+        | DiscordError::InvalidArgument(_)
+        | DiscordError::InvalidProxy(_)
+        | DiscordError::ConnectCancelled => false,
+        DiscordError::ConnectionClosed(code) => {
+            !CLEAN_CLOSE_CODES.contains(code) && !(resume && SESSION_INVALID_CODES.contains(code))
+        }
+        _ => true,
+    }
+}
+
+fn connect_timeout(timeout: Option<f64>) -> PyResult<Option<time::Duration>> {
+    match timeout {
+        Some(timeout) if !(timeout.is_finite() && timeout > 0.0) => {
+            Err(DiscordError::InvalidArgument(format!(
+                "timeout must be a positive number of seconds, got {}",
+                timeout
+            ))
+            .into())
+        }
+        timeout => Ok(timeout.map(time::Duration::from_secs_f64)),
+    }
+}
+
+fn wav_to_object(py: Python, data: &[u8], checksum: bool) -> PyObject {
+    if checksum {
+        (PyBytes::new(py, data), sha256_hex(data)).to_object(py)
+    } else {
+        PyBytes::new(py, data).to_object(py)
+    }
+}
+
+/// A recording decoded by `stop_record`, before it is handed to Python.
+enum Decoded<'w> {
+    Segments(Vec<(f64, Vec<u8>)>),
+    File(Option<Vec<u8>>),
+    /// Samples and their number of channels, to be streamed into the
+    /// writer as a WAV file.
+    Stream(&'w PyObject, Option<(u16, Vec<i16>)>),
+}
+
+/// Size of the chunks passed to the writer.
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes a file into a Python file-like object in chunks and returns the
+/// number of bytes written, with its digest if `checksum` is set.
+fn write_file(py: Python, writer: &PyObject, data: &[u8], checksum: bool) -> PyResult<PyObject> {
+    for chunk in data.chunks(WRITE_CHUNK_SIZE) {
+        writer.call_method1(py, "write", (PyBytes::new(py, chunk),))?;
+    }
+    flush_writer(py, writer)?;
+    if checksum {
+        Ok((data.len(), sha256_hex(data)).to_object(py))
+    } else {
+        Ok(data.len().to_object(py))
+    }
+}
+
+/// Streams samples into a Python file-like object as a WAV file, one chunk
+/// at a time, without serializing the whole file first. A generic writer
+/// cannot seek back, so the header holds `WAV_UNKNOWN_SIZE` instead of the
+/// sizes. Returns the number of bytes written, with their digest if
+/// `checksum` is set.
+fn stream_wav(
+    py: Python,
+    writer: &PyObject,
+    channels: u16,
+    sample_rate: u32,
+    samples: &[i16],
+    checksum: bool,
+) -> PyResult<PyObject> {
+    let mut digest = Sha256::new();
+    let mut written = 0;
+    let mut write = |bytes: &[u8]| -> PyResult<()> {
+        writer.call_method1(py, "write", (PyBytes::new(py, bytes),))?;
+        digest.update(bytes);
+        written += bytes.len();
+        Ok(())
+    };
+    write(&wav_stream_header(channels, sample_rate))?;
+    for chunk in samples.chunks(WRITE_CHUNK_SIZE / 2) {
+        write(&pcm_bytes(chunk))?;
+    }
+    flush_writer(py, writer)?;
+    if checksum {
+        Ok((written, format!("{:x}", digest.finalize())).to_object(py))
+    } else {
+        Ok(written.to_object(py))
+    }
+}
+
+/// Flushes the writer if it can be. The recording only reaches the writer
+/// in `stop_record`, so this is the one point where flushing makes it
+/// durable.
+fn flush_writer(py: Python, writer: &PyObject) -> PyResult<()> {
+    if writer.as_ref(py).hasattr("flush")? {
+        writer.call_method0(py, "flush")?;
+    }
+    Ok(())
+}
+
+fn segments_to_list(py: Python, segments: Vec<(f64, Vec<u8>)>, checksum: bool) -> PyObject {
+    PyList::new(
+        py,
+        segments.iter().map(|(start, data)| {
+            if checksum {
+                (*start, PyBytes::new(py, data), sha256_hex(data)).to_object(py)
+            } else {
+                (*start, PyBytes::new(py, data)).to_object(py)
+            }
+        }),
+    )
+    .to_object(py)
+}
+
+fn completed_future(py: Python, result: PyObject) -> PyResult<PyObject> {
+    let loop_ = py.import("asyncio")?.call0("get_running_loop")?;
+    let ftr = loop_.call_method0("create_future")?;
+    ftr.call_method1("set_result", (result,))?;
+    Ok(ftr.into_py(py))
+}
+
+#[pyclass]
+pub(crate) struct VoiceConnector {
+    #[pyo3(get, set)]
+    session_id: String,
+    #[pyo3(get, set)]
+    user_id: String,
+    #[pyo3(get)]
+    server_id: String,
+    #[pyo3(get)]
     endpoint: String,
+    #[pyo3(get, set)]
+    proxy: Option<String>,
+    /// DSCP code point the voice packets are marked with.
+    #[pyo3(get, set)]
+    dscp: Option<u8>,
     token: String,
+    cancel: ConnectCancel,
+    backoff: Backoff,
+    /// Reconnects since the last successful connection, see `next_backoff`.
+    reconnect_attempt: u32,
 }
 
 #[pymethods]
@@ -249,36 +2349,138 @@ impl VoiceConnector {
             user_id: String::new(),
             server_id: String::new(),
             endpoint: String::new(),
+            proxy: None,
+            dscp: None,
             token: String::new(),
+            cancel: ConnectCancel::default(),
+            backoff: Backoff::default(),
+            reconnect_attempt: 0,
         }
     }
 
-    fn update_connection_config(&mut self, token: &str, server_id: &str, endpoint: &str) {
+    /// Takes the `token`, `guild_id` and `endpoint` of a VOICE_SERVER_UPDATE.
+    /// The endpoint may be given with or without a port.
+    fn update_connection_config(
+        &mut self,
+        token: &str,
+        server_id: &str,
+        endpoint: &str,
+    ) -> PyResult<()> {
         info!("Update Connection Info;");
+        // Test server URLs, see `VoiceGatewayBuilder::endpoint`, keep their port.
+        self.endpoint = if cfg!(feature = "dev-gateway") && endpoint.contains("://") {
+            endpoint.to_string()
+        } else {
+            gateway_host(endpoint)?
+        };
         self.token = token.to_string();
         self.server_id = server_id.to_string();
-        self.endpoint = endpoint.to_string();
+        Ok(())
     }
 
-    #[text_signature = "(loop, /)"]
-    fn connect(&mut self, py: Python, loop_: PyObject) -> PyResult<PyObject> {
-        let (ftr, res): (PyObject, PyObject) = {
-            let ftr = loop_.call_method0(py, "create_future")?;
-            (ftr.clone_ref(py), ftr)
+    /// Connects to the voice gateway. A failed attempt is retried up to
+    /// `retries` times, waiting as set with `set_backoff` in between, or
+    /// `backoff_ms` before the first retry if given. `timeout` applies to
+    /// each attempt.
+    #[text_signature = "(loop, progress=None, resume_from=None, timeout=None, retries=0, backoff_ms=None, /)"]
+    #[args(
+        progress = "None",
+        resume_from = "None",
+        timeout = "None",
+        retries = "0",
+        backoff_ms = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn connect(
+        &mut self,
+        py: Python,
+        loop_: PyObject,
+        progress: Option<PyObject>,
+        resume_from: Option<&PyDict>,
+        timeout: Option<f64>,
+        retries: u32,
+        backoff_ms: Option<u64>,
+    ) -> PyResult<PyObject> {
+        if retries > MAX_CONNECT_RETRIES {
+            return Err(DiscordError::InvalidArgument(format!(
+                "retries must be at most {}, got {}",
+                MAX_CONNECT_RETRIES, retries
+            ))
+            .into());
+        }
+        let backoff = match backoff_ms {
+            Some(base_ms) => Backoff::new(
+                base_ms,
+                self.backoff.max_ms.max(base_ms),
+                self.backoff.multiplier,
+                self.backoff.jitter,
+            )?,
+            None => self.backoff,
         };
+        let (ftr, res) = futures::create_future(py, &loop_)?;
+        let session = resume_from.map(session_from_dict).transpose()?;
+        let timeout = connect_timeout(timeout)?;
 
         let mut gateway = VoiceGatewayBuilder::default();
-        gateway
-            .endpoint(&self.endpoint)
-            .session_id(&self.session_id)
-            .user_id(&self.user_id)
-            .token(&self.token)
-            .server_id(&self.server_id);
+        match &session {
+            Some(session) => gateway
+                .endpoint(&session.endpoint)
+                .session_id(&session.session_id)
+                .user_id(&session.user_id)
+                .token(&session.token)
+                .server_id(&session.server_id),
+            None => gateway
+                .endpoint(&self.endpoint)
+                .session_id(&self.session_id)
+                .user_id(&self.user_id)
+                .token(&self.token)
+                .server_id(&self.server_id),
+        }
+        .proxy(self.proxy.as_deref())?
+        .dscp(self.dscp)?
+        .timeout(timeout);
+        // Each attempt gets its own handle so that cancelling an abandoned
+        // attempt does not affect the next one.
+        self.cancel = ConnectCancel::default();
+        gateway.cancel(self.cancel.clone());
+        let cancel = self.cancel.clone();
 
         thread::spawn(move || {
-            let result = match gateway.connect() {
-                Ok(mut gateway) => gateway.connection_flow(false).and(Ok(gateway)),
-                Err(e) => Err(e),
+            let report = |stage: &str| {
+                info!("Connection progress: {}", stage);
+                if let Some(progress) = &progress {
+                    let gil = Python::acquire_gil();
+                    let py = gil.python();
+                    let _ = futures::call_soon(py, &loop_, progress, stage.into_py(py));
+                }
+            };
+            let mut attempt = 0;
+            let result = loop {
+                let result = gateway.connect(&report).and_then(|mut gateway| {
+                    match &session {
+                        Some(session) => {
+                            gateway.restore_session(session)?;
+                            gateway.connection_flow(true, &report)?;
+                        }
+                        None => gateway.connection_flow(false, &report)?,
+                    }
+                    Ok(gateway)
+                });
+                match result {
+                    Err(e) if attempt < retries && is_retryable(&e, session.is_some()) => {
+                        let delay = backoff.delay(attempt);
+                        attempt += 1;
+                        warn!(
+                            "Connection attempt {} failed: {}; retrying in {:?}",
+                            attempt, e, delay
+                        );
+                        report("retrying");
+                        if let Err(e) = cancel.sleep(delay) {
+                            break Err(e);
+                        }
+                    }
+                    result => break result,
+                }
             };
             let gil = Python::acquire_gil();
             let py = gil.python();
@@ -288,12 +2490,7 @@ impl VoiceConnector {
             }
             match result {
                 Ok(gw) => {
-                    let obj = VoiceConnection {
-                        gateway: Arc::new(Mutex::new(gw)),
-                        queue: Arc::new(Mutex::new(SsrcPacketQueue::new())),
-                        player: None,
-                        recorder: Arc::new(Mutex::new(None)),
-                    };
+                    let obj = VoiceConnection::new(gw);
                     let _ = futures::set_result(py, loop_, ftr, obj.into_py(py));
                 }
                 Err(e) => {
@@ -303,4 +2500,39 @@ impl VoiceConnector {
         });
         Ok(res)
     }
+
+    /// Sets the backoff between connection attempts: `base_ms` before the
+    /// first retry, `multiplier` times longer before each following one up
+    /// to `max_ms`, each lengthened by up to `jitter` of itself at random.
+    #[text_signature = "(base_ms, max_ms, multiplier, jitter, /)"]
+    fn set_backoff(
+        &mut self,
+        base_ms: u64,
+        max_ms: u64,
+        multiplier: f64,
+        jitter: f64,
+    ) -> PyResult<()> {
+        self.backoff = Backoff::new(base_ms, max_ms, multiplier, jitter)?;
+        Ok(())
+    }
+
+    /// Seconds to wait before the next reconnect, growing with each call
+    /// until `reset_backoff`.
+    fn next_backoff(&mut self) -> f64 {
+        let delay = self.backoff.delay(self.reconnect_attempt);
+        self.reconnect_attempt = self.reconnect_attempt.saturating_add(1);
+        delay.as_secs_f64()
+    }
+
+    /// Starts the backoff of `next_backoff` over, once a connection succeeded.
+    fn reset_backoff(&mut self) {
+        self.reconnect_attempt = 0;
+    }
+
+    /// Aborts the connection started by the last `connect`, which then
+    /// fails with `ConnectionCancelled`. Does nothing once it has finished.
+    fn cancel_connect(&self) {
+        info!("Cancel Connection;");
+        self.cancel.cancel();
+    }
 }
@@ -1,3 +1,4 @@
+use pyo3::exceptions::{PyTimeoutError, PyValueError};
 use pyo3::PyErr;
 use pyo3::{create_exception, PyObject, Python, ToPyObject};
 use thiserror::Error;
@@ -11,7 +12,21 @@ create_exception!(ffi, InternalIOError, pyo3::exceptions::PyException);
 create_exception!(ffi, TlsError, pyo3::exceptions::PyException);
 create_exception!(ffi, GatewayError, pyo3::exceptions::PyException);
 create_exception!(ffi, TryReconnect, pyo3::exceptions::PyException);
+create_exception!(ffi, SessionInvalidated, TryReconnect);
 create_exception!(ffi, EncryptionFailed, pyo3::exceptions::PyException);
+create_exception!(ffi, ConnectionCancelled, pyo3::exceptions::PyException);
+create_exception!(ffi, TooManyPlayers, pyo3::exceptions::PyException);
+create_exception!(ffi, UdpDiscoveryError, pyo3::exceptions::PyException);
+create_exception!(ffi, UdpDiscoveryTimeout, UdpDiscoveryError);
+
+/// Close codes after which the connection must not be re-established.
+pub(crate) const CLEAN_CLOSE_CODES: [u16; 3] = [1000, 4014, 4015];
+
+/// Close codes after which the session cannot be resumed:
+/// 4006 (session no longer valid) and 4009 (session timeout).
+/// Reconnecting requires a fresh IDENTIFY with a new session.
+/// Any other code may be recovered from by resuming the session.
+pub(crate) const SESSION_INVALID_CODES: [u16; 2] = [4006, 4009];
 
 #[derive(Debug, Error)]
 #[allow(clippy::large_enum_variant)]
@@ -24,14 +39,21 @@ pub(crate) enum DiscordError {
     IoError(#[from] std::io::Error),
     #[error("IO Error: {0:?}")]
     InvalidDnsName(#[from] webpki::InvalidDNSNameError),
+    #[error("Failed to Resolve Gateway Host {0}: {1}")]
+    DnsResolutionFailed(String, std::io::Error),
+    #[error("Failed to Connect to Gateway Host {0}: {1}")]
+    TcpConnectFailed(String, std::io::Error),
+    #[error("TLS Handshake Failed: {0}")]
+    TlsHandshakeFailed(std::io::Error),
+    #[error("Websocket Upgrade Rejected: {0}")]
+    WebsocketUpgradeRejected(String),
+    #[error("Invalid Proxy URL: {0}")]
+    InvalidProxy(String),
+    #[error("Proxy Connection Failed: {0}")]
+    ProxyConnectFailed(String),
     #[error("Websocket Handshake Failed: {0}")]
     WebsocketHandshakeFailed(
-        #[from]
-        tungstenite::HandshakeError<
-            tungstenite::ClientHandshake<
-                rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream>,
-            >,
-        >,
+        #[from] tungstenite::HandshakeError<tungstenite::ClientHandshake<crate::ws::GatewayStream>>,
     ),
     #[error("Gateway Received Error Message: {0}")]
     TungsteniteError(#[from] tungstenite::Error),
@@ -45,10 +67,34 @@ pub(crate) enum DiscordError {
     ConnectionClosed(u16),
     #[error("Failed to Encrypt / Decrypt: {0}")]
     EncryptionError(xsalsa20poly1305::aead::Error),
+    #[error("No Supported Encryption Mode Offered: {0:?}")]
+    NoSupportedEncryptionMode(Vec<String>),
     #[error("Opus Error: {0:?}")]
     OpusError(#[from] audiopus::Error),
     #[error("Wav Error: {0:?}")]
     WavFileError(#[from] hound::Error),
+    #[error("FFmpeg Exited with {0}")]
+    FFmpegExited(std::process::ExitStatus),
+    #[error("Invalid Argument: {0}")]
+    InvalidArgument(String),
+    #[error("Timed Out Connecting to the Gateway")]
+    ConnectTimeout,
+    #[error("Connection to the Gateway Cancelled")]
+    ConnectCancelled,
+    #[error("Connection Not Ready: the voice handshake has not completed")]
+    ConnectionNotReady,
+    #[error("Too Many Active Players (limit: {0})")]
+    PlayerLimitReached(usize),
+    #[error("Resampling Failed: {0}")]
+    ResampleFailed(String),
+    #[error("UDP Discovery Failed: {0}")]
+    DiscoveryFailed(String),
+    #[error("UDP Discovery Timed Out: no response from the voice server, is UDP blocked?")]
+    DiscoveryTimeout,
+    #[error("MP3 Encoding Failed: {0}")]
+    Mp3EncodeFailed(String),
+    #[error("Failed to Probe the Input: {0}")]
+    ProbeFailed(String),
 }
 
 impl From<DiscordError> for PyErr {
@@ -59,18 +105,39 @@ impl From<DiscordError> for PyErr {
             TlsConnectorCreationFailed(_) => InternalError::new_err(err.to_string()),
             IoError(_) => InternalIOError::new_err(err.to_string()),
             InvalidDnsName(_) => InternalError::new_err(err.to_string()),
+            DnsResolutionFailed(_, _) => InternalIOError::new_err(err.to_string()),
+            TcpConnectFailed(_, _) => InternalIOError::new_err(err.to_string()),
+            TlsHandshakeFailed(_) => TlsError::new_err(err.to_string()),
+            WebsocketUpgradeRejected(_) => GatewayError::new_err(err.to_string()),
+            InvalidProxy(_) => InternalError::new_err(err.to_string()),
+            ProxyConnectFailed(_) => InternalIOError::new_err(err.to_string()),
             WebsocketHandshakeFailed(_) => TlsError::new_err(err.to_string()),
             TungsteniteError(_) => GatewayError::new_err(err.to_string()),
             SerdeError(_) => InternalError::new_err(err.to_string()),
             InvalidOpCode(_) => GatewayError::new_err(err.to_string()),
             AddrParseFailed(_) => GatewayError::new_err(err.to_string()),
-            ConnectionClosed(c) if ![1000, 4014, 4015].contains(&c) => {
+            ConnectionClosed(c) if SESSION_INVALID_CODES.contains(&c) => {
+                SessionInvalidated::new_err(err.to_string())
+            }
+            ConnectionClosed(c) if !CLEAN_CLOSE_CODES.contains(&c) => {
                 TryReconnect::new_err(err.to_string())
             }
             ConnectionClosed(_) => GatewayError::new_err(err.to_string()),
             EncryptionError(_) => EncryptionFailed::new_err(err.to_string()),
+            NoSupportedEncryptionMode(_) => EncryptionFailed::new_err(err.to_string()),
             OpusError(_) => InternalError::new_err(err.to_string()),
             WavFileError(_) => InternalIOError::new_err(err.to_string()),
+            FFmpegExited(_) => InternalIOError::new_err(err.to_string()),
+            InvalidArgument(_) => PyValueError::new_err(err.to_string()),
+            ConnectTimeout => PyTimeoutError::new_err(err.to_string()),
+            ConnectCancelled => ConnectionCancelled::new_err(err.to_string()),
+            PlayerLimitReached(_) => TooManyPlayers::new_err(err.to_string()),
+            ConnectionNotReady => GatewayError::new_err(err.to_string()),
+            ResampleFailed(_) => InternalError::new_err(err.to_string()),
+            DiscoveryFailed(_) => UdpDiscoveryError::new_err(err.to_string()),
+            DiscoveryTimeout => UdpDiscoveryTimeout::new_err(err.to_string()),
+            Mp3EncodeFailed(_) => InternalError::new_err(err.to_string()),
+            ProbeFailed(_) => InternalIOError::new_err(err.to_string()),
         }
     }
 }
@@ -83,18 +150,39 @@ impl From<&DiscordError> for PyErr {
             TlsConnectorCreationFailed(_) => InternalError::new_err(err.to_string()),
             IoError(_) => InternalIOError::new_err(err.to_string()),
             InvalidDnsName(_) => InternalError::new_err(err.to_string()),
+            DnsResolutionFailed(_, _) => InternalIOError::new_err(err.to_string()),
+            TcpConnectFailed(_, _) => InternalIOError::new_err(err.to_string()),
+            TlsHandshakeFailed(_) => TlsError::new_err(err.to_string()),
+            WebsocketUpgradeRejected(_) => GatewayError::new_err(err.to_string()),
+            InvalidProxy(_) => InternalError::new_err(err.to_string()),
+            ProxyConnectFailed(_) => InternalIOError::new_err(err.to_string()),
             WebsocketHandshakeFailed(_) => TlsError::new_err(err.to_string()),
             TungsteniteError(_) => GatewayError::new_err(err.to_string()),
             SerdeError(_) => InternalError::new_err(err.to_string()),
             InvalidOpCode(_) => GatewayError::new_err(err.to_string()),
             AddrParseFailed(_) => GatewayError::new_err(err.to_string()),
-            ConnectionClosed(c) if ![1000, 4014, 4015].contains(&c) => {
+            ConnectionClosed(c) if SESSION_INVALID_CODES.contains(&c) => {
+                SessionInvalidated::new_err(err.to_string())
+            }
+            ConnectionClosed(c) if !CLEAN_CLOSE_CODES.contains(&c) => {
                 TryReconnect::new_err(err.to_string())
             }
             ConnectionClosed(_) => GatewayError::new_err(err.to_string()),
             EncryptionError(_) => EncryptionFailed::new_err(err.to_string()),
+            NoSupportedEncryptionMode(_) => EncryptionFailed::new_err(err.to_string()),
             OpusError(_) => InternalError::new_err(err.to_string()),
             WavFileError(_) => InternalIOError::new_err(err.to_string()),
+            FFmpegExited(_) => InternalIOError::new_err(err.to_string()),
+            InvalidArgument(_) => PyValueError::new_err(err.to_string()),
+            ConnectTimeout => PyTimeoutError::new_err(err.to_string()),
+            ConnectCancelled => ConnectionCancelled::new_err(err.to_string()),
+            PlayerLimitReached(_) => TooManyPlayers::new_err(err.to_string()),
+            ConnectionNotReady => GatewayError::new_err(err.to_string()),
+            ResampleFailed(_) => InternalError::new_err(err.to_string()),
+            DiscoveryFailed(_) => UdpDiscoveryError::new_err(err.to_string()),
+            DiscoveryTimeout => UdpDiscoveryTimeout::new_err(err.to_string()),
+            Mp3EncodeFailed(_) => InternalError::new_err(err.to_string()),
+            ProbeFailed(_) => InternalIOError::new_err(err.to_string()),
         }
     }
 }
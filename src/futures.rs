@@ -1,5 +1,28 @@
 #![allow(dead_code)]
-use pyo3::prelude::*;
+use pyo3::{exceptions::PyTypeError, prelude::*};
+
+/// Methods of the event loop used by the native threads.
+const LOOP_METHODS: [&str; 2] = ["create_future", "call_soon_threadsafe"];
+
+/// Checks that `loop_` looks like an asyncio event loop, so that a wrong
+/// argument is reported to the caller instead of inside a spawned thread.
+pub(crate) fn validate_loop(py: Python, loop_: &PyObject) -> PyResult<()> {
+    let loop_ = loop_.as_ref(py);
+    for method in LOOP_METHODS.iter() {
+        if !loop_.hasattr(*method)? {
+            return Err(PyTypeError::new_err("expected an asyncio event loop"));
+        }
+    }
+    Ok(())
+}
+
+/// Creates a future on `loop_`, returning one handle for the spawned thread
+/// and one for the caller.
+pub(crate) fn create_future(py: Python, loop_: &PyObject) -> PyResult<(PyObject, PyObject)> {
+    validate_loop(py, loop_)?;
+    let ftr = loop_.call_method0(py, "create_future")?;
+    Ok((ftr.clone_ref(py), ftr))
+}
 
 pub(crate) fn set_result(
     py: Python,
@@ -22,3 +45,13 @@ pub(crate) fn set_exception(
     loop_.call_method1(py, "call_soon_threadsafe", (set, exception.to_object(py)))?;
     Ok(())
 }
+
+pub(crate) fn call_soon(
+    py: Python,
+    loop_: &PyObject,
+    callback: &PyObject,
+    arg: PyObject,
+) -> PyResult<()> {
+    loop_.call_method1(py, "call_soon_threadsafe", (callback.clone_ref(py), arg))?;
+    Ok(())
+}
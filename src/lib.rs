@@ -3,23 +3,31 @@
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate smart_default;
 
 pub(crate) mod connection;
 pub(crate) mod error;
 pub(crate) mod futures;
+pub(crate) mod live;
+pub(crate) mod loopback;
+#[cfg(test)]
+pub(crate) mod mock;
+pub(crate) mod mp3;
+pub(crate) mod opus;
 pub(crate) mod payload;
 pub(crate) mod player;
+pub(crate) mod probe;
+pub(crate) mod proxy;
 pub(crate) mod recorder;
+pub(crate) mod resample;
 pub(crate) mod state;
 pub(crate) mod ws;
 
-use pyo3::prelude::*;
+use pyo3::{prelude::*, wrap_pyfunction};
 use pyo3_log::{Caching, Logger};
 
-use crate::{
-    connection::{VoiceConnection, VoiceConnector},
-    error::*,
-};
+use crate::{connection::*, error::*};
 
 #[pymodule]
 fn ffi(py: Python, m: &PyModule) -> PyResult<()> {
@@ -27,12 +35,24 @@ fn ffi(py: Python, m: &PyModule) -> PyResult<()> {
 
     m.add_class::<VoiceConnector>()?;
     m.add_class::<VoiceConnection>()?;
+    m.add_class::<RecordStream>()?;
     m.add("MissingFieldError", py.get_type::<MissingFieldError>())?;
     m.add("InternalError", py.get_type::<InternalError>())?;
     m.add("InternalIOError", py.get_type::<InternalIOError>())?;
     m.add("TlsError", py.get_type::<TlsError>())?;
     m.add("GatewayError", py.get_type::<GatewayError>())?;
     m.add("TryReconnect", py.get_type::<TryReconnect>())?;
+    m.add("SessionInvalidated", py.get_type::<SessionInvalidated>())?;
     m.add("EncryptionFailed", py.get_type::<EncryptionFailed>())?;
+    m.add("ConnectionCancelled", py.get_type::<ConnectionCancelled>())?;
+    m.add("TooManyPlayers", py.get_type::<TooManyPlayers>())?;
+    m.add("UdpDiscoveryError", py.get_type::<UdpDiscoveryError>())?;
+    m.add("UdpDiscoveryTimeout", py.get_type::<UdpDiscoveryTimeout>())?;
+    m.add_function(wrap_pyfunction!(active_player_count, m)?)?;
+    m.add_function(wrap_pyfunction!(active_recorder_count, m)?)?;
+    m.add_function(wrap_pyfunction!(set_max_players, m)?)?;
+    m.add_function(wrap_pyfunction!(channel_encoder_settings, m)?)?;
+    m.add_function(wrap_pyfunction!(probe, m)?)?;
+    m.add_function(wrap_pyfunction!(supported_encryption_modes, m)?)?;
     Ok(())
 }
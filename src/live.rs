@@ -0,0 +1,269 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::{
+    error::Result,
+    recorder::{AudioDecoder, Level, RecordGains, RecordOptions, SsrcPacketQueue},
+    ws::VoiceGateway,
+};
+
+/// Maximum number of PCM chunks waiting for the consumer.
+/// Older chunks are dropped when the consumer falls behind.
+pub(crate) const MAX_PENDING_CHUNKS: usize = 16;
+
+/// Milliseconds allowed between two level measurements.
+pub(crate) const LEVEL_INTERVAL_MS: std::ops::RangeInclusive<u64> = 20..=1000;
+
+/// Number of speakers whose levels are measured, those with the most
+/// packets, so that a large channel costs no more than this many decoders.
+const MAX_LEVEL_SPEAKERS: usize = 16;
+
+struct Pending(Mutex<VecDeque<Vec<i16>>>, Condvar);
+
+/// Periodically drains the packet queue of the running recorder and hands
+/// the mixed PCM to a consumer at a fixed cadence. `finished` is called
+/// after the last chunk, once the tap is stopped or decoding failed.
+pub(crate) struct LiveTap {
+    stopped: Arc<AtomicBool>,
+    pending: Arc<Pending>,
+    decode_thread: thread::JoinHandle<()>,
+    deliver_thread: thread::JoinHandle<()>,
+}
+
+fn decode_loop(
+    gateway: &Arc<Mutex<VoiceGateway>>,
+    queue: &Arc<Mutex<SsrcPacketQueue>>,
+    gains: &Mutex<RecordGains>,
+    options: &RecordOptions,
+    interval: time::Duration,
+    stopped: &AtomicBool,
+    pending: &Pending,
+) -> Result<()> {
+    let mut decoder = {
+        let gateway = gateway.lock();
+        AudioDecoder::from_gateway(&*gateway, options)?
+    };
+    let mut next_iteration = time::Instant::now();
+    while !stopped.load(Ordering::Acquire) {
+        next_iteration += interval;
+        let now = time::Instant::now();
+        next_iteration = next_iteration.max(now);
+        thread::sleep(next_iteration - now);
+
+        // Gains may change while recording, so they are read every time.
+        decoder.set_gains(gains.lock().clone());
+        decoder.set_users(gateway.lock().ssrc_map.clone());
+        let mut taken = queue.lock().take();
+        if let Some(pcm) = taken.mix(&mut decoder, options)? {
+            let mut chunks = pending.0.lock();
+            if chunks.len() >= MAX_PENDING_CHUNKS {
+                warn!("Live record consumer is too slow; dropping old audio");
+                chunks.pop_front();
+            }
+            chunks.push_back(pcm);
+            pending.1.notify_one();
+        }
+    }
+    Ok(())
+}
+
+impl LiveTap {
+    pub fn new<Consumer, Finished>(
+        consumer: Consumer,
+        finished: Finished,
+        gateway: Arc<Mutex<VoiceGateway>>,
+        queue: Arc<Mutex<SsrcPacketQueue>>,
+        gains: Arc<Mutex<RecordGains>>,
+        options: RecordOptions,
+        interval: time::Duration,
+    ) -> Self
+    where
+        Consumer: Fn(Vec<i16>) + Send + 'static,
+        Finished: FnOnce() + Send + 'static,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let pending = Arc::new(Pending(Mutex::new(VecDeque::new()), Condvar::new()));
+
+        let decode_thread = {
+            let stopped = Arc::clone(&stopped);
+            let pending = Arc::clone(&pending);
+            thread::spawn(move || {
+                if let Err(e) = decode_loop(
+                    &gateway, &queue, &gains, &options, interval, &stopped, &pending,
+                ) {
+                    error!("Live record stopped: {}", e);
+                }
+                let _chunks = pending.0.lock();
+                stopped.store(true, Ordering::Release);
+                pending.1.notify_all();
+            })
+        };
+
+        let deliver_thread = {
+            let stopped = Arc::clone(&stopped);
+            let pending = Arc::clone(&pending);
+            thread::spawn(move || {
+                loop {
+                    let chunk = {
+                        let mut chunks = pending.0.lock();
+                        while chunks.is_empty() && !stopped.load(Ordering::Acquire) {
+                            pending.1.wait(&mut chunks);
+                        }
+                        match chunks.pop_front() {
+                            Some(chunk) => chunk,
+                            None => break,
+                        }
+                    };
+                    consumer(chunk);
+                }
+                finished();
+            })
+        };
+
+        Self {
+            stopped,
+            pending,
+            decode_thread,
+            deliver_thread,
+        }
+    }
+
+    /// Stops the tap and waits up to `timeout` for both threads to exit.
+    /// Returns `false` if a thread is still running after the timeout.
+    pub fn stop_and_join(self, timeout: time::Duration) -> bool {
+        {
+            let _chunks = self.pending.0.lock();
+            self.stopped.store(true, Ordering::Release);
+            self.pending.1.notify_all();
+        }
+        let deadline = time::Instant::now() + timeout;
+        while !(self.decode_thread.is_finished() && self.deliver_thread.is_finished()) {
+            if time::Instant::now() >= deadline {
+                warn!("Live record threads did not finish within {:?}", timeout);
+                return false;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        let _ = self.decode_thread.join();
+        let _ = self.deliver_thread.join();
+        true
+    }
+}
+
+/// The level of a speaker, with their user ID if it is known.
+pub(crate) type SpeakerLevel = (u32, Option<String>, Level);
+
+/// Periodically measures the level of each speaker over the last interval
+/// of the running recorder and hands them to a consumer, without taking the
+/// packets from the recording. Once everyone is quiet, the consumer is given
+/// one empty list and then nothing until someone speaks again.
+pub(crate) struct LevelMeter {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: thread::JoinHandle<()>,
+}
+
+fn measure_loop<Consumer>(
+    gateway: &Arc<Mutex<VoiceGateway>>,
+    queue: &Arc<Mutex<SsrcPacketQueue>>,
+    interval: time::Duration,
+    stopped: &(Mutex<bool>, Condvar),
+    consumer: Consumer,
+) -> Result<()>
+where
+    Consumer: Fn(Vec<SpeakerLevel>),
+{
+    // Only levels are measured, so the audio is decoded at the lowest rate
+    // that still covers speech.
+    let options = RecordOptions {
+        channels: 1,
+        sample_rate: 16000,
+        ..Default::default()
+    };
+    let mut decoder = {
+        let gateway = gateway.lock();
+        AudioDecoder::from_gateway(&*gateway, &options)?
+    };
+    let mut quiet = true;
+    let mut next_iteration = time::Instant::now();
+    loop {
+        next_iteration += interval;
+        next_iteration = next_iteration.max(time::Instant::now());
+        {
+            // Waits on the condvar rather than sleeping so that stopping
+            // does not wait for the interval.
+            let (lock, condvar) = stopped;
+            let mut stopped = lock.lock();
+            while !*stopped && !condvar.wait_until(&mut stopped, next_iteration).timed_out() {}
+            if *stopped {
+                break;
+            }
+        }
+        // Copied so that the receiver is not blocked while decoding.
+        let mut recent = queue.lock().recent(interval.as_secs_f64());
+        let levels = recent.levels(&mut decoder, MAX_LEVEL_SPEAKERS);
+        if levels.is_empty() && quiet {
+            continue;
+        }
+        quiet = levels.is_empty();
+        let users = gateway.lock().ssrc_map.clone();
+        consumer(
+            levels
+                .into_iter()
+                .map(|(ssrc, level)| (ssrc, users.get(&ssrc).cloned(), level))
+                .collect(),
+        );
+    }
+    Ok(())
+}
+
+impl LevelMeter {
+    pub fn new<Consumer>(
+        consumer: Consumer,
+        gateway: Arc<Mutex<VoiceGateway>>,
+        queue: Arc<Mutex<SsrcPacketQueue>>,
+        interval: time::Duration,
+    ) -> Self
+    where
+        Consumer: Fn(Vec<SpeakerLevel>) + Send + 'static,
+    {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || {
+                if let Err(e) = measure_loop(&gateway, &queue, interval, &stopped, consumer) {
+                    error!("Level meter stopped: {}", e);
+                }
+            })
+        };
+        Self { stopped, thread }
+    }
+
+    /// Stops the meter and waits up to `timeout` for its thread to exit.
+    /// Returns `false` if it is still running after the timeout, e.g. because
+    /// the consumer is blocked.
+    pub fn stop_and_join(self, timeout: time::Duration) -> bool {
+        {
+            let (lock, condvar) = &*self.stopped;
+            *lock.lock() = true;
+            condvar.notify_all();
+        }
+        let deadline = time::Instant::now() + timeout;
+        while !self.thread.is_finished() {
+            if time::Instant::now() >= deadline {
+                warn!("Level meter thread did not finish within {:?}", timeout);
+                return false;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        let _ = self.thread.join();
+        true
+    }
+}
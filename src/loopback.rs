@@ -0,0 +1,78 @@
+use std::{io::Cursor, sync::Arc};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use parking_lot::Mutex;
+
+use crate::{
+    error::Result,
+    player::{CHANNELS, SAMPLING_RATE},
+};
+
+/// Samples per channel of the longest Opus packet, 120ms at 48kHz.
+const MAX_PACKET_SAMPLES: usize = 5760;
+
+/// Capture shared by a connection and its players. `None` while nothing is
+/// captured.
+pub(crate) type SharedLoopback = Arc<Mutex<Option<Loopback>>>;
+
+/// The Opus frames the players of a connection sent, so that a test can
+/// check what was transmitted without a second client listening.
+#[derive(Debug, Default)]
+pub(crate) struct Loopback {
+    frames: Vec<Vec<u8>>,
+}
+
+impl Loopback {
+    pub(crate) fn push(&mut self, frame: Vec<u8>) {
+        self.frames.push(frame);
+    }
+
+    /// Decodes the frames into a WAV file, one after the other. Nothing is
+    /// sent while paused, muted or disconnected, so none of these spans are
+    /// in the file either.
+    pub(crate) fn decode(&self) -> Result<Vec<u8>> {
+        let mut decoder = audiopus::coder::Decoder::new(
+            audiopus::SampleRate::Hz48000,
+            audiopus::Channels::Stereo,
+        )?;
+        let mut pcm = [0i16; MAX_PACKET_SAMPLES * CHANNELS as usize];
+        let wavspec = WavSpec {
+            channels: CHANNELS,
+            sample_rate: SAMPLING_RATE as u32,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut buffer = vec![];
+        {
+            let mut wavwriter = WavWriter::new(Cursor::new(&mut buffer), wavspec)?;
+            for frame in &self.frames {
+                let samples = decoder.decode(Some(&frame[..]), &mut pcm[..], false)?;
+                for &sample in &pcm[..samples * CHANNELS as usize] {
+                    wavwriter.write_sample(sample)?;
+                }
+            }
+            wavwriter.finalize()?;
+        }
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hound::WavReader;
+
+    use super::*;
+    use crate::player::{OPUS_SILENCE, SAMPLES_PER_FRAME};
+
+    #[test]
+    fn captured_frames_decode_in_order() {
+        let mut loopback = Loopback::default();
+        for _ in 0..3 {
+            loopback.push(OPUS_SILENCE.to_vec());
+        }
+        let wav = loopback.decode().unwrap();
+        let reader = WavReader::new(Cursor::new(wav)).unwrap();
+        assert_eq!(reader.spec().channels, CHANNELS);
+        assert_eq!(reader.duration(), 3 * SAMPLES_PER_FRAME);
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    io,
+    net::{TcpListener, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use serde_json::json;
+use tungstenite::{handshake::HandshakeError, Message};
+
+pub(crate) const MOCK_SSRC: u32 = 12345;
+pub(crate) const MOCK_SECRET_KEY: [u8; 32] = [7; 32];
+pub(crate) const MOCK_ENCRYPTION_MODE: &str = "xsalsa20_poly1305_lite";
+
+/// A voice server on `127.0.0.1` that speaks just enough of the voice
+/// protocol to run the gateway, player and recorder without Discord.
+/// It serves a single connection.
+///
+/// The websocket answers IDENTIFY with READY, SELECT_PROTOCOL with
+/// SESSION_DESCRIPTION, HEARTBEAT with HEARTBEAT_ACK and RESUME with
+/// RESUMED. The UDP socket
+/// answers IP discovery and echoes every other datagram back to its sender.
+pub(crate) struct MockGateway {
+    pub port: u16,
+    stopped: Arc<AtomicBool>,
+    ws_thread: thread::JoinHandle<()>,
+    udp_thread: thread::JoinHandle<()>,
+}
+
+impl MockGateway {
+    pub(crate) fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let udp = UdpSocket::bind("127.0.0.1:0")?;
+        udp.set_read_timeout(Some(time::Duration::from_millis(100)))?;
+        let port = listener.local_addr()?.port();
+        let udp_port = udp.local_addr()?.port();
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let ws_thread = thread::spawn(move || {
+            if let Err(e) = serve_ws(listener, udp_port) {
+                warn!("Mock gateway stopped: {}", e);
+            }
+        });
+        let udp_thread = {
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || serve_udp(&udp, &stopped))
+        };
+
+        Ok(Self {
+            port,
+            stopped,
+            ws_thread,
+            udp_thread,
+        })
+    }
+
+    /// Stops the UDP server and waits for both threads. The websocket thread
+    /// exits once the client closes the connection.
+    pub(crate) fn stop(self) {
+        self.stopped.store(true, Ordering::Release);
+        let _ = self.udp_thread.join();
+        let _ = self.ws_thread.join();
+    }
+}
+
+fn serve_ws(listener: TcpListener, udp_port: u16) -> tungstenite::Result<()> {
+    let (stream, _) = listener.accept()?;
+    let mut ws = tungstenite::accept(stream).map_err(|e| match e {
+        HandshakeError::Failure(e) => e,
+        HandshakeError::Interrupted(_) => {
+            tungstenite::Error::Io(io::Error::new(io::ErrorKind::WouldBlock, "interrupted"))
+        }
+    })?;
+    let send = |payload: serde_json::Value| Message::text(payload.to_string());
+
+    ws.write_message(send(json!({"op": 8, "d": {"heartbeat_interval": 41250.0}})))?;
+    loop {
+        let text = match ws.read_message()? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let payload: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(payload) => payload,
+            Err(_) => continue,
+        };
+        let reply = match payload["op"].as_u64() {
+            Some(0) => json!({
+                "op": 2,
+                "d": {
+                    "ssrc": MOCK_SSRC,
+                    "ip": "127.0.0.1",
+                    "port": udp_port,
+                    "modes": [MOCK_ENCRYPTION_MODE],
+                }
+            }),
+            Some(1) => json!({
+                "op": 4,
+                "d": {
+                    "mode": MOCK_ENCRYPTION_MODE,
+                    "secret_key": MOCK_SECRET_KEY,
+                }
+            }),
+            Some(3) => json!({"op": 6, "d": payload["d"]}),
+            Some(7) => json!({"op": 9, "d": null}),
+            _ => continue,
+        };
+        ws.write_message(send(reply))?;
+    }
+    Ok(())
+}
+
+fn serve_udp(socket: &UdpSocket, stopped: &AtomicBool) {
+    let mut buff = [0u8; 2048];
+    while !stopped.load(Ordering::Acquire) {
+        let (size, addr) = match socket.recv_from(&mut buff) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        if size == 70 && buff[0..2] == 1u16.to_be_bytes() {
+            let mut reply = [0u8; 70];
+            reply[0..2].copy_from_slice(&2u16.to_be_bytes());
+            reply[2..4].copy_from_slice(&70u16.to_be_bytes());
+            let ip = addr.ip().to_string();
+            reply[4..4 + ip.len()].copy_from_slice(ip.as_bytes());
+            reply[68..70].copy_from_slice(&addr.port().to_be_bytes());
+            let _ = socket.send_to(&reply, addr);
+        } else {
+            let _ = socket.send_to(&buff[..size], addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::{
+        player::{AudioInput, AudioPlayer, EncoderOptions, SAMPLES_PER_FRAME, SAMPLING_RATE},
+        recorder::{AudioDecoder, AudioRecorder, RecordOptions, SsrcPacketQueue},
+        state::ConnectionState,
+        ws::{SessionState, VoiceGatewayBuilder},
+    };
+
+    const TONE_FREQUENCY: f64 = 440.0;
+    const TONE_AMPLITUDE: f64 = 0.5;
+
+    /// A stereo sine wave lasting `frames` frames.
+    struct Tone {
+        frame: usize,
+        frames: usize,
+    }
+
+    impl AudioInput for Tone {
+        fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+            if self.frame >= self.frames {
+                return None;
+            }
+            for (i, sample) in buffer.chunks_mut(2).enumerate() {
+                let n = self.frame * SAMPLES_PER_FRAME as usize + i;
+                let t = n as f64 / SAMPLING_RATE as f64;
+                let value = (2.0 * std::f64::consts::PI * TONE_FREQUENCY * t).sin();
+                let value = (value * TONE_AMPLITUDE * 32767.0) as i16;
+                sample.iter_mut().for_each(|s| *s = value);
+            }
+            self.frame += 1;
+            Some(buffer.len())
+        }
+    }
+
+    #[test]
+    fn play_and_record_round_trip() {
+        let mock = MockGateway::start().unwrap();
+
+        let mut builder = VoiceGatewayBuilder::default();
+        builder
+            .endpoint("127.0.0.1")
+            .user_id("1")
+            .server_id("2")
+            .session_id("3")
+            .token("4")
+            .port(mock.port)
+            .plain(true);
+        let mut gateway = builder.connect(&|_| {}).unwrap();
+        gateway.connection_flow(false, &|_| {}).unwrap();
+        assert_eq!(gateway.ssrc, MOCK_SSRC);
+        assert_eq!(gateway.secret_key, MOCK_SECRET_KEY);
+        let gateway = Arc::new(Mutex::new(gateway));
+
+        // The player sets the shared state when it starts, so the recorder
+        // has to be started after it.
+        let (played, finished) = mpsc::channel();
+        let source: Box<dyn AudioInput> = Box::new(Tone {
+            frame: 0,
+            frames: 50,
+        });
+        let player = AudioPlayer::new(
+            move |err| {
+                let _ = played.send(err.map(|e| e.to_string()));
+            },
+            Arc::clone(&gateway),
+            Arc::new(Mutex::new(source.into())),
+            EncoderOptions::default(),
+            Arc::new(Mutex::new(None)),
+        );
+        let queue = Arc::new(Mutex::new(SsrcPacketQueue::new()));
+        let options = RecordOptions::default();
+        let recorder = AudioRecorder::new(
+            |_| {},
+            Arc::clone(&gateway),
+            Arc::clone(&queue),
+            options,
+            None,
+            Arc::default(),
+        );
+
+        let err = finished
+            .recv_timeout(time::Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(err, None);
+        assert!(player.stop_and_join(time::Duration::from_secs(1)));
+        assert!(recorder.stop_and_join(time::Duration::from_secs(5)));
+
+        let mut decoder = AudioDecoder::from_gateway(&*gateway.lock(), &options).unwrap();
+        let mut queue = queue.lock();
+        let pcm = queue
+            .mix(&mut decoder, &options)
+            .unwrap()
+            .expect("nothing recorded");
+        assert_eq!(queue.speakers(), vec![MOCK_SSRC]);
+        assert_eq!(queue.active_speakers(60.0), vec![MOCK_SSRC]);
+        assert!(queue.stats().streams[&MOCK_SSRC].decoded > 0);
+
+        let rms = (pcm
+            .iter()
+            .map(|&s| (s as f64 / 32767.0).powi(2))
+            .sum::<f64>()
+            / pcm.len() as f64)
+            .sqrt();
+        assert!(
+            rms > TONE_AMPLITUDE / 4.0,
+            "recorded audio is too quiet: {}",
+            rms
+        );
+
+        gateway.lock().close(1000).unwrap();
+        mock.stop();
+    }
+
+    #[test]
+    fn resume_restores_connected_state() {
+        let mock = MockGateway::start().unwrap();
+
+        let mut builder = VoiceGatewayBuilder::default();
+        builder
+            .endpoint("127.0.0.1")
+            .user_id("1")
+            .server_id("2")
+            .session_id("3")
+            .token("4")
+            .port(mock.port)
+            .plain(true);
+        let mut gateway = builder.connect(&|_| {}).unwrap();
+        gateway
+            .restore_session(&SessionState {
+                ssrc: MOCK_SSRC,
+                endpoint_ip: String::from("127.0.0.1"),
+                port: mock.port,
+                secret_key: MOCK_SECRET_KEY,
+                ..SessionState::default()
+            })
+            .unwrap();
+        assert!(gateway.state.is_state(ConnectionState::Disconnected));
+
+        let (progress, steps) = mpsc::channel();
+        gateway
+            .connection_flow(true, &|step| {
+                let _ = progress.send(step.to_string());
+            })
+            .unwrap();
+        assert_eq!(steps.try_iter().collect::<Vec<_>>(), ["hello", "resumed"]);
+        assert!(gateway.state.is_state(ConnectionState::Connected));
+        assert!(gateway.is_ready());
+
+        gateway.close(1000).unwrap();
+        mock.stop();
+    }
+}
@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, InterleavedPcm, MonoPcm, Quality};
+
+use crate::error::{DiscordError, Result};
+
+/// Serialization of a decoded recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SmartDefault)]
+pub(crate) enum RecordFormat {
+    #[default]
+    Wav,
+    Mp3,
+}
+
+impl FromStr for RecordFormat {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wav" => Ok(RecordFormat::Wav),
+            "mp3" => Ok(RecordFormat::Mp3),
+            _ => Err(DiscordError::InvalidArgument(format!(
+                "Unknown format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Default MP3 bitrate in kbps.
+pub(crate) const DEFAULT_MP3_BITRATE: u32 = 128;
+
+/// Sample rates MPEG-1, 2 and 2.5 layer III can store.
+const MP3_SAMPLE_RATES: [u32; 9] = [8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+pub(crate) fn mp3_bitrate(kbps: u32) -> Result<Bitrate> {
+    Ok(match kbps {
+        8 => Bitrate::Kbps8,
+        16 => Bitrate::Kbps16,
+        24 => Bitrate::Kbps24,
+        32 => Bitrate::Kbps32,
+        40 => Bitrate::Kbps40,
+        48 => Bitrate::Kbps48,
+        64 => Bitrate::Kbps64,
+        80 => Bitrate::Kbps80,
+        96 => Bitrate::Kbps96,
+        112 => Bitrate::Kbps112,
+        128 => Bitrate::Kbps128,
+        160 => Bitrate::Kbps160,
+        192 => Bitrate::Kbps192,
+        224 => Bitrate::Kbps224,
+        256 => Bitrate::Kbps256,
+        320 => Bitrate::Kbps320,
+        kbps => {
+            return Err(DiscordError::InvalidArgument(format!(
+                "Unsupported MP3 bitrate: {}kbps",
+                kbps
+            )))
+        }
+    })
+}
+
+/// Fails unless an MP3 can hold `channels` channels at `sample_rate`.
+pub(crate) fn check_mp3_layout(channels: u16, sample_rate: u32) -> Result<()> {
+    if !(1..=2).contains(&channels) {
+        return Err(DiscordError::InvalidArgument(format!(
+            "MP3 holds 1 or 2 channels, not {}; use the mixed layout or WAV",
+            channels
+        )));
+    }
+    if !MP3_SAMPLE_RATES.contains(&sample_rate) {
+        return Err(DiscordError::InvalidArgument(format!(
+            "Unsupported MP3 sample rate: {}",
+            sample_rate
+        )));
+    }
+    Ok(())
+}
+
+fn encode_error<E: std::fmt::Display>(e: E) -> DiscordError {
+    DiscordError::Mp3EncodeFailed(e.to_string())
+}
+
+/// Encodes interleaved `samples` to a constant bitrate MP3 with LAME.
+pub(crate) fn encode_mp3(
+    samples: &[i16],
+    channels: u16,
+    sample_rate: u32,
+    kbps: u32,
+) -> Result<Vec<u8>> {
+    check_mp3_layout(channels, sample_rate)?;
+    let mut builder =
+        Builder::new().ok_or_else(|| encode_error("Could not allocate the LAME encoder"))?;
+    builder
+        .set_num_channels(channels as u8)
+        .map_err(encode_error)?;
+    builder.set_sample_rate(sample_rate).map_err(encode_error)?;
+    builder
+        .set_brate(mp3_bitrate(kbps)?)
+        .map_err(encode_error)?;
+    builder.set_quality(Quality::Good).map_err(encode_error)?;
+    let mut encoder = builder.build().map_err(encode_error)?;
+
+    let frames = samples.len() / channels as usize;
+    let mut output = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(frames));
+    let size = if channels == 1 {
+        encoder.encode(MonoPcm(samples), output.spare_capacity_mut())
+    } else {
+        encoder.encode(InterleavedPcm(samples), output.spare_capacity_mut())
+    }
+    .map_err(encode_error)?;
+    // SAFETY: LAME initialized the first `size` bytes of the spare capacity.
+    unsafe { output.set_len(size) };
+
+    // The last frame and the padding of the encoder.
+    output.reserve(7200);
+    let size = encoder
+        .flush::<FlushNoGap>(output.spare_capacity_mut())
+        .map_err(encode_error)?;
+    unsafe { output.set_len(output.len() + size) };
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mp3_frames_are_encoded() {
+        let samples = (0..48000)
+            .flat_map(|n| {
+                let s = (0.3 * (n as f64 * 440.0 * std::f64::consts::TAU / 48000.0).sin() * 32767.0)
+                    as i16;
+                vec![s, s]
+            })
+            .collect::<Vec<_>>();
+        let mp3 = encode_mp3(&samples, 2, 48000, 128).unwrap();
+        // Frame sync of the first MPEG audio frame.
+        assert_eq!(mp3[0], 0xFF);
+        assert_eq!(mp3[1] & 0xE0, 0xE0);
+        // One second at 128kbps, give or take the padding.
+        assert!((14_000..20_000).contains(&mp3.len()), "{}", mp3.len());
+    }
+
+    #[test]
+    fn unsupported_mp3_settings_are_rejected() {
+        assert!(mp3_bitrate(100).is_err());
+        assert!(check_mp3_layout(3, 48000).is_err());
+        assert!(check_mp3_layout(2, 96000).is_err());
+        assert!(check_mp3_layout(1, 44100).is_ok());
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, Cursor, ErrorKind, Read},
+};
+
+use crate::error::{DiscordError, Result};
+
+/// Largest Opus packet, from RFC 6716.
+pub(crate) const MAX_OPUS_PACKET: usize = 1275;
+
+/// A source of Opus packets that are sent as they are, without decoding
+/// and encoding them again.
+pub(crate) trait OpusInput: Send {
+    /// Copies the next packet into `buffer` and returns its size, or `None`
+    /// at the end of the source.
+    fn read_opus_frame(&mut self, buffer: &mut [u8]) -> Option<usize>;
+
+    /// Called once `read_opus_frame` returned `None`, to tell whether the
+    /// source ended or failed.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn invalid_data(message: String) -> DiscordError {
+    DiscordError::IoError(io::Error::new(ErrorKind::InvalidData, message))
+}
+
+/// Longest duration of an Opus packet, 120ms at 48kHz.
+const MAX_PACKET_SAMPLES: u32 = 5760;
+
+/// Number of 48kHz samples (per channel) in an Opus packet, from its
+/// TOC byte as described in RFC 6716 section 3.1. `None` for a packet
+/// that holds no frame or more than 120ms, which RFC 6716 forbids.
+pub(crate) fn opus_packet_samples(packet: &[u8]) -> Option<u32> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    let frame = match config {
+        // SILK: 10, 20, 40 and 60ms.
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        // Hybrid: 10 and 20ms.
+        12..=15 => [480, 960][config as usize % 2],
+        // CELT: 2.5, 5, 10 and 20ms.
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+    let frames = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3F) as u32,
+    };
+    match frame * frames {
+        0 => None,
+        samples if samples > MAX_PACKET_SAMPLES => None,
+        samples => Some(samples),
+    }
+}
+
+/// Reads a DCA file, the length prefixed Opus packets used by Discord bots.
+/// Both DCA0, which has no header, and DCA1, which starts with a JSON
+/// metadata header, are accepted.
+pub(crate) struct DcaAudio {
+    reader: Box<dyn Read + Send>,
+    error: Option<DiscordError>,
+}
+
+impl DcaAudio {
+    pub(crate) fn new(path: &str) -> Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    fn from_reader<R: Read + Send + 'static>(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let reader: Box<dyn Read + Send> = if &magic == b"DCA1" {
+            let mut length = [0u8; 4];
+            reader.read_exact(&mut length)?;
+            let length = i32::from_le_bytes(length);
+            if length < 0 {
+                return Err(invalid_data(format!(
+                    "Invalid DCA header length: {}",
+                    length
+                )));
+            }
+            io::copy(&mut (&mut reader).take(length as u64), &mut io::sink())?;
+            Box::new(reader)
+        } else {
+            // DCA0 starts with the first packet.
+            Box::new(Cursor::new(magic).chain(reader))
+        };
+        Ok(Self {
+            reader,
+            error: None,
+        })
+    }
+
+    fn read_frame(&mut self, buffer: &mut [u8]) -> Result<Option<usize>> {
+        let mut length = [0u8; 2];
+        match self.reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let length = i16::from_le_bytes(length);
+        if length <= 0 || length as usize > buffer.len().min(MAX_OPUS_PACKET) {
+            return Err(invalid_data(format!(
+                "Invalid DCA frame length: {}",
+                length
+            )));
+        }
+        let length = length as usize;
+        self.reader.read_exact(&mut buffer[..length])?;
+        Ok(Some(length))
+    }
+}
+
+impl OpusInput for DcaAudio {
+    fn read_opus_frame(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        match self.read_frame(buffer) {
+            Ok(size) => size,
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+/// Reads the Opus packets of the first logical stream of an Ogg Opus file
+/// (`.opus`, `.ogg`), as described in RFC 7845.
+pub(crate) struct OggOpusAudio {
+    reader: Box<dyn Read + Send>,
+    serial: Option<u32>,
+    packets: VecDeque<Vec<u8>>,
+    partial: Vec<u8>,
+    /// Packets read so far, counting the two header packets.
+    read: usize,
+    error: Option<DiscordError>,
+}
+
+impl OggOpusAudio {
+    pub(crate) fn new(path: &str) -> Result<Self> {
+        Ok(Self::from_reader(BufReader::new(File::open(path)?)))
+    }
+
+    fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Self {
+            reader: Box::new(reader),
+            serial: None,
+            packets: VecDeque::new(),
+            partial: Vec::new(),
+            read: 0,
+            error: None,
+        }
+    }
+
+    /// Reads the next page into `packets`. Returns `false` at the end of the
+    /// file.
+    fn read_page(&mut self) -> Result<bool> {
+        let mut header = [0u8; 27];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        if &header[0..4] != b"OggS" {
+            return Err(invalid_data("Missing Ogg page capture pattern".to_string()));
+        }
+        let serial = u32::from_le_bytes([header[14], header[15], header[16], header[17]]);
+        let mut lacing = vec![0u8; header[26] as usize];
+        self.reader.read_exact(&mut lacing)?;
+        let mut data = vec![0u8; lacing.iter().map(|&l| l as usize).sum()];
+        self.reader.read_exact(&mut data)?;
+        if *self.serial.get_or_insert(serial) != serial {
+            // Another logical stream multiplexed into the file.
+            return Ok(true);
+        }
+
+        let mut offset = 0;
+        for &length in &lacing {
+            self.partial
+                .extend_from_slice(&data[offset..offset + length as usize]);
+            offset += length as usize;
+            // A lacing value below 255 ends the packet.
+            if length < 255 {
+                self.packets.push_back(std::mem::take(&mut self.partial));
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_frame(&mut self, buffer: &mut [u8]) -> Result<Option<usize>> {
+        loop {
+            let packet = match self.packets.pop_front() {
+                Some(packet) => packet,
+                None if self.read_page()? => continue,
+                None => return Ok(None),
+            };
+            self.read += 1;
+            match self.read {
+                1 if !packet.starts_with(b"OpusHead") => {
+                    return Err(invalid_data("Not an Ogg Opus stream".to_string()))
+                }
+                // OpusHead and OpusTags.
+                1 | 2 => continue,
+                _ => {}
+            }
+            if packet.is_empty() {
+                continue;
+            }
+            if packet.len() > buffer.len().min(MAX_OPUS_PACKET) {
+                return Err(invalid_data(format!(
+                    "Opus packet too large: {} bytes",
+                    packet.len()
+                )));
+            }
+            buffer[..packet.len()].copy_from_slice(&packet);
+            return Ok(Some(packet.len()));
+        }
+    }
+}
+
+impl OpusInput for OggOpusAudio {
+    fn read_opus_frame(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        match self.read_frame(buffer) {
+            Ok(size) => size,
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_durations_follow_the_toc() {
+        // CELT 20ms, one frame.
+        assert_eq!(opus_packet_samples(&[31 << 3]), Some(960));
+        // SILK 60ms, two frames.
+        assert_eq!(opus_packet_samples(&[3 << 3 | 1]), Some(5760));
+        // CELT 2.5ms, code 3 with 4 frames.
+        assert_eq!(opus_packet_samples(&[16 << 3 | 3, 4]), Some(480));
+        assert_eq!(opus_packet_samples(&[]), None);
+    }
+
+    #[test]
+    fn malformed_frame_counts_have_no_duration() {
+        // Code 3 without frames.
+        assert_eq!(opus_packet_samples(&[31 << 3 | 3, 0]), None);
+        // Code 3 with 3 frames of 60ms.
+        assert_eq!(opus_packet_samples(&[3 << 3 | 3, 3]), None);
+        // Code 3 with 6 frames of 20ms is exactly 120ms.
+        assert_eq!(opus_packet_samples(&[31 << 3 | 3, 6]), Some(5760));
+    }
+
+    #[test]
+    fn dca_frames_are_read_with_or_without_header() {
+        let frames = [2u8, 0, 0xF8, 0xFF, 1, 0, 0xFC];
+        let mut dca1 = b"DCA1".to_vec();
+        dca1.extend_from_slice(&2i32.to_le_bytes());
+        dca1.extend_from_slice(b"{}");
+        dca1.extend_from_slice(&frames);
+
+        for data in vec![frames.to_vec(), dca1] {
+            let mut audio = DcaAudio::from_reader(Cursor::new(data)).unwrap();
+            let mut buffer = [0u8; MAX_OPUS_PACKET];
+            assert_eq!(audio.read_opus_frame(&mut buffer), Some(2));
+            assert_eq!(buffer[..2], [0xF8, 0xFF]);
+            assert_eq!(audio.read_opus_frame(&mut buffer), Some(1));
+            assert_eq!(audio.read_opus_frame(&mut buffer), None);
+            assert!(audio.finish().is_ok());
+        }
+    }
+
+    fn ogg_page(serial: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = vec![];
+        for packet in packets {
+            lacing.extend(std::iter::repeat(255).take(packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+        }
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0; 10]);
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&[0; 8]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+        page
+    }
+
+    #[test]
+    fn ogg_headers_and_other_streams_are_skipped() {
+        let large = vec![0xFC; 300];
+        let mut data = ogg_page(1, &[b"OpusHead"]);
+        data.extend(ogg_page(1, &[b"OpusTags"]));
+        data.extend(ogg_page(2, &[&[0x00]]));
+        data.extend(ogg_page(1, &[&[0xF8, 0xFF], &large]));
+
+        let mut audio = OggOpusAudio::from_reader(Cursor::new(data));
+        let mut buffer = [0u8; MAX_OPUS_PACKET];
+        assert_eq!(audio.read_opus_frame(&mut buffer), Some(2));
+        assert_eq!(audio.read_opus_frame(&mut buffer), Some(300));
+        assert_eq!(audio.read_opus_frame(&mut buffer), None);
+        assert!(audio.finish().is_ok());
+    }
+
+    #[test]
+    fn ogg_without_opus_head_fails() {
+        let mut audio = OggOpusAudio::from_reader(Cursor::new(ogg_page(1, &[b"OggVorbis"])));
+        let mut buffer = [0u8; MAX_OPUS_PACKET];
+        assert_eq!(audio.read_opus_frame(&mut buffer), None);
+        assert!(audio.finish().is_err());
+    }
+}
@@ -53,7 +53,8 @@ impl OpCode {
             5 => OpCode::Speaking(serde_json::from_value(payload.d)?),
             6 => OpCode::HeartbeatAck(serde_json::from_value(payload.d)?),
             8 => OpCode::Hello(serde_json::from_value(payload.d)?),
-            9 => OpCode::Resumed(serde_json::from_value(payload.d)?),
+            // RESUMED carries no data; `d` is usually null but not always.
+            9 => OpCode::Resumed(Resumed),
             12 => OpCode::ClientConnect(serde_json::from_value(payload.d)?),
             13 => OpCode::ClientDisconnect(serde_json::from_value(payload.d)?),
             code => return Err(DiscordError::InvalidOpCode(code)),
@@ -129,11 +130,13 @@ pub(crate) struct Ready {
 }
 
 impl Ready {
+    /// The offered modes this build supports, in the order of the server.
     pub(crate) fn get_encryption_mode(&self) -> Vec<EncryptionMode> {
         let modes = self
             .modes
             .iter()
             .filter_map(|m| m.parse::<EncryptionMode>().ok())
+            .filter(|m| EncryptionMode::SUPPORTED.contains(m))
             .collect::<Vec<_>>();
         modes
     }
@@ -164,6 +167,10 @@ pub(crate) struct SessionDescription {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct Speaking {
     pub speaking: u8,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub ssrc: Option<u32>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -192,6 +199,8 @@ pub(crate) struct ClientDisconnect {}
 
 //
 
+/// The discriminants are exposed to Python as `EncryptionMode` values, so
+/// they must not change; new modes take the next value.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Copy)]
 pub(crate) enum EncryptionMode {
     XSalsa20Poly1305 = 0,
@@ -205,6 +214,25 @@ impl Default for EncryptionMode {
     }
 }
 
+impl EncryptionMode {
+    /// Modes this build can encrypt and decrypt.
+    pub(crate) const SUPPORTED: [EncryptionMode; 3] = [
+        EncryptionMode::XSalsa20Poly1305,
+        EncryptionMode::XSalsa20Poly1305Suffix,
+        EncryptionMode::XSalsa20Poly1305Lite,
+    ];
+
+    /// Bytes the encryption adds to the payload: the MAC and, depending on
+    /// the mode, the nonce.
+    pub(crate) fn overhead(self) -> usize {
+        match self {
+            EncryptionMode::XSalsa20Poly1305 => 16,
+            EncryptionMode::XSalsa20Poly1305Suffix => 16 + 24,
+            EncryptionMode::XSalsa20Poly1305Lite => 16 + 4,
+        }
+    }
+}
+
 impl FromStr for EncryptionMode {
     type Err = std::io::Error;
 
@@ -234,12 +262,17 @@ impl Into<String> for EncryptionMode {
 }
 
 pub(crate) trait Encryptor: Sized {
+    /// Encrypts the payload in `buffer` and appends the nonce as the mode
+    /// requires. `rng` draws the random nonces of the suffix mode; it is
+    /// `rand::thread_rng()` outside of tests, which seed it to get a
+    /// reproducible output.
     fn encrypt(
         &self,
         cipher: &XSalsa20Poly1305,
         nonce: u32,
         header: &[u8],
         buffer: &mut dyn Buffer,
+        rng: &mut dyn RngCore,
     ) -> std::result::Result<(), xsalsa20poly1305::aead::Error>;
 
     fn decrypt(
@@ -256,6 +289,7 @@ impl Encryptor for EncryptionMode {
         lite: u32,
         header: &[u8],
         buffer: &mut dyn Buffer,
+        rng: &mut dyn RngCore,
     ) -> std::result::Result<(), xsalsa20poly1305::aead::Error> {
         match self {
             EncryptionMode::XSalsa20Poly1305 => {
@@ -267,7 +301,7 @@ impl Encryptor for EncryptionMode {
             }
             EncryptionMode::XSalsa20Poly1305Suffix => {
                 let mut nonce = [0u8; 24];
-                rand::thread_rng().fill_bytes(&mut nonce);
+                rng.fill_bytes(&mut nonce);
                 let nonce = GenericArray::from_slice(&nonce);
                 cipher.encrypt_in_place(nonce, b"", buffer)?;
                 buffer.extend_from_slice(&nonce)?;
@@ -335,3 +369,79 @@ bitflags! {
         const PROPRITY   = 0b0000_0100;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use xsalsa20poly1305::aead::NewAead;
+
+    use super::*;
+    use crate::player::AudioBuffer;
+
+    const HEADER: [u8; 12] = [0x80, 0x78, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+    const PAYLOAD: &[u8] = &[0xF8, 0xFF, 0xFE, 1, 2, 3];
+
+    fn cipher() -> XSalsa20Poly1305 {
+        XSalsa20Poly1305::new(GenericArray::from_slice(&[7u8; 32]))
+    }
+
+    /// Encrypts `PAYLOAD` in suffix mode with an RNG seeded with `seed` and
+    /// returns the whole packet.
+    fn suffix_packet(seed: u64) -> Vec<u8> {
+        let mut data = [0u8; 128];
+        data[..12].copy_from_slice(&HEADER);
+        data[12..12 + PAYLOAD.len()].copy_from_slice(PAYLOAD);
+        let mut buffer = AudioBuffer::new(&mut data[12..], PAYLOAD.len());
+        EncryptionMode::XSalsa20Poly1305Suffix
+            .encrypt(
+                &cipher(),
+                0,
+                &HEADER,
+                &mut buffer,
+                &mut StdRng::seed_from_u64(seed),
+            )
+            .unwrap();
+        let size = 12 + buffer.as_ref().len();
+        data[..size].to_vec()
+    }
+
+    #[test]
+    fn unknown_modes_are_not_selected() {
+        let ready: Ready = serde_json::from_str(
+            r#"{"ssrc": 1, "ip": "127.0.0.1", "port": 9,
+                "modes": ["aead_aes256_gcm", "xsalsa20_poly1305_lite", "xsalsa20_poly1305"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ready.get_encryption_mode(),
+            [
+                EncryptionMode::XSalsa20Poly1305Lite,
+                EncryptionMode::XSalsa20Poly1305
+            ]
+        );
+    }
+
+    #[test]
+    fn suffix_nonce_comes_from_the_rng() {
+        let packet = suffix_packet(42);
+        assert_eq!(packet.len(), 12 + PAYLOAD.len() + 16 + 24);
+        assert_eq!(packet, suffix_packet(42));
+        assert_ne!(packet, suffix_packet(43));
+
+        let mut nonce = [0u8; 24];
+        StdRng::seed_from_u64(42).fill_bytes(&mut nonce);
+        assert_eq!(packet[packet.len() - 24..], nonce);
+    }
+
+    #[test]
+    fn suffix_packet_round_trips() {
+        let mut data = suffix_packet(42);
+        let size = data.len();
+        let mut buffer = AudioBuffer::new(&mut data, size);
+        let header = EncryptionMode::XSalsa20Poly1305Suffix
+            .decrypt(&cipher(), &mut buffer)
+            .unwrap();
+        assert_eq!(header, HEADER);
+        assert_eq!(buffer.as_ref(), PAYLOAD);
+    }
+}
@@ -1,4 +1,5 @@
-use parking_lot::Mutex;
+use audiopus::coder::GenericCtl;
+use parking_lot::{Condvar, Mutex};
 use xsalsa20poly1305::{
     aead::{generic_array::GenericArray, Buffer, Error, NewAead},
     XSalsa20Poly1305,
@@ -6,18 +7,23 @@ use xsalsa20poly1305::{
 
 use crate::{
     error::{DiscordError, Result},
+    loopback::SharedLoopback,
+    opus::{opus_packet_samples, OpusInput, MAX_OPUS_PACKET},
     payload::{EncryptionMode, Encryptor, SpeakingType},
-    state::{ConnectionState, State},
+    resample::resample,
+    state::{ActiveGuard, ConnectionState, State, ACTIVE_PLAYERS},
     ws::VoiceGateway,
 };
 
 use std::{
+    collections::VecDeque,
     fmt,
     io::{ErrorKind, Read},
     net::{SocketAddr, UdpSocket},
     process::{Child, Command, Stdio},
     slice,
-    sync::Arc,
+    str::FromStr,
+    sync::{atomic::Ordering, mpsc, Arc},
     thread, time,
 };
 
@@ -37,30 +43,53 @@ pub const FRAME_SIZE: u32 = SAMPLES_PER_FRAME * SAMPLE_SIZE as u32;
 
 pub(crate) trait AudioInput: Send {
     fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize>;
+
+    /// Called once `read_pcm_frame` returned `None`, to tell whether the
+    /// source ended or failed.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
+/// What a player reads from: PCM that is encoded on the fly, or Opus
+/// packets that are sent without re-encoding.
+pub(crate) enum AudioSource {
+    Pcm(Box<dyn AudioInput>),
+    Opus(Box<dyn OpusInput>),
+}
+
+impl From<Box<dyn AudioInput>> for AudioSource {
+    fn from(audio: Box<dyn AudioInput>) -> Self {
+        AudioSource::Pcm(audio)
+    }
+}
+
+/// Time given to ffmpeg to exit after its output ended.
+const FFMPEG_EXIT_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
 pub(crate) struct FFmpegAudio {
     process: Child,
 }
 
 impl FFmpegAudio {
     pub(crate) fn new(input: &str) -> Result<Self> {
-        let process = Command::new("ffmpeg")
-            .arg("-i")
-            .arg(input)
-            .args(&[
-                "-f",
-                "s16le",
-                "-ar",
-                "48000",
-                "-ac",
-                "2",
-                "-loglevel",
-                "warning",
-                "pipe:1",
-            ])
-            .stdout(Stdio::piped())
-            .spawn()?;
+        let mut command = Command::new("ffmpeg");
+        command.arg("-i").arg(input).args(&[
+            "-f",
+            "s16le",
+            "-ar",
+            "48000",
+            "-ac",
+            "2",
+            "-loglevel",
+            "warning",
+            "pipe:1",
+        ]);
+        Self::spawn(command)
+    }
+
+    fn spawn(mut command: Command) -> Result<Self> {
+        let process = command.stdout(Stdio::piped()).spawn()?;
         Ok(Self { process })
     }
 }
@@ -70,18 +99,213 @@ impl AudioInput for FFmpegAudio {
         let stdout = self.process.stdout.as_mut()?;
         let bytes =
             unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 2) };
-        stdout.read_exact(bytes).map(|_| buffer.len()).ok()
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match stdout.read(&mut bytes[filled..]) {
+                Ok(0) => break,
+                Ok(size) => filled += size,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to read from ffmpeg: {:?}", e);
+                    break;
+                }
+            }
+        }
+        if filled == 0 {
+            return None;
+        }
+        // The last frame is padded with silence so that the tail is played.
+        bytes[filled..].iter_mut().for_each(|b| *b = 0);
+        Some(filled / 2)
+    }
+
+    /// Fails with the exit status of ffmpeg unless it exited successfully.
+    /// A process that is still running after `FFMPEG_EXIT_TIMEOUT` is killed
+    /// and reported as such.
+    fn finish(&mut self) -> Result<()> {
+        let started = time::Instant::now();
+        let status = loop {
+            if let Some(status) = self.process.try_wait()? {
+                break status;
+            }
+            if started.elapsed() >= FFMPEG_EXIT_TIMEOUT {
+                self.process.kill()?;
+                break self.process.wait()?;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        };
+        if status.success() {
+            Ok(())
+        } else {
+            Err(DiscordError::FFmpegExited(status))
+        }
     }
 }
 
 impl Drop for FFmpegAudio {
     fn drop(&mut self) {
+        if let Ok(Some(_)) = self.process.try_wait() {
+            return;
+        }
         if let Err(e) = self.process.kill() {
             error!("Could not kill ffmpeg process: {:?}", e);
         }
     }
 }
 
+/// Plays a WAV file without ffmpeg. The file is decoded and converted to
+/// 48kHz stereo up front, which suits short clips such as sound effects.
+pub(crate) struct WavFileAudio {
+    samples: Vec<i16>,
+    position: usize,
+}
+
+impl WavFileAudio {
+    pub(crate) fn new(path: &str) -> Result<Self> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .samples::<f32>()
+                .collect::<std::result::Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 / scale))
+                    .collect::<std::result::Result<_, _>>()?
+            }
+        };
+        let stereo = to_stereo(&samples, spec.channels as usize);
+        let resampled = resample(&stereo, 2, spec.sample_rate, SAMPLING_RATE as u32)?;
+        Ok(Self {
+            samples: resampled
+                .iter()
+                .map(|s| (s.min(1.0).max(-1.0) * 32767.0) as i16)
+                .collect(),
+            position: 0,
+        })
+    }
+}
+
+/// Converts interleaved samples with `channels` channels to stereo.
+/// Mono is duplicated and any channel after the first two is dropped.
+fn to_stereo(samples: &[f32], channels: usize) -> Vec<f32> {
+    match channels {
+        2 => samples.to_vec(),
+        1 => samples.iter().flat_map(|&s| vec![s, s]).collect(),
+        n => samples
+            .chunks_exact(n)
+            .flat_map(|frame| frame[..2].to_vec())
+            .collect(),
+    }
+}
+
+impl AudioInput for WavFileAudio {
+    fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        if self.position >= self.samples.len() {
+            return None;
+        }
+        let end = (self.position + buffer.len()).min(self.samples.len());
+        let size = end - self.position;
+        buffer[..size].copy_from_slice(&self.samples[self.position..end]);
+        // The last frame is padded with silence.
+        buffer[size..].iter_mut().for_each(|s| *s = 0);
+        self.position = end;
+        Some(buffer.len())
+    }
+}
+
+/// How long `StreamAudio` waits for a frame to be fed before playing
+/// silence instead.
+const STREAM_UNDERRUN_WAIT: time::Duration = time::Duration::from_millis(20);
+
+#[derive(Default)]
+struct StreamBuffer {
+    samples: VecDeque<i16>,
+    ended: bool,
+}
+
+/// The feeding end of a `StreamAudio`, which takes 48kHz stereo 16-bit
+/// little-endian PCM, e.g. from Python.
+#[derive(Clone, Default)]
+pub(crate) struct PcmStream(Arc<(Mutex<StreamBuffer>, Condvar)>);
+
+impl PcmStream {
+    /// Queues `data` to be played. Empty `data` marks the end of the stream,
+    /// like `end`.
+    pub(crate) fn feed(&self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            self.end();
+            return Ok(());
+        }
+        if data.len() % SAMPLE_SIZE as usize != 0 {
+            return Err(DiscordError::InvalidArgument(format!(
+                "PCM data must be whole 16-bit stereo frames: {} bytes",
+                data.len()
+            )));
+        }
+        let (buffer, fed) = &*self.0;
+        let mut buffer = buffer.lock();
+        if buffer.ended {
+            return Err(DiscordError::InvalidArgument(
+                "The stream has already ended".to_string(),
+            ));
+        }
+        buffer.samples.extend(
+            data.chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])),
+        );
+        fed.notify_one();
+        Ok(())
+    }
+
+    /// Marks the end of the stream. The player finishes once the audio fed
+    /// so far has been played.
+    pub(crate) fn end(&self) {
+        let (buffer, fed) = &*self.0;
+        buffer.lock().ended = true;
+        fed.notify_one();
+    }
+
+    pub(crate) fn source(&self) -> StreamAudio {
+        StreamAudio(self.clone())
+    }
+}
+
+/// Plays the PCM fed into a `PcmStream`. Running out of data is not the end
+/// of the stream: silence is played until more is fed or the stream is
+/// ended.
+pub(crate) struct StreamAudio(PcmStream);
+
+impl AudioInput for StreamAudio {
+    fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        let (stream, fed) = &*(self.0).0;
+        let mut stream = stream.lock();
+        let deadline = time::Instant::now() + STREAM_UNDERRUN_WAIT;
+        while stream.samples.len() < buffer.len() && !stream.ended {
+            if fed.wait_until(&mut stream, deadline).timed_out() {
+                break;
+            }
+        }
+        if stream.samples.len() < buffer.len() && !stream.ended {
+            // No data yet; keep the stream going.
+            buffer.iter_mut().for_each(|s| *s = 0);
+            return Some(buffer.len());
+        }
+        let size = stream.samples.len().min(buffer.len());
+        if size == 0 {
+            return None;
+        }
+        for (sample, value) in buffer.iter_mut().zip(stream.samples.drain(..size)) {
+            *sample = value;
+        }
+        // The last frame is padded with silence so that the tail is played.
+        buffer[size..].iter_mut().for_each(|s| *s = 0);
+        Some(size)
+    }
+}
+
 #[derive(Debug)]
 pub struct AudioBuffer<'a> {
     slice: &'a mut [u8],
@@ -142,6 +366,398 @@ impl Buffer for AudioBuffer<'_> {
 
 pub(crate) const BUFSIZE: usize = 1275 + 24 + 12 + 24 + 16 + 12;
 
+pub(crate) const OPUS_SILENCE: [u8; 3] = [0xF8, 0xFF, 0xFE];
+pub(crate) const SILENCE_FRAMES: usize = 5;
+
+/// Audio bandwidth of the Opus encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Bandwidth(audiopus::Bandwidth);
+
+impl FromStr for Bandwidth {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        use audiopus::Bandwidth::*;
+        match s {
+            "auto" => Ok(Bandwidth(Auto)),
+            "narrowband" => Ok(Bandwidth(Narrowband)),
+            "mediumband" => Ok(Bandwidth(Mediumband)),
+            "wideband" => Ok(Bandwidth(Wideband)),
+            "superwideband" => Ok(Bandwidth(Superwideband)),
+            "fullband" => Ok(Bandwidth(Fullband)),
+            _ => Err(DiscordError::InvalidArgument(format!(
+                "Unknown bandwidth: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Valid range of an explicit Opus bitrate in bits per second.
+pub(crate) const BITRATE_RANGE: std::ops::RangeInclusive<i32> = 500..=512_000;
+
+/// Bitrate of the Opus encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Bitrate(audiopus::Bitrate);
+
+impl Bitrate {
+    pub(crate) fn bits_per_second(bps: i32) -> Result<Self> {
+        if !BITRATE_RANGE.contains(&bps) {
+            return Err(DiscordError::InvalidArgument(format!(
+                "Bitrate must be between {} and {} bps: {}",
+                BITRATE_RANGE.start(),
+                BITRATE_RANGE.end(),
+                bps
+            )));
+        }
+        Ok(Bitrate(audiopus::Bitrate::BitsPerSecond(bps)))
+    }
+}
+
+impl FromStr for Bitrate {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Bitrate(audiopus::Bitrate::Auto)),
+            "max" => Ok(Bitrate(audiopus::Bitrate::Max)),
+            s => match s.parse() {
+                Ok(bps) => Bitrate::bits_per_second(bps),
+                Err(_) => Err(DiscordError::InvalidArgument(format!(
+                    "Unknown bitrate: {}",
+                    s
+                ))),
+            },
+        }
+    }
+}
+
+/// Kind of audio the encoder is tuned for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Signal(audiopus::Signal);
+
+impl FromStr for Signal {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        use audiopus::Signal::*;
+        match s {
+            "auto" => Ok(Signal(Auto)),
+            "voice" => Ok(Signal(Voice)),
+            "music" => Ok(Signal(Music)),
+            _ => Err(DiscordError::InvalidArgument(format!(
+                "Unknown signal: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Highest Opus encoder complexity.
+pub(crate) const MAX_COMPLEXITY: u8 = 10;
+
+/// Named presets of the encoder options.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EncoderProfile {
+    /// Speech at a low bitrate.
+    Voice,
+    /// The default options, tuned for music.
+    Music,
+    /// Music at a high bitrate, for channels that allow it.
+    High,
+    /// Leaves the bandwidth and the signal to the encoder, which adapts them
+    /// to the content frame by frame, e.g. for speech followed by music.
+    Auto,
+}
+
+impl FromStr for EncoderProfile {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "voice" => Ok(EncoderProfile::Voice),
+            "music" => Ok(EncoderProfile::Music),
+            "high" => Ok(EncoderProfile::High),
+            "auto" => Ok(EncoderProfile::Auto),
+            _ => Err(DiscordError::InvalidArgument(format!(
+                "Unknown profile: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl EncoderProfile {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            EncoderProfile::Voice => "voice",
+            EncoderProfile::Music => "music",
+            EncoderProfile::High => "high",
+            EncoderProfile::Auto => "auto",
+        }
+    }
+
+    pub(crate) fn options(self) -> EncoderOptions {
+        match self {
+            EncoderProfile::Voice => EncoderOptions {
+                bitrate: Bitrate(audiopus::Bitrate::BitsPerSecond(64_000)),
+                bandwidth: Bandwidth(audiopus::Bandwidth::Auto),
+                max_bandwidth: Bandwidth(audiopus::Bandwidth::Wideband),
+                signal: Signal(audiopus::Signal::Voice),
+                complexity: 5,
+                fec: true,
+                prediction_disabled: false,
+                bitrate_ramp_ms: 0,
+                vbr: true,
+                vbr_constraint: false,
+            },
+            EncoderProfile::Music => EncoderOptions {
+                signal: Signal(audiopus::Signal::Music),
+                ..EncoderOptions::default()
+            },
+            EncoderProfile::High => EncoderOptions {
+                bitrate: Bitrate(audiopus::Bitrate::BitsPerSecond(256_000)),
+                signal: Signal(audiopus::Signal::Music),
+                // Redundant data only costs quality at this bitrate.
+                fec: false,
+                ..EncoderOptions::default()
+            },
+            EncoderProfile::Auto => EncoderOptions {
+                bandwidth: Bandwidth(audiopus::Bandwidth::Auto),
+                signal: Signal(audiopus::Signal::Auto),
+                ..EncoderOptions::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EncoderOptions {
+    /// Explicit bitrate, or `auto` / `max` to let the encoder decide.
+    pub bitrate: Bitrate,
+    /// Bandwidth the encoder is forced to use. `auto` lets it choose.
+    /// A forced bandwidth also bounds what `signal` can do: with `fullband`
+    /// the encoder cannot switch to the narrower SILK modes for speech.
+    pub bandwidth: Bandwidth,
+    /// Upper limit of the bandwidth the encoder may choose on its own.
+    /// Only matters when `bandwidth` is `auto`.
+    pub max_bandwidth: Bandwidth,
+    /// Kind of audio the encoder is tuned for. `auto` detects it from the
+    /// content of each frame.
+    pub signal: Signal,
+    /// Encoder complexity from 0 to `MAX_COMPLEXITY`. Lower values use less
+    /// CPU at the expense of quality.
+    pub complexity: u8,
+    /// Whether in-band forward error correction is enabled.
+    pub fec: bool,
+    /// Encodes every frame independently of the previous ones, so that a
+    /// lost packet does not degrade the following frames. Costs compression.
+    pub prediction_disabled: bool,
+    /// Milliseconds over which an explicit bitrate is reached, starting
+    /// from a quarter of it. 0 starts at the full bitrate.
+    pub bitrate_ramp_ms: u32,
+    /// Variable bitrate, where `bitrate` is an average. Without it every
+    /// packet has the same size and `bitrate` is a hard target.
+    pub vbr: bool,
+    /// Keeps the variable bitrate close to `bitrate` over short spans, so
+    /// that it bursts less. Only matters with `vbr`.
+    pub vbr_constraint: bool,
+}
+
+/// Changes applied to the encoder of a running player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EncoderCommand {
+    Fec(bool),
+    /// Keeps encoding and timing the source without sending it.
+    Mute(bool),
+}
+
+impl Default for EncoderOptions {
+    fn default() -> EncoderOptions {
+        EncoderOptions {
+            bitrate: Bitrate(audiopus::Bitrate::BitsPerSecond(128_000)),
+            bandwidth: Bandwidth(audiopus::Bandwidth::Fullband),
+            max_bandwidth: Bandwidth(audiopus::Bandwidth::Fullband),
+            signal: Signal(audiopus::Signal::Auto),
+            complexity: MAX_COMPLEXITY,
+            fec: true,
+            prediction_disabled: false,
+            bitrate_ramp_ms: 0,
+            vbr: true,
+            vbr_constraint: false,
+        }
+    }
+}
+
+impl EncoderOptions {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.max_bandwidth.0 == audiopus::Bandwidth::Auto {
+            return Err(DiscordError::InvalidArgument(
+                "max_bandwidth cannot be auto".to_string(),
+            ));
+        }
+        if self.complexity > MAX_COMPLEXITY {
+            return Err(DiscordError::InvalidArgument(format!(
+                "Complexity must be between 0 and {}: {}",
+                MAX_COMPLEXITY, self.complexity
+            )));
+        }
+        if self.bitrate_ramp_ms > MAX_BITRATE_RAMP_MS {
+            return Err(DiscordError::InvalidArgument(format!(
+                "bitrate_ramp_ms must be between 0 and {}: {}",
+                MAX_BITRATE_RAMP_MS, self.bitrate_ramp_ms
+            )));
+        }
+        if self.vbr_constraint && !self.vbr {
+            return Err(DiscordError::InvalidArgument(
+                "vbr_constraint requires vbr".to_string(),
+            ));
+        }
+        // Constant `auto` or `max` would not tell the encoder what to hold.
+        if !self.vbr && !matches!(self.bitrate.0, audiopus::Bitrate::BitsPerSecond(_)) {
+            return Err(DiscordError::InvalidArgument(
+                "Constant bitrate requires an explicit bitrate".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Lowers the bitrate to `channel_bps`, the bitrate of the voice channel,
+    /// if it is above. `auto` and `max` become the channel bitrate, since
+    /// either may exceed it.
+    pub(crate) fn limit_to_channel(&mut self, channel_bps: u32) -> Result<()> {
+        check_channel_bitrate(channel_bps)?;
+        let limit = channel_bps as i32;
+        match self.bitrate.0 {
+            audiopus::Bitrate::BitsPerSecond(bps) if bps <= limit => {}
+            bitrate => {
+                debug!(
+                    "Limiting the bitrate {:?} to the channel bitrate {}bps",
+                    bitrate, limit
+                );
+                self.bitrate = Bitrate(audiopus::Bitrate::BitsPerSecond(limit));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, command: EncoderCommand) {
+        match command {
+            EncoderCommand::Fec(enabled) => self.fec = enabled,
+            // Kept by the encoder instead, see `AudioEncoder::continue_from`.
+            EncoderCommand::Mute(_) => {}
+        }
+    }
+}
+
+/// Bitrates a Discord voice channel can be set to, in bits per second.
+pub(crate) const CHANNEL_BITRATE_RANGE: std::ops::RangeInclusive<u32> = 8_000..=384_000;
+
+fn check_channel_bitrate(channel_bps: u32) -> Result<()> {
+    if !CHANNEL_BITRATE_RANGE.contains(&channel_bps) {
+        return Err(DiscordError::InvalidArgument(format!(
+            "Channel bitrate must be between {} and {} bps: {}",
+            CHANNEL_BITRATE_RANGE.start(),
+            CHANNEL_BITRATE_RANGE.end(),
+            channel_bps
+        )));
+    }
+    Ok(())
+}
+
+/// Encoder settings suited to a voice channel, as the `profile`, `bitrate`
+/// and `max_bandwidth` arguments of `play`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChannelSettings {
+    pub profile: &'static str,
+    pub bitrate: u32,
+    pub max_bandwidth: Option<&'static str>,
+}
+
+impl ChannelSettings {
+    /// The voice profile below 32kbps, where music sounds poor anyway, and
+    /// narrower bands at the lowest bitrates so that the few bits are not
+    /// spread over highs. The bitrate is that of the profile, or the
+    /// channel bitrate if it is lower.
+    pub(crate) fn for_channel(channel_bps: u32) -> Result<Self> {
+        check_channel_bitrate(channel_bps)?;
+        let (profile, max_bandwidth) = match channel_bps {
+            0..=15_999 => (EncoderProfile::Voice, Some("narrowband")),
+            16_000..=31_999 => (EncoderProfile::Voice, None),
+            32_000..=63_999 => (EncoderProfile::Auto, Some("superwideband")),
+            64_000..=255_999 => (EncoderProfile::Music, None),
+            _ => (EncoderProfile::High, None),
+        };
+        let bitrate = match profile.options().bitrate.0 {
+            audiopus::Bitrate::BitsPerSecond(bps) => (bps as u32).min(channel_bps),
+            _ => channel_bps,
+        };
+        Ok(ChannelSettings {
+            profile: profile.name(),
+            bitrate,
+            max_bandwidth,
+        })
+    }
+}
+
+/// `OPUS_SET_PREDICTION_DISABLED_REQUEST` of `opus_defines.h`, which
+/// audiopus has no setter for.
+const OPUS_SET_PREDICTION_DISABLED_REQUEST: i32 = 4042;
+
+/// Longest bitrate ramp accepted by `EncoderOptions`.
+pub(crate) const MAX_BITRATE_RAMP_MS: u32 = 10_000;
+/// Frames between two bitrate changes while ramping, so that the encoder
+/// is not reconfigured for every frame.
+const BITRATE_RAMP_STEP: u32 = 5;
+
+/// Raises the bitrate linearly from a quarter of the target to the target
+/// over the first frames, so that playback does not start with a burst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BitrateRamp {
+    target: i32,
+    frames: u32,
+    frame: u32,
+}
+
+impl BitrateRamp {
+    /// `None` unless a ramp is set and the bitrate is explicit.
+    fn new(options: &EncoderOptions) -> Option<Self> {
+        match options.bitrate.0 {
+            audiopus::Bitrate::BitsPerSecond(target) if options.bitrate_ramp_ms > 0 => {
+                Some(BitrateRamp {
+                    target,
+                    frames: (options.bitrate_ramp_ms / FRAME_LENGTH as u32).max(1),
+                    frame: 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn bitrate(&self) -> i32 {
+        if self.frame >= self.frames {
+            return self.target;
+        }
+        let start = (self.target / 4).max(*BITRATE_RANGE.start());
+        let step = (self.target - start) as i64 * self.frame as i64 / self.frames as i64;
+        start + step as i32
+    }
+
+    /// Moves to the next frame. Returns the bitrate to switch to, if any.
+    fn advance(&mut self) -> Option<i32> {
+        self.frame += 1;
+        if self.frame % BITRATE_RAMP_STEP == 0 || self.is_finished() {
+            Some(self.bitrate())
+        } else {
+            None
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.frame >= self.frames
+    }
+}
+
 pub(crate) struct AudioEncoder {
     opus: audiopus::coder::Encoder,
     cipher: XSalsa20Poly1305,
@@ -152,6 +768,9 @@ pub(crate) struct AudioEncoder {
     pcm_buff: [i16; 1920],
     buff: [u8; BUFSIZE],
     encryption: EncryptionMode,
+    ramp: Option<BitrateRamp>,
+    muted: bool,
+    loopback: Option<SharedLoopback>,
 }
 
 impl fmt::Debug for AudioEncoder {
@@ -164,17 +783,44 @@ impl fmt::Debug for AudioEncoder {
 }
 
 impl AudioEncoder {
-    pub(crate) fn from_gateway(gateway: &VoiceGateway) -> Result<AudioEncoder> {
+    pub(crate) fn from_gateway(
+        gateway: &VoiceGateway,
+        options: &EncoderOptions,
+    ) -> Result<AudioEncoder> {
         let mut encoder = audiopus::coder::Encoder::new(
             audiopus::SampleRate::Hz48000,
             audiopus::Channels::Stereo,
             audiopus::Application::Audio,
         )?;
-        encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(128_000))?;
-        encoder.enable_inband_fec()?;
+        // A new encoder starts the ramp over, so that a reconnect does not
+        // burst either.
+        let ramp = BitrateRamp::new(options);
+        match ramp {
+            Some(ramp) => encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(ramp.bitrate()))?,
+            None => encoder.set_bitrate(options.bitrate.0)?,
+        }
+        if options.fec {
+            encoder.enable_inband_fec()?;
+        } else {
+            encoder.disable_inband_fec()?;
+        }
         encoder.set_packet_loss_perc(15)?;
-        encoder.set_bandwidth(audiopus::Bandwidth::Fullband)?;
-        encoder.set_signal(audiopus::Signal::Auto)?;
+        encoder.set_max_bandwidth(options.max_bandwidth.0)?;
+        encoder.set_bandwidth(options.bandwidth.0)?;
+        encoder.set_signal(options.signal.0)?;
+        encoder.set_complexity(options.complexity)?;
+        encoder.set_vbr(options.vbr)?;
+        if options.vbr {
+            encoder.set_vbr_constraint(options.vbr_constraint)?;
+        }
+        if options.prediction_disabled {
+            // Not every build of libopus knows this request; the player
+            // still works without it, only with the usual latency.
+            if let Err(e) = encoder.set_encoder_ctl_request(OPUS_SET_PREDICTION_DISABLED_REQUEST, 1)
+            {
+                warn!("The installed opus cannot disable prediction: {}", e);
+            }
+        }
         let key = GenericArray::clone_from_slice(&gateway.secret_key);
         let cipher = XSalsa20Poly1305::new(&key);
         let encryption = gateway.encryption;
@@ -189,9 +835,34 @@ impl AudioEncoder {
             pcm_buff: [0; 1920],
             buff: [0; BUFSIZE],
             encryption,
+            ramp,
+            muted: false,
+            loopback: None,
         })
     }
 
+    /// Copies each frame sent into `loopback` while it captures.
+    pub(crate) fn set_loopback(&mut self, loopback: SharedLoopback) {
+        self.loopback = Some(loopback);
+    }
+
+    /// Moves the bitrate ramp, if any, one frame further.
+    pub(crate) fn advance_bitrate_ramp(&mut self) -> Result<()> {
+        let (bitrate, finished) = match &mut self.ramp {
+            Some(ramp) => (ramp.advance(), ramp.is_finished()),
+            None => return Ok(()),
+        };
+        if finished {
+            self.ramp = None;
+        }
+        if let Some(bps) = bitrate {
+            debug!("Ramping the bitrate to {}bps", bps);
+            self.opus
+                .set_bitrate(audiopus::Bitrate::BitsPerSecond(bps))?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn prepare_packet(
         &mut self,
         size: usize,
@@ -204,8 +875,13 @@ impl AudioEncoder {
         header[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
         self.buff[..12].copy_from_slice(&header);
         let mut buffer = AudioBuffer::new(&mut self.buff[12..], size);
-        self.encryption
-            .encrypt(&self.cipher, self.lite_nonce, &header, &mut buffer)?;
+        self.encryption.encrypt(
+            &self.cipher,
+            self.lite_nonce,
+            &header,
+            &mut buffer,
+            &mut rand::thread_rng(),
+        )?;
         self.lite_nonce = self.lite_nonce.wrapping_add(1);
         Ok(buffer.len())
     }
@@ -216,30 +892,190 @@ impl AudioEncoder {
         self.opus.encode(&self.pcm_buff, &mut self.buff[12..])
     }
 
+    /// Encodes the PCM buffer, resetting the Opus encoder and retrying once
+    /// if the first attempt fails.
+    pub(crate) fn encode_pcm_buffer_or_reset(
+        &mut self,
+    ) -> std::result::Result<usize, audiopus::error::Error> {
+        match self.encode_pcm_buffer() {
+            Ok(size) => Ok(size),
+            Err(e) => {
+                warn!("Failed to encode: {:?}; resetting the encoder", e);
+                self.opus.reset_state()?;
+                let size = self.encode_pcm_buffer()?;
+                info!("Encoder recovered after reset");
+                Ok(size)
+            }
+        }
+    }
+
     pub(crate) fn send_opus_packet(
         &mut self,
         socket: &UdpSocket,
         addr: &SocketAddr,
         size: usize,
-    ) -> Result<()> {
+    ) -> Result<bool> {
+        self.send_opus_frame(socket, addr, size, SAMPLES_PER_FRAME)
+    }
+
+    /// Buffer that Opus packets read from an `OpusInput` are copied into
+    /// before `send_opus_frame`.
+    pub(crate) fn opus_buffer(&mut self) -> &mut [u8] {
+        &mut self.buff[12..12 + MAX_OPUS_PACKET]
+    }
+
+    /// Sends the `size` bytes of Opus in the buffer, advancing the RTP
+    /// timestamp by the `samples` they hold. While muted nothing is sent,
+    /// but the sequence and timestamp still advance so that unmuting
+    /// continues the stream.
+    ///
+    /// Returns `false` if the frame was dropped because the socket did not
+    /// take it within its send timeout. Its timestamp is still used up, so
+    /// that listeners see a lost frame instead of the stream shifting.
+    pub(crate) fn send_opus_frame(
+        &mut self,
+        socket: &UdpSocket,
+        addr: &SocketAddr,
+        size: usize,
+        samples: u32,
+    ) -> Result<bool> {
         self.sequence = self.sequence.wrapping_add(1);
+        if self.muted {
+            self.timestamp = self.timestamp.wrapping_add(samples);
+            return Ok(true);
+        }
+        // The frame is encrypted in place, so it is copied before.
+        let frame = match &self.loopback {
+            Some(loopback) if loopback.lock().is_some() => Some(self.buff[12..12 + size].to_vec()),
+            _ => None,
+        };
         let size = self
             .prepare_packet(size)
             .map_err(DiscordError::EncryptionError)?;
-        if let Err(e) = socket.send_to(&self.buff[0..size + 12], addr) {
-            if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut {
+        let sent = match socket.send_to(&self.buff[0..size + 12], addr) {
+            Ok(_) => true,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
                 warn!(
                     "A packet has been dropped: (seq: {}, ssrc: {})",
                     &self.sequence, &self.ssrc
                 );
-                return Ok(());
-            } else {
-                return Err(DiscordError::from(e));
+                false
+            }
+            Err(e) => return Err(DiscordError::from(e)),
+        };
+        if let (true, Some(frame), Some(loopback)) = (sent, frame, &self.loopback) {
+            if let Some(loopback) = &mut *loopback.lock() {
+                loopback.push(frame);
             }
         }
-        self.timestamp = self.timestamp.wrapping_add(SAMPLES_PER_FRAME);
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        Ok(sent)
+    }
+
+    pub(crate) fn apply(&mut self, command: EncoderCommand) -> Result<()> {
+        match command {
+            EncoderCommand::Fec(true) => self.opus.enable_inband_fec()?,
+            EncoderCommand::Fec(false) => self.opus.disable_inband_fec()?,
+            EncoderCommand::Mute(muted) => self.muted = muted,
+        }
         Ok(())
     }
+
+    /// Carries the RTP sequence and timestamp over from the encoder used before
+    /// a reconnect so that listeners see one continuous stream. The timestamp
+    /// is advanced by `outage` so the gap is timed as silence. A mute and the
+    /// loopback are carried over as well, and so is the nonce of the lite
+    /// mode, which a resumed session keeps the secret key of.
+    pub(crate) fn continue_from(&mut self, previous: &AudioEncoder, outage: time::Duration) {
+        let samples = (outage.as_secs_f64() * SAMPLING_RATE as f64) as u32;
+        self.lite_nonce = previous.lite_nonce;
+        self.muted = previous.muted;
+        self.loopback = previous.loopback.clone();
+        self.sequence = previous.sequence;
+        self.timestamp = previous.timestamp.wrapping_add(samples);
+    }
+
+    /// Sends a few frames of Opus silence so that listeners flush their
+    /// jitter buffer instead of interpolating the end of the stream.
+    pub(crate) fn send_silence_frames(
+        &mut self,
+        socket: &UdpSocket,
+        addr: &SocketAddr,
+    ) -> Result<()> {
+        for _ in 0..SILENCE_FRAMES {
+            self.buff[12..12 + OPUS_SILENCE.len()].copy_from_slice(&OPUS_SILENCE);
+            self.send_opus_packet(socket, addr, OPUS_SILENCE.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Width of a bucket of `FrameStats::histogram` in bytes.
+pub(crate) const FRAME_HISTOGRAM_BUCKET: usize = 64;
+const FRAME_HISTOGRAM_BUCKETS: usize = MAX_OPUS_PACKET / FRAME_HISTOGRAM_BUCKET + 1;
+
+/// Sizes of the Opus frames a player sent, to compare the bandwidth the
+/// encoder actually used with the nominal bitrate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FrameStats {
+    pub frames: u64,
+    pub bytes: u64,
+    /// 48kHz samples per channel of the frames.
+    pub samples: u64,
+    pub min: usize,
+    pub max: usize,
+    /// Frames counted by size, `FRAME_HISTOGRAM_BUCKET` bytes per bucket.
+    pub histogram: [u64; FRAME_HISTOGRAM_BUCKETS],
+    /// Frames dropped because the socket did not take them within its send
+    /// timeout. They are not counted in the fields above.
+    pub dropped: u64,
+}
+
+impl Default for FrameStats {
+    fn default() -> FrameStats {
+        FrameStats {
+            frames: 0,
+            bytes: 0,
+            samples: 0,
+            min: 0,
+            max: 0,
+            histogram: [0; FRAME_HISTOGRAM_BUCKETS],
+            dropped: 0,
+        }
+    }
+}
+
+impl FrameStats {
+    pub(crate) fn add(&mut self, size: usize, samples: u32) {
+        self.min = if self.frames == 0 {
+            size
+        } else {
+            self.min.min(size)
+        };
+        self.max = self.max.max(size);
+        self.frames += 1;
+        self.bytes += size as u64;
+        self.samples += samples as u64;
+        self.histogram[(size / FRAME_HISTOGRAM_BUCKET).min(FRAME_HISTOGRAM_BUCKETS - 1)] += 1;
+    }
+
+    pub(crate) fn mean(&self) -> f64 {
+        if self.frames == 0 {
+            0.0
+        } else {
+            self.bytes as f64 / self.frames as f64
+        }
+    }
+
+    /// Bits per second of Opus payload, without the RTP and encryption
+    /// overhead.
+    pub(crate) fn bitrate(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            self.bytes as f64 * 8.0 * SAMPLING_RATE as f64 / self.samples as f64
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -247,7 +1083,9 @@ pub(crate) struct AudioPlayer {
     thread: thread::JoinHandle<()>,
     gateway: Arc<Mutex<VoiceGateway>>,
     state: Arc<State>,
-    source: Arc<Mutex<Box<dyn AudioInput>>>,
+    source: Arc<Mutex<AudioSource>>,
+    commands: mpsc::Sender<EncoderCommand>,
+    stats: Arc<Mutex<FrameStats>>,
 }
 
 impl fmt::Debug for AudioPlayer {
@@ -262,21 +1100,29 @@ impl fmt::Debug for AudioPlayer {
 fn play_loop(
     gateway: &Arc<Mutex<VoiceGateway>>,
     state: &Arc<State>,
-    source: &Arc<Mutex<Box<dyn AudioInput>>>,
+    source: &Arc<Mutex<AudioSource>>,
+    mut options: EncoderOptions,
+    commands: &mpsc::Receiver<EncoderCommand>,
+    stats: &Mutex<FrameStats>,
+    loopback: &SharedLoopback,
 ) -> Result<()> {
     let mut next_iteration = time::Instant::now();
 
-    let (mut encoder, mut socket) = {
+    let (mut encoder, mut socket, generation, id) = {
         let mut gateway = gateway.lock();
-        gateway.speaking(SpeakingType::MICROPHONE)?;
+        gateway.speaking(SpeakingType::MICROPHONE, 0)?;
         (
-            AudioEncoder::from_gateway(&*gateway)?,
+            AudioEncoder::from_gateway(&*gateway, &options)?,
             gateway.clone_socket()?,
+            Arc::clone(&gateway.generation),
+            gateway.id,
         )
     };
+    encoder.set_loopback(Arc::clone(loopback));
+    let mut current_generation = generation.load(Ordering::Acquire);
 
-    let addr = socket.peer_addr()?;
-    info!("Socket connected to: {:?}", addr);
+    let mut addr = socket.peer_addr()?;
+    info!("[conn {}] Player socket connected to: {:?}", id, addr);
 
     use ConnectionState::*;
     loop {
@@ -285,37 +1131,118 @@ fn play_loop(
         }
 
         if state.is_state(Paused) {
+            // Clears the speaking indicator for the pause, after a few frames
+            // of silence so that listeners do not stretch the last frame.
+            encoder.send_silence_frames(&socket, &addr)?;
+            if let Err(e) = gateway.lock().clear_speaking() {
+                warn!("[conn {}] Failed to clear speaking on pause: {}", id, e);
+            }
             state.wait_not_until(Paused);
+            if !state.is_state(Finished) && !encoder.muted {
+                // The state set before the pause is still stored, so it is
+                // also sent after a reconnect if this fails.
+                if let Err(e) = gateway.lock().resend_speaking() {
+                    warn!("[conn {}] Failed to send speaking on resume: {}", id, e);
+                }
+            }
             continue;
         }
 
-        if state.is_state(Disconnected) {
+        // The source is not read while disconnected, so playback resumes
+        // where it stopped instead of skipping the audio of the outage.
+        // After `VoiceGateway::reconnect` playback never stopped, so there
+        // is no outage to account for.
+        let outage = if state.is_state(Disconnected) {
+            let disconnected = time::Instant::now();
             state.wait_until(Connected);
+            Some(disconnected.elapsed())
+        } else if generation.load(Ordering::Acquire) != current_generation {
+            Some(time::Duration::default())
+        } else {
+            None
+        };
+        if let Some(outage) = outage {
             next_iteration = time::Instant::now();
-            let gw = gateway.lock();
-            encoder = AudioEncoder::from_gateway(&*gw)?;
-            socket = gw.clone_socket()?
-        }
-
-        next_iteration += time::Duration::from_millis(20);
-        let buff_size = {
-            let mut audio = source.lock();
-            if audio.read_pcm_frame(&mut encoder.pcm_buff).is_some() {
-                match encoder.encode_pcm_buffer() {
-                    Ok(bytes) => Some(bytes),
-                    Err(e) => {
-                        error!("Failed to encode: {:?}", e);
-                        return Err(e.into());
+            let mut gw = gateway.lock();
+            current_generation = gw.generation.load(Ordering::Acquire);
+            let mut reconnected = AudioEncoder::from_gateway(&*gw, &options)?;
+            reconnected.continue_from(&encoder, outage);
+            encoder = reconnected;
+            socket = gw.clone_socket()?;
+            addr = socket.peer_addr()?;
+            // The new session starts without speaking, which a muted player
+            // keeps until it is unmuted.
+            if !encoder.muted {
+                gw.resend_speaking()?;
+            }
+        }
+
+        // Commands are also kept in `options` so that they survive a reconnect.
+        for command in commands.try_iter() {
+            if command == EncoderCommand::Mute(true) && !encoder.muted {
+                // Like a pause, ends what listeners hear with silence.
+                encoder.send_silence_frames(&socket, &addr)?;
+            }
+            match encoder.apply(command) {
+                Ok(()) => options.apply(command),
+                Err(e) => error!("[conn {}] Failed to apply {:?}: {}", id, command, e),
+            }
+            if let EncoderCommand::Mute(muted) = command {
+                let mut gateway = gateway.lock();
+                let speaking = if muted {
+                    gateway.clear_speaking()
+                } else {
+                    gateway.resend_speaking()
+                };
+                if let Err(e) = speaking {
+                    warn!("[conn {}] Failed to update speaking on mute: {}", id, e);
+                }
+            }
+        }
+
+        if let Err(e) = encoder.advance_bitrate_ramp() {
+            error!("[conn {}] Failed to ramp the bitrate: {}", id, e);
+        }
+
+        let buff_size = match &mut *source.lock() {
+            AudioSource::Pcm(audio) => {
+                if audio.read_pcm_frame(&mut encoder.pcm_buff).is_some() {
+                    match encoder.encode_pcm_buffer_or_reset() {
+                        Ok(bytes) => Some((bytes, SAMPLES_PER_FRAME)),
+                        Err(e) => {
+                            error!("[conn {}] Failed to encode: {:?}", id, e);
+                            return Err(e.into());
+                        }
                     }
+                } else {
+                    audio.finish()?;
+                    None
+                }
+            }
+            AudioSource::Opus(audio) => {
+                let buffer = encoder.opus_buffer();
+                if let Some(bytes) = audio.read_opus_frame(buffer) {
+                    // Packets of other durations than 20ms are paced and
+                    // timestamped by what they hold; a malformed one as 20ms.
+                    let samples =
+                        opus_packet_samples(&buffer[..bytes]).unwrap_or(SAMPLES_PER_FRAME);
+                    Some((bytes, samples))
+                } else {
+                    audio.finish()?;
+                    None
                 }
-            } else {
-                None
             }
         };
 
-        if let Some(size) = buff_size {
+        if let Some((size, samples)) = buff_size {
             if size > 0 {
-                encoder.send_opus_packet(&socket, &addr, size)?;
+                next_iteration +=
+                    time::Duration::from_micros(samples as u64 * 1_000_000 / SAMPLING_RATE as u64);
+                if encoder.send_opus_frame(&socket, &addr, size, samples)? {
+                    stats.lock().add(size, samples);
+                } else {
+                    stats.lock().dropped += 1;
+                }
                 let now = time::Instant::now();
                 next_iteration = next_iteration.max(now);
                 thread::sleep(next_iteration - now);
@@ -325,6 +1252,8 @@ fn play_loop(
         }
     }
 
+    encoder.send_silence_frames(&socket, &addr)?;
+
     Ok(())
 }
 
@@ -332,7 +1261,9 @@ impl AudioPlayer {
     pub fn new<After>(
         after: After,
         gateway: Arc<Mutex<VoiceGateway>>,
-        source: Arc<Mutex<Box<dyn AudioInput>>>,
+        source: Arc<Mutex<AudioSource>>,
+        options: EncoderOptions,
+        loopback: SharedLoopback,
     ) -> Self
     where
         After: FnOnce(Option<DiscordError>) + Send + 'static,
@@ -343,19 +1274,27 @@ impl AudioPlayer {
             Arc::clone(&gateway.state)
         };
         state.set_state(Connected);
+        let (commands, receiver) = mpsc::channel();
+        let active = ActiveGuard::new(&ACTIVE_PLAYERS);
+        let stats = Arc::new(Mutex::new(FrameStats::default()));
 
         Self {
             gateway: Arc::clone(&gateway),
             state: Arc::clone(&state),
             source: Arc::clone(&source),
+            commands,
+            stats: Arc::clone(&stats),
             thread: thread::spawn(move || {
+                let _active = active;
                 let mut err = None;
-                if let Err(e) = play_loop(&gateway, &state, &source) {
+                if let Err(e) = play_loop(
+                    &gateway, &state, &source, options, &receiver, &stats, &loopback,
+                ) {
                     err = Some(e);
                 }
                 {
                     let mut gateway = gateway.lock();
-                    let _ = gateway.speaking(SpeakingType::empty());
+                    let _ = gateway.speaking(SpeakingType::empty(), 0);
                 }
                 after(err);
             }),
@@ -374,6 +1313,44 @@ impl AudioPlayer {
         self.state.set_state(ConnectionState::Finished);
     }
 
+    /// Stops sending while the source keeps being read, so that unmuting
+    /// continues at the current position rather than where it was muted.
+    pub fn mute(&self) {
+        self.send_command(EncoderCommand::Mute(true));
+    }
+
+    pub fn unmute(&self) {
+        self.send_command(EncoderCommand::Mute(false));
+    }
+
+    /// Sends `command` to the encoder. It is applied before the next frame.
+    pub fn send_command(&self, command: EncoderCommand) {
+        if self.commands.send(command).is_err() {
+            warn!("Player has already finished; {:?} is ignored", command);
+        }
+    }
+
+    /// Sizes of the frames sent so far, kept after the player finished.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.stats.lock().clone()
+    }
+
+    /// Stops playing and waits up to `timeout` for the player thread to exit.
+    /// Returns `false` if the thread is still running after the timeout.
+    pub fn stop_and_join(self, timeout: time::Duration) -> bool {
+        self.stop();
+        let deadline = time::Instant::now() + timeout;
+        while !self.thread.is_finished() {
+            if time::Instant::now() >= deadline {
+                warn!("Player thread did not finish within {:?}", timeout);
+                return false;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        let _ = self.thread.join();
+        true
+    }
+
     #[allow(dead_code)]
     pub fn is_paused(&self) -> bool {
         self.state.is_state(ConnectionState::Paused)
@@ -382,4 +1359,275 @@ impl AudioPlayer {
     pub fn is_playing(&self) -> bool {
         self.state.is_state(ConnectionState::Playing)
     }
+
+    pub fn is_alive(&self) -> bool {
+        !self.thread.is_finished()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn shell(script: &str) -> FFmpegAudio {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        FFmpegAudio::spawn(command).unwrap()
+    }
+
+    fn exit_status(mut audio: FFmpegAudio) -> std::process::ExitStatus {
+        let mut buffer = [0i16; 1920];
+        assert_eq!(audio.read_pcm_frame(&mut buffer), None);
+        match audio.finish() {
+            Err(DiscordError::FFmpegExited(status)) => status,
+            other => panic!("expected FFmpegExited, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ffmpeg_partial_frame_is_padded() {
+        let mut audio = shell(r"printf '\001\000\002\000\003\000'");
+        let mut buffer = [-1i16; 1920];
+        assert_eq!(audio.read_pcm_frame(&mut buffer), Some(3));
+        assert_eq!(buffer[..3], [1, 2, 3]);
+        assert!(buffer[3..].iter().all(|&s| s == 0));
+        assert_eq!(audio.read_pcm_frame(&mut buffer), None);
+        assert!(audio.finish().is_ok());
+    }
+
+    #[test]
+    fn ffmpeg_exit_code_is_reported() {
+        let status = exit_status(shell("exit 8"));
+        assert_eq!(status.code(), Some(8));
+        assert!(DiscordError::FFmpegExited(status).to_string().contains('8'));
+    }
+
+    #[test]
+    fn ffmpeg_signal_is_reported() {
+        let status = exit_status(shell("kill -9 $$"));
+        assert_eq!(status.code(), None);
+        assert_eq!(status.signal(), Some(9));
+    }
+
+    fn lite_session() -> crate::ws::SessionState {
+        crate::ws::SessionState {
+            endpoint: String::new(),
+            server_id: String::new(),
+            user_id: String::new(),
+            session_id: String::new(),
+            token: String::new(),
+            ssrc: 3,
+            endpoint_ip: "127.0.0.1".to_string(),
+            port: 9,
+            encryption: EncryptionMode::XSalsa20Poly1305Lite,
+            secret_key: [7; 32],
+        }
+    }
+
+    /// The nonce the lite mode appended to a packet of `size` encrypted bytes.
+    fn lite_nonce(encoder: &AudioEncoder, size: usize) -> u32 {
+        let mut nonce = [0u8; 4];
+        nonce.copy_from_slice(&encoder.buff[12 + size - 4..12 + size]);
+        u32::from_be_bytes(nonce)
+    }
+
+    #[test]
+    fn lite_nonce_continues_across_a_reconnect() {
+        let gateway = VoiceGateway::detached(&lite_session()).unwrap();
+        let options = EncoderOptions::default();
+        let mut previous = AudioEncoder::from_gateway(&gateway, &options).unwrap();
+        let mut nonces = vec![];
+        for _ in 0..2 {
+            let size = previous.prepare_packet(OPUS_SILENCE.len()).unwrap();
+            nonces.push(lite_nonce(&previous, size));
+        }
+        let mut encoder = AudioEncoder::from_gateway(&gateway, &options).unwrap();
+        encoder.continue_from(&previous, time::Duration::from_secs(1));
+        let size = encoder.prepare_packet(OPUS_SILENCE.len()).unwrap();
+        nonces.push(lite_nonce(&encoder, size));
+        assert_eq!(nonces, [0, 1, 2]);
+    }
+
+    #[test]
+    fn muted_encoder_keeps_timing_without_sending() {
+        let gateway = VoiceGateway::detached(&lite_session()).unwrap();
+        let mut encoder = AudioEncoder::from_gateway(&gateway, &EncoderOptions::default()).unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(time::Duration::from_millis(100)))
+            .unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut packet = [0u8; BUFSIZE];
+
+        encoder.apply(EncoderCommand::Mute(true)).unwrap();
+        encoder.send_silence_frames(&socket, &addr).unwrap();
+        assert!(receiver.recv(&mut packet).is_err());
+
+        encoder.apply(EncoderCommand::Mute(false)).unwrap();
+        encoder.send_silence_frames(&socket, &addr).unwrap();
+        receiver.recv(&mut packet).unwrap();
+        let frames = SILENCE_FRAMES as u32;
+        assert_eq!(packet[2..4], (frames as u16 + 1).to_be_bytes());
+        assert_eq!(packet[4..8], (frames * SAMPLES_PER_FRAME).to_be_bytes());
+    }
+
+    #[test]
+    fn ffmpeg_success_is_not_an_error() {
+        let mut audio = shell("exit 0");
+        let mut buffer = [0i16; 1920];
+        assert_eq!(audio.read_pcm_frame(&mut buffer), None);
+        assert!(audio.finish().is_ok());
+    }
+
+    #[test]
+    fn bitrate_ramps_up_to_the_target() {
+        let options = EncoderOptions {
+            bitrate_ramp_ms: 200,
+            ..EncoderOptions::default()
+        };
+        let mut ramp = BitrateRamp::new(&options).unwrap();
+        assert_eq!(ramp.bitrate(), 32_000);
+        let steps = (0..10).filter_map(|_| ramp.advance()).collect::<Vec<_>>();
+        assert_eq!(steps, [80_000, 128_000]);
+        assert!(ramp.is_finished());
+
+        let auto = EncoderOptions {
+            bitrate: "auto".parse().unwrap(),
+            ..options
+        };
+        assert_eq!(BitrateRamp::new(&auto), None);
+        assert_eq!(BitrateRamp::new(&EncoderOptions::default()), None);
+    }
+
+    #[test]
+    fn auto_profile_leaves_bandwidth_and_signal_to_opus() {
+        let options = "auto".parse::<EncoderProfile>().unwrap().options();
+        assert_eq!(options.bandwidth, "auto".parse().unwrap());
+        assert_eq!(options.signal, "auto".parse().unwrap());
+        assert_eq!(options.max_bandwidth, "fullband".parse().unwrap());
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn bitrate_is_limited_to_the_channel() {
+        let mut options = EncoderOptions::default();
+        options.limit_to_channel(64_000).unwrap();
+        assert_eq!(options.bitrate, Bitrate::bits_per_second(64_000).unwrap());
+        options.limit_to_channel(96_000).unwrap();
+        assert_eq!(options.bitrate, Bitrate::bits_per_second(64_000).unwrap());
+
+        let mut max = EncoderOptions {
+            bitrate: "max".parse().unwrap(),
+            ..EncoderOptions::default()
+        };
+        max.limit_to_channel(8_000).unwrap();
+        assert_eq!(max.bitrate, Bitrate::bits_per_second(8_000).unwrap());
+        assert!(max.limit_to_channel(4_000).is_err());
+        assert!(max.limit_to_channel(512_000).is_err());
+    }
+
+    #[test]
+    fn constant_bitrate_needs_an_explicit_bitrate() {
+        let cbr = EncoderOptions {
+            vbr: false,
+            ..EncoderOptions::default()
+        };
+        assert!(cbr.validate().is_ok());
+        let auto = EncoderOptions {
+            bitrate: "auto".parse().unwrap(),
+            ..cbr
+        };
+        assert!(auto.validate().is_err());
+        let constrained = EncoderOptions {
+            vbr_constraint: true,
+            ..cbr
+        };
+        assert!(constrained.validate().is_err());
+    }
+
+    #[test]
+    fn channel_settings_fit_the_channel() {
+        for &bps in &[8_000, 16_000, 48_000, 96_000, 384_000] {
+            let settings = ChannelSettings::for_channel(bps).unwrap();
+            assert!(settings.bitrate <= bps);
+            let mut options = settings
+                .profile
+                .parse::<EncoderProfile>()
+                .unwrap()
+                .options();
+            if let Some(max_bandwidth) = settings.max_bandwidth {
+                options.max_bandwidth = max_bandwidth.parse().unwrap();
+            }
+            assert!(options.validate().is_ok());
+        }
+        let low = ChannelSettings::for_channel(8_000).unwrap();
+        assert_eq!(
+            (low.profile, low.max_bandwidth),
+            ("voice", Some("narrowband"))
+        );
+        assert_eq!(
+            ChannelSettings::for_channel(384_000).unwrap().bitrate,
+            256_000
+        );
+    }
+
+    #[test]
+    fn frame_stats_track_sizes() {
+        let mut stats = FrameStats::default();
+        assert_eq!(stats.bitrate(), 0.0);
+        stats.add(320, 960);
+        stats.add(160, 960);
+        stats.add(MAX_OPUS_PACKET, 960);
+        assert_eq!((stats.min, stats.max), (160, MAX_OPUS_PACKET));
+        assert_eq!(stats.histogram[2], 1);
+        assert_eq!(stats.histogram[5], 1);
+        assert_eq!(stats.histogram[FRAME_HISTOGRAM_BUCKETS - 1], 1);
+        assert_eq!(stats.histogram.iter().sum::<u64>(), 3);
+
+        let mut constant = FrameStats::default();
+        for _ in 0..50 {
+            constant.add(320, 960);
+        }
+        // 320 bytes every 20ms.
+        assert_eq!(constant.mean(), 320.0);
+        assert_eq!(constant.bitrate(), 128_000.0);
+    }
+
+    #[test]
+    fn stream_plays_silence_until_fed() {
+        let stream = PcmStream::default();
+        let mut audio = stream.source();
+        let mut buffer = [-1i16; 1920];
+        assert_eq!(audio.read_pcm_frame(&mut buffer), Some(1920));
+        assert!(buffer.iter().all(|&s| s == 0));
+
+        let frame = (0..1920i16).flat_map(i16::to_le_bytes).collect::<Vec<_>>();
+        stream.feed(&frame).unwrap();
+        assert_eq!(audio.read_pcm_frame(&mut buffer), Some(1920));
+        assert_eq!(buffer[1919], 1919);
+    }
+
+    #[test]
+    fn stream_ends_after_the_fed_audio() {
+        let stream = PcmStream::default();
+        let mut audio = stream.source();
+        stream.feed(&[1, 0, 2, 0, 3, 0, 4, 0]).unwrap();
+        stream.feed(&[]).unwrap();
+        assert!(stream.feed(&[1, 0, 2, 0]).is_err());
+
+        let mut buffer = [-1i16; 1920];
+        assert_eq!(audio.read_pcm_frame(&mut buffer), Some(4));
+        assert_eq!(buffer[..4], [1, 2, 3, 4]);
+        assert!(buffer[4..].iter().all(|&s| s == 0));
+        assert_eq!(audio.read_pcm_frame(&mut buffer), None);
+    }
+
+    #[test]
+    fn stream_rejects_partial_frames() {
+        let stream = PcmStream::default();
+        assert!(stream.feed(&[1, 0, 2]).is_err());
+    }
 }
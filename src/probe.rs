@@ -0,0 +1,223 @@
+use std::{
+    io::ErrorKind,
+    process::{Command, Output, Stdio},
+};
+
+use crate::error::{DiscordError, Result};
+
+/// What ffprobe, or ffmpeg without it, tells about a media file. Fields it
+/// could not tell are `None`, e.g. the duration of a live stream.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct MediaInfo {
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    /// Container format, e.g. `mp3` or `mov,mp4,m4a,3gp,3g2,mj2`.
+    pub format: Option<String>,
+    /// Codec of the first audio stream.
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    /// Overall bitrate in bits per second.
+    pub bitrate: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    format: Option<ProbeFormat>,
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeFormat {
+    format_name: Option<String>,
+    duration: Option<String>,
+    bit_rate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeStream {
+    codec_name: Option<String>,
+    sample_rate: Option<String>,
+    channels: Option<u16>,
+}
+
+/// Probes `input` with ffprobe, or with ffmpeg if ffprobe is not installed.
+pub(crate) fn probe(input: &str) -> Result<MediaInfo> {
+    let mut ffprobe = Command::new("ffprobe");
+    ffprobe.args(&[
+        "-v",
+        "error",
+        "-select_streams",
+        "a:0",
+        "-show_entries",
+        "format=format_name,duration,bit_rate:stream=codec_name,sample_rate,channels",
+        "-of",
+        "json",
+        // An input starting with `-` would otherwise be taken as an option.
+        "-i",
+        input,
+    ]);
+    match run(ffprobe) {
+        Ok(output) if output.status.success() => parse_ffprobe(&output.stdout),
+        Ok(output) => Err(probe_error(&output.stderr)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            debug!("ffprobe not found; probing {} with ffmpeg", input);
+            let mut ffmpeg = Command::new("ffmpeg");
+            ffmpeg.args(&["-hide_banner", "-i", input]);
+            // ffmpeg fails without an output file, after printing the input.
+            let output = run(ffmpeg)?;
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            parse_ffmpeg(&stderr).ok_or_else(|| probe_error(&output.stderr))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn run(mut command: Command) -> std::io::Result<Output> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+}
+
+fn probe_error(stderr: &[u8]) -> DiscordError {
+    let stderr = String::from_utf8_lossy(stderr);
+    let message = stderr.lines().rev().find(|line| !line.trim().is_empty());
+    DiscordError::ProbeFailed(message.unwrap_or("no output").trim().to_string())
+}
+
+fn parse_ffprobe(stdout: &[u8]) -> Result<MediaInfo> {
+    let output: ProbeOutput = serde_json::from_slice(stdout)?;
+    let mut info = MediaInfo::default();
+    if let Some(format) = output.format {
+        info.format = format.format_name;
+        info.duration = format.duration.and_then(|d| d.parse().ok());
+        info.bitrate = format.bit_rate.and_then(|b| b.parse().ok());
+    }
+    if let Some(stream) = output.streams.into_iter().next() {
+        info.codec = stream.codec_name;
+        info.sample_rate = stream.sample_rate.and_then(|r| r.parse().ok());
+        info.channels = stream.channels;
+    }
+    Ok(info)
+}
+
+/// Parses the description of the input that `ffmpeg -i` prints, or returns
+/// `None` if ffmpeg could not open it.
+fn parse_ffmpeg(stderr: &str) -> Option<MediaInfo> {
+    let mut info = MediaInfo::default();
+    let input = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("Input #0, "))?;
+    info.format = input.split(", from ").next().map(str::to_string);
+    for line in stderr.lines().map(str::trim) {
+        if let Some(fields) = line.strip_prefix("Duration: ") {
+            for field in fields.split(", ") {
+                if let Some(bitrate) = field.strip_prefix("bitrate: ") {
+                    info.bitrate = parse_kbps(bitrate);
+                } else if info.duration.is_none() {
+                    info.duration = parse_timestamp(field);
+                }
+            }
+        } else if info.codec.is_none() && line.starts_with("Stream #0:") {
+            let audio = match line.split_once("Audio: ") {
+                Some((_, audio)) => audio,
+                None => continue,
+            };
+            let mut fields = audio.split(", ");
+            info.codec = fields
+                .next()
+                .and_then(|codec| codec.split_whitespace().next())
+                .map(str::to_string);
+            for field in fields {
+                if let Some(rate) = field.strip_suffix(" Hz") {
+                    info.sample_rate = rate.parse().ok();
+                } else if info.channels.is_none() {
+                    info.channels = parse_channels(field);
+                }
+            }
+        }
+    }
+    Some(info)
+}
+
+/// Seconds of an `HH:MM:SS.cc` duration. `N/A` yields `None`.
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let mut parts = timestamp.splitn(3, ':');
+    let hours: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+fn parse_kbps(bitrate: &str) -> Option<u64> {
+    let kbps: u64 = bitrate.strip_suffix(" kb/s")?.parse().ok()?;
+    Some(kbps * 1000)
+}
+
+fn parse_channels(layout: &str) -> Option<u16> {
+    match layout {
+        "mono" => Some(1),
+        "stereo" => Some(2),
+        "5.1" | "5.1(side)" => Some(6),
+        "7.1" => Some(8),
+        layout => layout.strip_suffix(" channels")?.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ffprobe_json_is_parsed() {
+        let json = br#"{
+            "programs": [],
+            "streams": [{"codec_name": "opus", "sample_rate": "48000", "channels": 2}],
+            "format": {"format_name": "ogg", "duration": "185.340000", "bit_rate": "131201"}
+        }"#;
+        let info = parse_ffprobe(json).unwrap();
+        assert_eq!(info.format.as_deref(), Some("ogg"));
+        assert_eq!(info.duration, Some(185.34));
+        assert_eq!(info.bitrate, Some(131_201));
+        assert_eq!(info.codec.as_deref(), Some("opus"));
+        assert_eq!(info.sample_rate, Some(48000));
+        assert_eq!(info.channels, Some(2));
+
+        let live = br#"{"streams": [], "format": {"format_name": "hls", "duration": "N/A"}}"#;
+        let info = parse_ffprobe(live).unwrap();
+        assert_eq!(info.duration, None);
+        assert_eq!(info.codec, None);
+    }
+
+    #[test]
+    fn ffmpeg_input_description_is_parsed() {
+        let stderr = "\
+Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'song.m4a':
+  Metadata:
+    major_brand     : M4A
+  Duration: 00:03:25.12, start: 0.000000, bitrate: 256 kb/s
+  Stream #0:0(und): Audio: aac (LC) (mp4a / 0x6134706D), 44100 Hz, stereo, fltp, 253 kb/s (default)
+At least one output file must be specified
+";
+        let info = parse_ffmpeg(stderr).unwrap();
+        assert_eq!(info.format.as_deref(), Some("mov,mp4,m4a,3gp,3g2,mj2"));
+        assert!((info.duration.unwrap() - 205.12).abs() < 1e-9);
+        assert_eq!(info.bitrate, Some(256_000));
+        assert_eq!(info.codec.as_deref(), Some("aac"));
+        assert_eq!(info.sample_rate, Some(44100));
+        assert_eq!(info.channels, Some(2));
+    }
+
+    #[test]
+    fn ffmpeg_failure_is_not_parsed() {
+        assert_eq!(
+            parse_ffmpeg("missing.mp3: No such file or directory\n"),
+            None
+        );
+        assert_eq!(parse_timestamp("N/A"), None);
+    }
+}
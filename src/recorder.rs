@@ -1,14 +1,18 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
-    io::Cursor,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{Cursor, ErrorKind},
+    net::UdpSocket,
     ops::{Deref, DerefMut},
-    sync::Arc,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
     thread,
 };
 
 use hound::{SampleFormat, WavSpec, WavWriter};
 use parking_lot::Mutex;
+use rand::Rng;
 use rtp_rs::Seq;
+use sha2::{Digest, Sha256};
 use std::time;
 use xsalsa20poly1305::{
     aead::{generic_array::GenericArray, Buffer, NewAead},
@@ -17,25 +21,519 @@ use xsalsa20poly1305::{
 
 use crate::{
     error::{DiscordError, Result},
+    mp3::{check_mp3_layout, encode_mp3, mp3_bitrate, RecordFormat, DEFAULT_MP3_BITRATE},
     payload::{EncryptionMode, Encryptor},
     player::*,
-    state::{ConnectionState, State},
+    resample::{resample, MAX_OUTPUT_RATE, MIN_OUTPUT_RATE},
+    state::{ActiveGuard, ConnectionState, State, ACTIVE_RECORDERS},
     ws::VoiceGateway,
 };
 
+/// How the streams of each speaker are mixed into the recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SmartDefault)]
+pub(crate) enum MixMode {
+    /// Plain sum, clamped to the valid range.
+    Sum,
+    /// Sum divided by the number of speakers active in the frame.
+    Average,
+    /// Blend that attenuates overlapping samples of the same sign.
+    #[default]
+    ConstantPower,
+}
+
+impl FromStr for MixMode {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sum" => Ok(MixMode::Sum),
+            "average" => Ok(MixMode::Average),
+            "constant_power" => Ok(MixMode::ConstantPower),
+            _ => Err(DiscordError::InvalidArgument(format!(
+                "Unknown mix mode: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl MixMode {
+    fn blend(self, r: f32, b: f32) -> f32 {
+        match self {
+            MixMode::Sum | MixMode::Average => r + b,
+            MixMode::ConstantPower => match (r, b) {
+                (r, b) if r < 0.0 && b < 0.0 => r + b - (r * b * -1.0),
+                (r, b) if r > 0.0 && b > 0.0 => r + b - (r * b),
+                (r, b) => r + b,
+            },
+        }
+    }
+}
+
+/// How the spans during which recording was paused appear in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SmartDefault)]
+pub(crate) enum PauseMode {
+    /// The paused spans are removed.
+    #[default]
+    Elide,
+    /// The paused spans are kept as silence.
+    Silence,
+}
+
+impl FromStr for PauseMode {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "elide" => Ok(PauseMode::Elide),
+            "silence" => Ok(PauseMode::Silence),
+            _ => Err(DiscordError::InvalidArgument(format!(
+                "Unknown pause mode: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// How the audio of lost packets is filled in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SmartDefault)]
+pub(crate) enum LossMode {
+    /// Opus packet loss concealment extrapolates the preceding audio.
+    Plc,
+    /// Lost packets are replaced with silence.
+    Silence,
+    /// The forward error correction data of the next packet is decoded if
+    /// that packet directly follows the lost one, or `Plc` is used otherwise.
+    /// The default, as it is never worse than `Plc`.
+    #[default]
+    Fec,
+}
+
+impl FromStr for LossMode {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plc" => Ok(LossMode::Plc),
+            "silence" => Ok(LossMode::Silence),
+            "fec" => Ok(LossMode::Fec),
+            _ => Err(DiscordError::InvalidArgument(format!(
+                "Unknown loss mode: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Maximum number of speakers written to a multitrack recording.
+pub(crate) const MAX_TRACKS: usize = 32;
+
+/// How the streams of each speaker are laid out in the recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SmartDefault)]
+pub(crate) enum Layout {
+    /// All speakers mixed into `channels` channels.
+    #[default]
+    Mixed,
+    /// One mono channel per speaker.
+    Multitrack,
+}
+
+impl FromStr for Layout {
+    type Err = DiscordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mixed" => Ok(Layout::Mixed),
+            "multitrack" => Ok(Layout::Multitrack),
+            _ => Err(DiscordError::InvalidArgument(format!(
+                "Unknown layout: {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RecordOptions {
+    pub mix: MixMode,
+    pub pause_mode: PauseMode,
+    pub loss_mode: LossMode,
+    pub layout: Layout,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Number of received packets pushed into the queue under one lock.
+    pub recv_batch_size: usize,
+    /// Gain in dB applied by the Opus decoder.
+    pub decoder_gain_db: f32,
+    /// Rate the decoded audio is resampled to, if it differs from
+    /// `sample_rate`.
+    pub output_rate: Option<u32>,
+    /// Milliseconds after which a packet that has not arrived in order is
+    /// concealed as lost. `None` searches the whole queue for it.
+    pub max_reorder_ms: Option<u32>,
+    /// Serialization of the decoded audio.
+    pub format: RecordFormat,
+    /// Bitrate of an MP3 recording in kbps.
+    pub mp3_bitrate: u32,
+    /// Level in dBFS of the noise that fills the gaps of each stream, or
+    /// `None` to fill them with silence.
+    pub comfort_noise_db: Option<f32>,
+    /// Number of speakers decoded, those with the most packets. `None`
+    /// decodes every speaker.
+    pub max_speakers: Option<usize>,
+}
+
+impl Default for RecordOptions {
+    fn default() -> RecordOptions {
+        RecordOptions {
+            mix: MixMode::default(),
+            pause_mode: PauseMode::default(),
+            loss_mode: LossMode::default(),
+            layout: Layout::default(),
+            channels: CHANNELS,
+            sample_rate: SAMPLING_RATE as u32,
+            recv_batch_size: 1,
+            decoder_gain_db: 0.0,
+            output_rate: None,
+            max_reorder_ms: None,
+            format: RecordFormat::default(),
+            mp3_bitrate: DEFAULT_MP3_BITRATE,
+            comfort_noise_db: None,
+            max_speakers: None,
+        }
+    }
+}
+
+impl RecordOptions {
+    /// These options with the layout replaced, if one is given. The layout
+    /// only matters when decoding, so it can be chosen after recording.
+    pub(crate) fn with_layout(self, layout: Option<Layout>) -> Self {
+        RecordOptions {
+            layout: layout.unwrap_or(self.layout),
+            ..self
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        self.opus_channels()?;
+        self.opus_sample_rate()?;
+        if !(1..=MAX_RECV_BATCH_SIZE).contains(&self.recv_batch_size) {
+            return Err(DiscordError::InvalidArgument(format!(
+                "recv_batch_size must be between 1 and {}: {}",
+                MAX_RECV_BATCH_SIZE, self.recv_batch_size
+            )));
+        }
+        self.opus_gain()?;
+        if self.max_speakers == Some(0) {
+            return Err(DiscordError::InvalidArgument(
+                "max_speakers must be at least 1".to_string(),
+            ));
+        }
+        if let Some(level) = self.comfort_noise_db {
+            if !COMFORT_NOISE_DB_RANGE.contains(&level) {
+                return Err(DiscordError::InvalidArgument(format!(
+                    "comfort_noise_db must be between {} and {}: {}",
+                    COMFORT_NOISE_DB_RANGE.start(),
+                    COMFORT_NOISE_DB_RANGE.end(),
+                    level
+                )));
+            }
+        }
+        if let Some(rate) = self.output_rate {
+            if !(MIN_OUTPUT_RATE..=MAX_OUTPUT_RATE).contains(&rate) {
+                return Err(DiscordError::InvalidArgument(format!(
+                    "output_rate must be between {} and {}: {}",
+                    MIN_OUTPUT_RATE, MAX_OUTPUT_RATE, rate
+                )));
+            }
+        }
+        if self.format == RecordFormat::Mp3 {
+            mp3_bitrate(self.mp3_bitrate)?;
+            // The number of tracks of a multitrack layout, which may also be
+            // chosen when decoding, is only checked by `encode_mp3`.
+            check_mp3_layout(self.channels, self.output_sample_rate())?;
+        }
+        Ok(())
+    }
+
+    /// The sample rate of the recording, after resampling.
+    pub(crate) fn output_sample_rate(&self) -> u32 {
+        self.output_rate.unwrap_or(self.sample_rate)
+    }
+
+    /// The decoder gain in the Q8 dB units of libopus.
+    fn opus_gain(&self) -> Result<i32> {
+        let gain = self.decoder_gain_db;
+        if gain.is_finite() && gain.abs() <= MAX_DECODER_GAIN_DB {
+            Ok((gain * 256.0).round() as i32)
+        } else {
+            Err(DiscordError::InvalidArgument(format!(
+                "decoder_gain_db must be between -{0} and {0}: {1}",
+                MAX_DECODER_GAIN_DB, gain
+            )))
+        }
+    }
+
+    fn opus_channels(&self) -> Result<audiopus::Channels> {
+        match self.channels {
+            1 => Ok(audiopus::Channels::Mono),
+            2 => Ok(audiopus::Channels::Stereo),
+            n => Err(DiscordError::InvalidArgument(format!(
+                "Unsupported number of channels: {}",
+                n
+            ))),
+        }
+    }
+
+    fn opus_sample_rate(&self) -> Result<audiopus::SampleRate> {
+        match self.sample_rate {
+            8000 => Ok(audiopus::SampleRate::Hz8000),
+            12000 => Ok(audiopus::SampleRate::Hz12000),
+            16000 => Ok(audiopus::SampleRate::Hz16000),
+            24000 => Ok(audiopus::SampleRate::Hz24000),
+            48000 => Ok(audiopus::SampleRate::Hz48000),
+            n => Err(DiscordError::InvalidArgument(format!(
+                "Unsupported sample rate: {}",
+                n
+            ))),
+        }
+    }
+}
+
+/// Number of samples (per channel) decoded from real packets and
+/// concealed by the decoder for a single stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct DecodeStats {
+    pub decoded: usize,
+    pub concealed: usize,
+}
+
+impl DecodeStats {
+    pub(crate) fn concealment_ratio(&self) -> f64 {
+        let total = self.decoded + self.concealed;
+        if total == 0 {
+            0.0
+        } else {
+            self.concealed as f64 / total as f64
+        }
+    }
+}
+
+/// RMS and peak amplitude of some decoded audio, where 1.0 is full scale.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Level {
+    pub rms: f32,
+    pub peak: f32,
+}
+
+impl Level {
+    pub(crate) fn of(pcm: &[f32]) -> Self {
+        if pcm.is_empty() {
+            return Self::default();
+        }
+        let (squares, peak) = pcm.iter().fold((0f64, 0f32), |(squares, peak), &sample| {
+            (squares + (sample as f64).powi(2), peak.max(sample.abs()))
+        });
+        Level {
+            rms: (squares / pcm.len() as f64).sqrt() as f32,
+            peak,
+        }
+    }
+}
+
+/// Longest silence in seconds inserted for a jump of the RTP timestamp.
+/// Discord stops sending while a speaker is silent, so longer pauses are
+/// shortened to this.
+const MAX_TIMESTAMP_GAP: f64 = 1.0;
+
+/// Longest silence in seconds kept for a pause with `PauseMode::Silence`,
+/// so that a long pause does not allocate without bound.
+const MAX_PAUSE_SILENCE: f64 = 60.0;
+
+/// Seconds of RTP time from `from` to `to`, counted across the wrap of the
+/// 32 bit timestamp. `None` if `to` is before `from`, that is more than half
+/// of the range ahead.
+fn timestamp_elapsed(from: u32, to: u32) -> Option<f64> {
+    let samples = to.wrapping_sub(from);
+    if samples > u32::MAX / 2 {
+        None
+    } else {
+        Some(samples as f64 / SAMPLING_RATE as f64)
+    }
+}
+
+/// Formats seconds as a WebVTT timestamp, `hh:mm:ss.ttt`.
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Longest pause in seconds between the packets of one speaker within an
+/// utterance of `RecordStats::utterances`.
+const UTTERANCE_GAP: f64 = 0.5;
+
+/// Largest decoder gain in dB, in either direction. libopus takes the gain
+/// as a 16 bit Q8 value.
+const MAX_DECODER_GAIN_DB: f32 = 127.0;
+
+/// Levels of comfort noise in dBFS. Above the range the noise is no longer
+/// a background, below it the noise is as good as silence.
+const COMFORT_NOISE_DB_RANGE: std::ops::RangeInclusive<f32> = -96.0..=-20.0;
+
+/// Fraction of clipped samples above which a warning is logged.
+pub(crate) const CLIPPING_WARN_RATIO: f64 = 0.01;
+
+/// Statistics of the last decoded recording.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct RecordStats {
+    /// Decode statistics keyed by SSRC.
+    pub streams: BTreeMap<u32, DecodeStats>,
+    /// Number of mixed samples.
+    pub samples: usize,
+    /// Number of mixed samples clamped to the valid range.
+    pub clipped: usize,
+    /// SSRC of each channel of a multitrack recording, in channel order.
+    pub tracks: Vec<u32>,
+    /// SSRCs left out because of `RecordOptions::max_speakers`.
+    pub skipped: Vec<u32>,
+    /// `(start, end)` of each utterance keyed by SSRC, in seconds from the
+    /// start of the recording. Not kept by `merge`, since the parts do not
+    /// share a start.
+    pub utterances: BTreeMap<u32, Vec<(f64, f64)>>,
+}
+
+impl RecordStats {
+    pub(crate) fn clipping_ratio(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            self.clipped as f64 / self.samples as f64
+        }
+    }
+
+    /// A WebVTT file with an empty cue per utterance, voiced by the user ID
+    /// of the speaker, or its SSRC if unknown, to be filled with a
+    /// transcription.
+    pub(crate) fn webvtt(&self, users: &HashMap<u32, String>) -> String {
+        let mut cues = self
+            .utterances
+            .iter()
+            .flat_map(|(ssrc, spans)| spans.iter().map(move |&span| (span, *ssrc)))
+            .collect::<Vec<_>>();
+        cues.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mut vtt = String::from("WEBVTT\n");
+        for ((start, end), ssrc) in cues {
+            let speaker = users
+                .get(&ssrc)
+                .cloned()
+                .unwrap_or_else(|| ssrc.to_string());
+            vtt.push_str(&format!(
+                "\n{} --> {}\n<v {}>\n",
+                vtt_timestamp(start),
+                vtt_timestamp(end),
+                speaker
+            ));
+        }
+        vtt
+    }
+
+    /// Adds the statistics of another part of the same recording.
+    fn merge(&mut self, other: &RecordStats) {
+        for (&ssrc, stats) in &other.streams {
+            let merged = self.streams.entry(ssrc).or_default();
+            merged.decoded += stats.decoded;
+            merged.concealed += stats.concealed;
+        }
+        self.samples += other.samples;
+        self.clipped += other.clipped;
+        for &ssrc in &other.tracks {
+            if !self.tracks.contains(&ssrc) {
+                self.tracks.push(ssrc);
+            }
+        }
+        for &ssrc in &other.skipped {
+            if !self.skipped.contains(&ssrc) {
+                self.skipped.push(ssrc);
+            }
+        }
+        // Only speakers skipped in every part are left out of the recording.
+        let streams = &self.streams;
+        self.skipped.retain(|ssrc| {
+            streams
+                .get(ssrc)
+                .is_none_or(|stats| stats.decoded + stats.concealed == 0)
+        });
+    }
+}
+
+/// A speaker identified either by SSRC or by Discord user ID.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Speaker {
+    Ssrc(u32),
+    User(String),
+}
+
+/// Gain applied to the stream of each speaker when it is mixed.
+/// A gain set for an SSRC takes precedence over one set for its user.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RecordGains(HashMap<Speaker, f32>);
+
+impl RecordGains {
+    pub(crate) fn set(&mut self, speaker: Speaker, gain: f32) -> Result<()> {
+        if !gain.is_finite() || gain < 0.0 {
+            return Err(DiscordError::InvalidArgument(format!(
+                "Gain must be a non-negative number: {}",
+                gain
+            )));
+        }
+        self.0.insert(speaker, gain);
+        Ok(())
+    }
+
+    fn gain(&self, ssrc: u32, users: &HashMap<u32, String>) -> f32 {
+        self.0
+            .get(&Speaker::Ssrc(ssrc))
+            .or_else(|| {
+                users
+                    .get(&ssrc)
+                    .and_then(|user| self.0.get(&Speaker::User(user.clone())))
+            })
+            .copied()
+            .unwrap_or(1.0)
+    }
+}
+
 pub(crate) struct AudioDecoder {
     opus: audiopus::coder::Decoder,
     cipher: XSalsa20Poly1305,
     encryption: EncryptionMode,
+    channels: usize,
+    sample_rate: u32,
+    pause_mode: PauseMode,
+    loss_mode: LossMode,
+    /// Seconds a missing packet is waited for, see `PacketQueue::get_packet`.
+    max_reorder: Option<f64>,
+    /// Peak amplitude of the uniform noise filling gaps, if any.
+    comfort_noise: Option<f32>,
+    gains: RecordGains,
+    users: HashMap<u32, String>,
 }
 
 impl AudioDecoder {
-    pub(crate) fn from_gateway(gateway: &VoiceGateway) -> Result<Self> {
-        let decoder = audiopus::coder::Decoder::new(
-            audiopus::SampleRate::Hz48000,
-            audiopus::Channels::Stereo,
-        )?;
-        info!("Audio Decoder created from gateway");
+    pub(crate) fn from_gateway(gateway: &VoiceGateway, options: &RecordOptions) -> Result<Self> {
+        let decoder =
+            audiopus::coder::Decoder::new(options.opus_sample_rate()?, options.opus_channels()?)?;
+        decoder.set_gain(options.opus_gain()?)?;
+        info!(
+            "Audio Decoder created from gateway ({}Hz, {}ch, {}dB)",
+            options.sample_rate, options.channels, options.decoder_gain_db
+        );
         let key = GenericArray::clone_from_slice(&gateway.secret_key);
         let cipher = XSalsa20Poly1305::new(&key);
         let encryption = gateway.encryption;
@@ -44,9 +542,37 @@ impl AudioDecoder {
             opus: decoder,
             cipher,
             encryption,
+            channels: options.channels as usize,
+            sample_rate: options.sample_rate,
+            pause_mode: options.pause_mode,
+            loss_mode: options.loss_mode,
+            max_reorder: options.max_reorder_ms.map(|ms| ms as f64 / 1000.0),
+            // Uniform noise of peak `a` has an RMS of `a / sqrt(3)`.
+            comfort_noise: options
+                .comfort_noise_db
+                .map(|db| 10f32.powf(db / 20.0) * 3f32.sqrt()),
+            gains: RecordGains::default(),
+            users: gateway.ssrc_map.clone(),
         })
     }
 
+    pub(crate) fn set_gains(&mut self, gains: RecordGains) {
+        self.gains = gains;
+    }
+
+    /// Updates the SSRC to user ID map used to look up gains set per user.
+    pub(crate) fn set_users(&mut self, users: HashMap<u32, String>) {
+        self.users = users;
+    }
+
+    fn gain(&self, ssrc: u32) -> f32 {
+        self.gains.gain(ssrc, &self.users)
+    }
+
+    pub(crate) fn users(&self) -> &HashMap<u32, String> {
+        &self.users
+    }
+
     pub(crate) fn decrypt_from_buffer(
         &mut self,
         buffer: &mut dyn Buffer,
@@ -67,44 +593,100 @@ impl AudioDecoder {
         }
     }
 
-    pub(crate) fn decode_packets(&mut self, queue: &mut PacketQueue) -> (f64, Vec<f32>) {
+    /// Decodes a single stream. `pauses` are the `(start, end)` receive times
+    /// during which recording was paused. Also returns the `(start, end)` of
+    /// the utterances in seconds from the start of the decoded stream. They
+    /// are split where no audio was received for `UTTERANCE_GAP`, but timed
+    /// by the decoded samples, so they match the output even where
+    /// `decode_packets` shortened a pause.
+    pub(crate) fn decode_packets(
+        &mut self,
+        queue: &mut PacketQueue,
+        pauses: &[(f64, f64)],
+    ) -> (f64, Vec<f32>, DecodeStats, Vec<(f64, f64)>) {
+        let mut stats = DecodeStats::default();
         let mut pcmdata = Vec::new();
+        let mut utterances: Vec<(f64, f64)> = vec![];
         let mut start_time = std::f64::MAX;
         let mut last_timestamp = None;
+        let mut last_received: Option<f64> = None;
+        let mut last_spoken: Option<f64> = None;
+        let (channels, rate) = (self.channels, self.sample_rate as f64);
+        let seconds = |samples: usize| (samples / channels) as f64 / rate;
         loop {
             debug!("Packet Decode Loop Start");
             use PacketResult::*;
-            match queue.get_packet() {
+            match queue.get_packet(self.max_reorder) {
                 Find(packet) => {
                     debug!(
                         "Recieve Valid Packet: {} {} {:?} {}",
                         packet.1, packet.2, packet.3, packet.4
                     );
                     start_time = start_time.min(packet.4);
+                    let paused = last_received.is_some_and(|received| {
+                        pauses
+                            .iter()
+                            .any(|&(start, _)| received <= start && start < packet.4)
+                    });
+                    if paused && self.pause_mode == PauseMode::Silence {
+                        let elapsed = packet.4 - last_received.unwrap_or(packet.4);
+                        let elapsed = elapsed.min(MAX_PAUSE_SILENCE);
+                        let mut margin =
+                            vec![0.0; self.channels * (elapsed * self.sample_rate as f64) as usize];
+                        pcmdata.append(&mut margin);
+                    }
+                    last_received = Some(packet.4);
                     if packet.1 < 10 {
                         last_timestamp = Some(packet.2);
                         continue;
                     }
-                    if let Some(timestamp) = last_timestamp {
-                        let mut elapsed =
-                            (packet.2 as f64 - timestamp as f64) / SAMPLING_RATE as f64;
+                    if let (Some(timestamp), false) = (last_timestamp, paused) {
+                        let elapsed = timestamp_elapsed(timestamp, packet.2).unwrap_or_else(|| {
+                            warn!(
+                                "RTP timestamp went back from {} to {}; no gap is inserted",
+                                timestamp, packet.2
+                            );
+                            0.0
+                        });
                         if elapsed > 0.02 {
-                            elapsed = elapsed.min(1.0);
-                            let mut margin = vec![
-                                0.0;
-                                2 * (SAMPLE_SIZE as f64 * (elapsed - 0.02) * SAMPLING_RATE as f64)
-                                    as usize
-                            ];
+                            let elapsed = elapsed.min(MAX_TIMESTAMP_GAP);
+                            let mut margin = self.gap(
+                                self.channels
+                                    * (SAMPLE_SIZE as f64
+                                        * (elapsed - 0.02)
+                                        * self.sample_rate as f64)
+                                        as usize,
+                            );
                             pcmdata.append(&mut margin);
                         }
                     }
                     let mut pcm = self.decode_raw(&packet.0, packet.1);
+                    stats.decoded += pcm.len() / self.channels;
+                    let start = seconds(pcmdata.len());
                     pcmdata.append(&mut pcm);
+                    let end = seconds(pcmdata.len());
+                    match utterances.last_mut() {
+                        Some(utterance)
+                            if last_spoken.is_some_and(|last| packet.4 - last <= UTTERANCE_GAP) =>
+                        {
+                            utterance.1 = end
+                        }
+                        _ => utterances.push((start, end)),
+                    }
+                    last_spoken = Some(packet.4);
                     last_timestamp = Some(packet.2)
                 }
                 Dropped => {
                     debug!("Recieve Dropped Packet");
-                    let mut pcm = self.decode_dropped_frame();
+                    let mut pcm = match self.loss_mode {
+                        LossMode::Plc => self.decode_dropped_frame(),
+                        LossMode::Silence => self.silent_frame(),
+                        LossMode::Fec => match queue.after_loss().copied() {
+                            Some(next) if next.1 >= 10 => self.decode_fec(&next.0, next.1),
+                            _ => self.decode_dropped_frame(),
+                        },
+                    };
+                    stats.concealed += pcm.len() / self.channels;
                     pcmdata.append(&mut pcm);
                     last_timestamp = None;
                     continue;
@@ -115,7 +697,7 @@ impl AudioDecoder {
                 }
             }
         }
-        (start_time, pcmdata)
+        (start_time, pcmdata, stats, utterances)
     }
 
     fn decode_raw(&mut self, data: &[u8], size: usize) -> std::vec::Vec<f32> {
@@ -126,29 +708,147 @@ impl AudioDecoder {
             .decode_float(Some(&data[..size]), &mut output[..], false)
             .unwrap_or(0);
         let mut output = output.to_vec();
-        output.truncate(size * 2);
+        output.truncate(size * self.channels);
         output
     }
 
-    fn decode_dropped_frame(&mut self) -> Vec<f32> {
-        debug!("Decoding Packet: DroppedData");
+    /// Samples per channel of a lost frame, assumed to be as long as the
+    /// last decoded one.
+    fn lost_frame_size(&mut self) -> usize {
         let n = self
             .opus
             .last_packet_duration()
-            .unwrap_or(SAMPLES_PER_FRAME) as usize;
+            .unwrap_or(self.sample_rate / 1000 * FRAME_LENGTH as u32) as usize;
+        n.min(1920 / self.channels)
+    }
+
+    fn decode_dropped_frame(&mut self) -> Vec<f32> {
+        debug!("Decoding Packet: DroppedData");
+        let n = self.lost_frame_size();
         if n == 0 {
             return vec![];
         }
         let mut output = [0f32; 1920];
         let size = self
             .opus
-            .decode_float::<&[u8], _>(None, &mut output[..n], false)
+            .decode_float::<&[u8], _>(None, &mut output[..n * self.channels], false)
             .unwrap_or(0);
         debug!("{}", size);
         let mut output = output.to_vec();
-        output.truncate(size * 2);
+        output.truncate(size * self.channels);
         output
     }
+
+    /// Recovers a lost frame from the FEC data of the packet following it.
+    fn decode_fec(&mut self, data: &[u8], size: usize) -> Vec<f32> {
+        debug!("Decoding Packet: FEC");
+        let n = self.lost_frame_size();
+        if n == 0 {
+            return vec![];
+        }
+        let mut output = [0f32; 1920];
+        match self
+            .opus
+            .decode_float(Some(&data[..size]), &mut output[..n * self.channels], true)
+        {
+            Ok(size) => {
+                let mut output = output.to_vec();
+                output.truncate(size * self.channels);
+                output
+            }
+            Err(_) => self.decode_dropped_frame(),
+        }
+    }
+
+    fn silent_frame(&mut self) -> Vec<f32> {
+        let len = self.lost_frame_size() * self.channels;
+        self.gap(len)
+    }
+
+    /// `len` samples of silence, or of comfort noise if it is enabled.
+    fn gap(&self, len: usize) -> Vec<f32> {
+        match self.comfort_noise {
+            Some(peak) => {
+                let mut rng = rand::thread_rng();
+                (0..len).map(|_| rng.gen_range(-peak..=peak)).collect()
+            }
+            None => vec![0.0; len],
+        }
+    }
+}
+
+/// Fixed part of an RTP header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RtpHeader {
+    pub version: u8,
+    pub padding: bool,
+    pub extension: bool,
+    pub csrc_count: u8,
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    pub(crate) fn parse(raw: &[u8; 12]) -> Self {
+        Self {
+            version: raw[0] >> 6,
+            padding: raw[0] & 0b0010_0000 != 0,
+            extension: raw[0] & 0b0001_0000 != 0,
+            csrc_count: raw[0] & 0x0F,
+            marker: raw[1] & 0b1000_0000 != 0,
+            payload_type: raw[1] & 0x7F,
+            sequence: u16::from_be_bytes([raw[2], raw[3]]),
+            timestamp: u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]),
+            ssrc: u32::from_be_bytes([raw[8], raw[9], raw[10], raw[11]]),
+        }
+    }
+}
+
+/// Maximum time headers are held back before a partial batch is delivered.
+pub(crate) const RTP_FLUSH_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Collects the headers of received packets and hands them to a consumer in
+/// batches, so that the consumer is not invoked for every packet.
+pub(crate) struct RtpHeaderSink {
+    consumer: Box<dyn Fn(Vec<RtpHeader>) + Send>,
+    batch_size: usize,
+    pending: Vec<RtpHeader>,
+    last_flush: time::Instant,
+}
+
+impl RtpHeaderSink {
+    pub(crate) fn new<Consumer>(consumer: Consumer, batch_size: usize) -> Self
+    where
+        Consumer: Fn(Vec<RtpHeader>) + Send + 'static,
+    {
+        let batch_size = batch_size.max(1);
+        Self {
+            consumer: Box::new(consumer),
+            batch_size,
+            pending: Vec::with_capacity(batch_size),
+            last_flush: time::Instant::now(),
+        }
+    }
+
+    fn push(&mut self, header: RtpHeader) {
+        self.pending.push(header);
+        if self.pending.len() >= self.batch_size || self.last_flush.elapsed() >= RTP_FLUSH_INTERVAL
+        {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.last_flush = time::Instant::now();
+        if self.pending.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.pending, Vec::with_capacity(self.batch_size));
+        (self.consumer)(batch);
+    }
 }
 
 /// .0: Data
@@ -158,6 +858,14 @@ impl AudioDecoder {
 /// .4: Recieved Time
 type Packet = ([u8; BUFSIZE], usize, u32, Seq, f64);
 
+/// Longest run of lost packets that is concealed. A longer gap in the
+/// sequence numbers means that the stream restarted.
+const MAX_LOST_PACKETS: usize = 50;
+
+/// Number of queued packets searched for one that arrived out of order.
+const MAX_REORDER_SCAN: usize = 1000;
+
+#[derive(Clone)]
 pub(crate) struct PacketQueue(VecDeque<Packet>, Option<Seq>);
 
 pub(crate) enum PacketResult<T> {
@@ -171,47 +879,69 @@ impl PacketQueue {
         Self(VecDeque::new(), None)
     }
 
-    #[allow(clippy::needless_range_loop)]
-    pub(crate) fn get_packet(&mut self) -> PacketResult<Packet> {
+    /// The next packet in sequence order. A missing packet is waited for
+    /// among those received up to `max_hold` seconds after the packet that
+    /// follows it, and reported as `Dropped` otherwise.
+    pub(crate) fn get_packet(&mut self, max_hold: Option<f64>) -> PacketResult<Packet> {
         use PacketResult::*;
-        match self.1 {
-            None => {
-                if let Some(packet) = self.0.pop_front() {
+        loop {
+            let packet = match self.0.pop_front() {
+                Some(packet) => packet,
+                None => return End,
+            };
+            let seq = match self.1 {
+                Some(seq) => seq,
+                None => {
                     debug!("First Packet");
                     self.1 = Some(packet.3);
-                    Find(packet)
-                } else {
-                    End
+                    return Find(packet);
                 }
+            };
+            // Seqが連続して届いた場合
+            if seq.next() == packet.3 {
+                debug!("Sequential Packet");
+                self.1 = Some(packet.3);
+                return Find(packet);
             }
-            Some(seq) => {
-                if let Some(packet) = self.0.pop_front() {
-                    // Seqが連続して届いた場合
-                    if seq.next() == packet.3 {
-                        debug!("Sequential Packet");
-                        self.1 = Some(packet.3);
-                        Find(packet)
-                    } else {
-                        debug!("No-sequential Packet");
-                        for i in 1..1000.min(self.0.len()) {
-                            if self.0.get(i).unwrap().3.next() == packet.3 {
-                                let front = self.0.drain(..i).collect::<Vec<_>>();
-                                for i in 0..front.len() - 1 {
-                                    self.0.push_front(front[i]);
-                                }
-                                let packet = front.last().copied().unwrap();
-                                self.1 = Some(packet.3);
-                                return Find(packet);
-                            }
-                        }
-                        Dropped
-                    }
-                } else {
-                    End
-                }
+            debug!("No-sequential Packet");
+            let expected = seq.next();
+            let position = self
+                .0
+                .iter()
+                .take(MAX_REORDER_SCAN)
+                .take_while(|p| max_hold.is_none_or(|hold| p.4 - packet.4 <= hold))
+                .position(|p| p.3 == expected);
+            if let Some(position) = position {
+                let reordered = self.0.remove(position).unwrap();
+                self.0.push_front(packet);
+                self.1 = Some(reordered.3);
+                return Find(reordered);
+            }
+            let gap = u16::from(packet.3).wrapping_sub(u16::from(seq));
+            if gap == 0 || gap > u16::MAX / 2 {
+                // A duplicate, or a packet that arrived after the ones
+                // following it were played.
+                debug!("Late Packet");
+                continue;
             }
+            if gap as usize - 1 > MAX_LOST_PACKETS {
+                // Too long a gap to conceal; the stream restarted.
+                self.1 = Some(packet.3);
+                return Find(packet);
+            }
+            // Reported once per lost packet until the gap is filled.
+            self.0.push_front(packet);
+            self.1 = Some(seq.next());
+            return Dropped;
         }
     }
+
+    /// The next packet, if it directly follows the one just reported as
+    /// `Dropped`, so that its FEC data covers the lost packet.
+    pub(crate) fn after_loss(&self) -> Option<&Packet> {
+        let lost = self.1?;
+        self.0.front().filter(|packet| lost.next() == packet.3)
+    }
 }
 
 impl Deref for PacketQueue {
@@ -230,22 +960,478 @@ impl DerefMut for PacketQueue {
 
 pub(crate) struct SsrcPacketQueue {
     queue: BTreeMap<u32, PacketQueue>,
+    pauses: Vec<(f64, f64)>,
+    stats: RecordStats,
+    /// Time each SSRC was last received, kept when the packets are discarded.
+    last_seen: HashMap<u32, f64>,
+    /// Seconds of packets kept per SSRC, for a pre-roll buffer. `None` keeps
+    /// every packet.
+    window: Option<f64>,
 }
 
 impl SsrcPacketQueue {
     pub(crate) fn new() -> Self {
         Self {
             queue: BTreeMap::new(),
+            pauses: Vec::new(),
+            stats: RecordStats::default(),
+            last_seen: HashMap::new(),
+            window: None,
+        }
+    }
+
+    /// SSRCs that produced at least one decoded frame in the last `decode`.
+    pub(crate) fn speakers(&self) -> Vec<u32> {
+        self.stats
+            .streams
+            .iter()
+            .filter(|(_, stats)| stats.decoded > 0)
+            .map(|(&ssrc, _)| ssrc)
+            .collect()
+    }
+
+    pub(crate) fn pause(&mut self) {
+        self.pauses.push((now(), std::f64::MAX));
+    }
+
+    pub(crate) fn resume(&mut self) {
+        if let Some(pause) = self.pauses.last_mut() {
+            pause.1 = now();
+        }
+    }
+
+    /// Queues a received packet and notes that its SSRC is active.
+    pub(crate) fn push(&mut self, ssrc: u32, packet: Packet) {
+        self.last_seen.insert(ssrc, packet.4);
+        let queue = self.queue.entry(ssrc).or_insert_with(PacketQueue::new);
+        if let Some(window) = self.window {
+            // A speaker who went quiet keeps at most a window of packets,
+            // which `snapshot` filters by time anyway.
+            let since = packet.4 - window;
+            while queue.front().is_some_and(|old| old.4 < since) {
+                queue.pop_front();
+            }
+        }
+        queue.push_back(packet);
+    }
+
+    /// Keeps only the packets received in the last `window` seconds from now
+    /// on, turning the queue into a pre-roll buffer.
+    pub(crate) fn set_window(&mut self, window: Option<f64>) {
+        self.window = window;
+    }
+
+    /// SSRCs received in the last `window` seconds, in ascending order.
+    pub(crate) fn active_speakers(&self, window: f64) -> Vec<u32> {
+        let since = now() - window;
+        let mut ssrcs = self
+            .last_seen
+            .iter()
+            .filter(|(_, &time)| time >= since)
+            .map(|(&ssrc, _)| ssrc)
+            .collect::<Vec<_>>();
+        ssrcs.sort_unstable();
+        ssrcs
+    }
+
+    /// Statistics of the last `decode`.
+    pub(crate) fn stats(&self) -> &RecordStats {
+        &self.stats
+    }
+
+    pub(crate) fn set_stats(&mut self, stats: RecordStats) {
+        self.stats = stats;
+    }
+
+    /// Discards every packet received so far. A pause that is still in
+    /// progress is kept so that resuming works as usual.
+    pub(crate) fn clear(&mut self) {
+        self.queue.clear();
+        self.pauses.retain(|&(_, end)| end == std::f64::MAX);
+    }
+
+    /// Copies the packets received in the last `window` seconds, or all of
+    /// them, into a new queue. This one is left untouched.
+    pub(crate) fn snapshot(&self, window: Option<f64>) -> SsrcPacketQueue {
+        let since = window.map_or(std::f64::MIN, |window| now() - window);
+        let queue = self
+            .queue
+            .iter()
+            .map(|(&ssrc, queue)| {
+                let packets = queue.iter().filter(|packet| packet.4 >= since).copied();
+                (ssrc, PacketQueue(packets.collect(), None))
+            })
+            .filter(|(_, queue)| !queue.is_empty())
+            .collect();
+        Self {
+            queue,
+            pauses: self.pauses.clone(),
+            stats: RecordStats::default(),
+            last_seen: HashMap::new(),
+            window: None,
+        }
+    }
+
+    /// Moves every packet received so far into a new queue, leaving this one
+    /// empty so that the receiver can keep filling it.
+    pub(crate) fn take(&mut self) -> SsrcPacketQueue {
+        let mut pauses = std::mem::take(&mut self.pauses);
+        // A pause still open goes on here and ends at the checkpoint in the
+        // part taken.
+        if let Some(pause) = pauses.last_mut().filter(|p| p.1 == std::f64::MAX) {
+            self.pauses.push(*pause);
+            pause.1 = now();
+        }
+        Self {
+            queue: std::mem::take(&mut self.queue),
+            pauses,
+            stats: RecordStats::default(),
+            last_seen: HashMap::new(),
+            window: None,
+        }
+    }
+
+    /// Like `snapshot`, but only looks at the end of each stream, so that
+    /// the cost does not grow with the length of the recording.
+    pub(crate) fn recent(&self, window: f64) -> SsrcPacketQueue {
+        let since = now() - window;
+        let mut recent = Self::new();
+        for (&ssrc, queue) in &self.queue {
+            // Packets are queued as they arrive.
+            let mut packets = queue
+                .iter()
+                .rev()
+                .take_while(|packet| packet.4 >= since)
+                .copied()
+                .collect::<Vec<_>>();
+            if !packets.is_empty() {
+                packets.reverse();
+                recent.queue.insert(ssrc, PacketQueue(packets.into(), None));
+            }
+        }
+        recent.pauses = self.pauses.clone();
+        recent
+    }
+
+    /// Decodes the `max` busiest streams and measures the level of each one,
+    /// discarding the audio. Streams without audio are left out.
+    pub(crate) fn levels(
+        &mut self,
+        decoder: &mut AudioDecoder,
+        max: usize,
+    ) -> BTreeMap<u32, Level> {
+        self.keep_busiest(max);
+        let mut levels = BTreeMap::new();
+        for (&ssrc, queue) in self.queue.iter_mut() {
+            let (_, pcm, _, _) = decoder.decode_packets(queue, &self.pauses);
+            if !pcm.is_empty() {
+                levels.insert(ssrc, Level::of(&pcm));
+            }
+        }
+        levels
+    }
+
+    /// Discards the packets of every SSRC but the `max` ones with the most
+    /// packets, the lowest SSRC first on a tie. Returns the discarded SSRCs.
+    fn keep_busiest(&mut self, max: usize) -> Vec<u32> {
+        let mut counts = self
+            .queue
+            .iter()
+            .filter(|(_, queue)| !queue.is_empty())
+            .map(|(&ssrc, queue)| (ssrc, queue.len()))
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1));
+        let skipped = counts
+            .iter()
+            .skip(max)
+            .map(|&(ssrc, _)| ssrc)
+            .collect::<Vec<_>>();
+        for ssrc in &skipped {
+            if let Some(queue) = self.queue.get_mut(ssrc) {
+                queue.clear();
+            }
+        }
+        skipped
+    }
+
+    /// Decodes every queued stream, resamples it to the output rate and pads
+    /// each one with silence so that they all start at the time of the
+    /// earliest stream. Streams are ordered by the time they started.
+    fn align(
+        &mut self,
+        decoder: &mut AudioDecoder,
+        options: &RecordOptions,
+    ) -> Result<Vec<(u32, Vec<f32>)>> {
+        let channels = options.channels as usize;
+        let rate = options.output_sample_rate();
+        let mut stats = RecordStats::default();
+        if let Some(max) = options.max_speakers {
+            stats.skipped = self.keep_busiest(max);
+            if !stats.skipped.is_empty() {
+                info!(
+                    "Decoding {} speakers; {} with fewer packets are skipped",
+                    max,
+                    stats.skipped.len()
+                );
+            }
+        }
+        let mut pcm_list = vec![];
+        for (&ssrc, queue) in self.queue.iter_mut() {
+            let (time, mut pcm, stat, utterances) = decoder.decode_packets(queue, &self.pauses);
+            stats.streams.insert(ssrc, stat);
+            let gain = decoder.gain(ssrc);
+            if gain != 1.0 {
+                pcm.iter_mut().for_each(|sample| *sample *= gain);
+            }
+            let pcm = resample(&pcm, channels, options.sample_rate, rate)?;
+            if !pcm.is_empty() {
+                pcm_list.push((time, ssrc, pcm, utterances));
+            }
+        }
+        self.stats = stats;
+        pcm_list.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        debug!("PCM List: len:{}", pcm_list.len());
+        let first_time = match pcm_list.get(0) {
+            Some(&(time, _, _, _)) => time,
+            None => return Ok(vec![]),
+        };
+
+        let mut pcms = vec![];
+        for (time, ssrc, mut packet, utterances) in pcm_list {
+            let offset = time - first_time;
+            self.stats.utterances.insert(
+                ssrc,
+                utterances
+                    .into_iter()
+                    .map(|(start, end)| (start + offset, end + offset))
+                    .collect(),
+            );
+            let mut margin = vec![
+                0f32;
+                (rate as f64 * channels as f64 * (time - first_time)) as usize
+                    / channels
+                    * channels
+            ];
+            margin.append(&mut packet);
+            pcms.push((ssrc, margin));
         }
+        Ok(pcms)
+    }
+
+    fn warn_clipping(&self) {
+        let ratio = self.stats.clipping_ratio();
+        if ratio > CLIPPING_WARN_RATIO {
+            warn!(
+                "{:.1}% of the recording is clipped; consider mix=\"average\"",
+                ratio * 100.0
+            );
+        }
+    }
+
+    /// Decodes every queued stream and mixes them into interleaved samples.
+    /// Returns `None` if no stream produced any audio.
+    pub(crate) fn mix(
+        &mut self,
+        decoder: &mut AudioDecoder,
+        options: &RecordOptions,
+    ) -> Result<Option<Vec<i16>>> {
+        let pcms = self.align(decoder, options)?;
+        if pcms.is_empty() {
+            return Ok(None);
+        }
+
+        let channels = options.channels as usize;
+        let mut mixed = vec![];
+        let range = pcms.iter().map(|(_, v)| v.len()).max().unwrap();
+        for frame in 0..range {
+            let mut result = 0.0;
+            let mut active = 0;
+            for (_, pcm) in &pcms {
+                let byte = match pcm.get(frame) {
+                    Some(&byte) => byte,
+                    None => continue,
+                };
+                active += 1;
+                result = options.mix.blend(result, byte);
+            }
+            if options.mix == MixMode::Average && active > 0 {
+                result /= active as f32;
+            }
+            if !(-1.0..=1.0).contains(&result) {
+                self.stats.clipped += 1;
+            }
+            mixed.push((result.min(1.0).max(-1.0) * 32767.0) as i16);
+        }
+        mixed.truncate(mixed.len() / channels * channels);
+        self.stats.samples = mixed.len();
+        self.warn_clipping();
+        Ok(Some(mixed))
+    }
+
+    /// Decodes every queued stream into its own mono channel and interleaves
+    /// them. The SSRC of each channel is recorded in `RecordStats::tracks`.
+    /// Returns `None` if no stream produced any audio.
+    pub(crate) fn multitrack(
+        &mut self,
+        decoder: &mut AudioDecoder,
+        options: &RecordOptions,
+    ) -> Result<Option<Vec<i16>>> {
+        let mut pcms = self.align(decoder, options)?;
+        if pcms.is_empty() {
+            return Ok(None);
+        }
+        if pcms.len() > MAX_TRACKS {
+            warn!(
+                "{} speakers recorded; only the first {} are written",
+                pcms.len(),
+                MAX_TRACKS
+            );
+            pcms.truncate(MAX_TRACKS);
+        }
+
+        let channels = options.channels as usize;
+        let frames = pcms.iter().map(|(_, v)| v.len()).max().unwrap() / channels;
+        let mut interleaved = Vec::with_capacity(frames * pcms.len());
+        for frame in 0..frames {
+            for (_, pcm) in &pcms {
+                let sum: f32 = (0..channels)
+                    .map(|c| pcm.get(frame * channels + c).copied().unwrap_or(0.0))
+                    .sum();
+                let sample = sum / channels as f32;
+                if !(-1.0..=1.0).contains(&sample) {
+                    self.stats.clipped += 1;
+                }
+                interleaved.push((sample.min(1.0).max(-1.0) * 32767.0) as i16);
+            }
+        }
+        self.stats.tracks = pcms.iter().map(|&(ssrc, _)| ssrc).collect();
+        self.stats.samples = interleaved.len();
+        self.warn_clipping();
+        Ok(Some(interleaved))
+    }
+
+    /// Splits the queue into utterances separated by more than `min_gap`
+    /// seconds in which nobody spoke, each with its start in seconds from
+    /// the first one. Discord stops sending packets while a speaker is
+    /// silent, so the gaps are taken from the receive times of the packets
+    /// that carry audio rather than from the decoded samples, whose gaps are
+    /// shortened by `decode_packets`.
+    pub(crate) fn split_on_silence(&mut self, min_gap: f64) -> Vec<(f64, SsrcPacketQueue)> {
+        let mut received = self
+            .queue
+            .values()
+            .flat_map(|queue| queue.iter())
+            .filter(|packet| packet.1 >= 10)
+            .map(|packet| packet.4)
+            .collect::<Vec<_>>();
+        received.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mut starts = vec![];
+        let mut last: Option<f64> = None;
+        for time in received {
+            if last.is_none_or(|last| time - last > min_gap) {
+                starts.push(time);
+            }
+            last = Some(time);
+        }
+        let first = match starts.first() {
+            Some(&first) => first,
+            None => return vec![],
+        };
+
+        let mut segments = starts
+            .iter()
+            .map(|_| Self {
+                queue: BTreeMap::new(),
+                pauses: self.pauses.clone(),
+                stats: RecordStats::default(),
+                last_seen: HashMap::new(),
+                window: None,
+            })
+            .collect::<Vec<_>>();
+        for (ssrc, mut queue) in std::mem::take(&mut self.queue) {
+            for packet in queue.drain(..) {
+                // Silence frames received before the first utterance belong
+                // to it.
+                let index = starts
+                    .iter()
+                    .rposition(|&start| start <= packet.4)
+                    .unwrap_or(0);
+                segments[index]
+                    .queue
+                    .entry(ssrc)
+                    .or_insert_with(PacketQueue::new)
+                    .push_back(packet);
+            }
+        }
+        starts
+            .into_iter()
+            .map(|start| start - first)
+            .zip(segments)
+            .collect()
+    }
+
+    /// Decodes every utterance found by `split_on_silence` into its own WAV
+    /// file. The statistics cover all of them.
+    pub(crate) fn decode_segments(
+        &mut self,
+        decoder: &mut AudioDecoder,
+        options: &RecordOptions,
+        min_gap: f64,
+    ) -> Result<Vec<(f64, Vec<u8>)>> {
+        let mut stats = RecordStats::default();
+        let mut segments = vec![];
+        for (start, mut segment) in self.split_on_silence(min_gap) {
+            if let Some(data) = segment.decode(decoder, options)? {
+                segments.push((start, data));
+            }
+            stats.merge(segment.stats());
+        }
+        self.stats = stats;
+        Ok(segments)
     }
 
     // pub(crate) fn reset(&mut self) {
     //     self.queue = BTreeMap::new();
     // }
-    pub(crate) fn decode(&mut self, decoder: &mut AudioDecoder) -> Result<Option<Vec<u8>>> {
+    /// Decodes the queue in the layout of `options` into interleaved samples
+    /// and their number of channels, without serializing them.
+    pub(crate) fn decode_samples(
+        &mut self,
+        decoder: &mut AudioDecoder,
+        options: &RecordOptions,
+    ) -> Result<Option<(u16, Vec<i16>)>> {
+        let samples = match options.layout {
+            Layout::Mixed => self.mix(decoder, options)?,
+            Layout::Multitrack => self.multitrack(decoder, options)?,
+        };
+        let channels = match options.layout {
+            Layout::Mixed => options.channels,
+            Layout::Multitrack => self.stats.tracks.len() as u16,
+        };
+        Ok(samples.map(|samples| (channels, samples)))
+    }
+
+    pub(crate) fn decode(
+        &mut self,
+        decoder: &mut AudioDecoder,
+        options: &RecordOptions,
+    ) -> Result<Option<Vec<u8>>> {
+        let (channels, samples) = match self.decode_samples(decoder, options)? {
+            Some(decoded) => decoded,
+            None => return Ok(None),
+        };
+        if options.format == RecordFormat::Mp3 {
+            let mp3 = encode_mp3(
+                &samples,
+                channels,
+                options.output_sample_rate(),
+                options.mp3_bitrate,
+            )?;
+            return Ok(Some(mp3));
+        }
         let wavspec = WavSpec {
-            channels: CHANNELS,
-            sample_rate: SAMPLING_RATE as u32,
+            channels,
+            sample_rate: options.output_sample_rate(),
             bits_per_sample: 16,
             sample_format: SampleFormat::Int,
         };
@@ -253,53 +1439,8 @@ impl SsrcPacketQueue {
         {
             let cursor = Cursor::new(&mut buffer);
             let mut wavwriter = WavWriter::new(cursor, wavspec)?;
-
-            let mut pcm_list = self
-                .queue
-                .iter_mut()
-                .map(|(&_ssrc, mut queue)| decoder.decode_packets(&mut queue))
-                .collect::<Vec<(f64, Vec<f32>)>>();
-            pcm_list.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-            debug!("PCM List: len:{}", pcm_list.len());
-            if pcm_list.is_empty() {
-                return Ok(None);
-            } else {
-                let first_time = pcm_list.get(0).unwrap().0;
-
-                let mut r_channel = vec![];
-                let mut l_channel = vec![];
-                let mut pcms = vec![];
-                for i in 0..pcm_list.len() {
-                    let (time, packet) = pcm_list.get_mut(i).unwrap();
-                    let mut margin =
-                        vec![0f32; (SAMPLING_RATE as f64 * 2.0 * (*time - first_time)) as usize];
-                    let mut packet = packet.clone();
-                    margin.append(&mut packet);
-                    pcms.push(margin);
-                }
-                let range = pcms.iter().map(|v| v.len()).max().unwrap();
-                for frame in 0..range {
-                    let mut result = 0.0;
-                    for user in 0..pcms.len() {
-                        let byte = pcms[user][frame];
-                        result = match (result, byte) {
-                            (r, b) if r < 0.0 && b < 0.0 => r + b - (r * b * -1.0),
-                            (r, b) if r > 0.0 && b > 0.0 => r + b - (r * b),
-                            (r, b) => r + b,
-                        };
-                    }
-                    if frame % 2 == 0 {
-                        r_channel.push(result.min(1.0).max(-1.0));
-                    } else {
-                        l_channel.push(result.min(1.0).max(-1.0));
-                    }
-                }
-                for (&l, &r) in l_channel.iter().zip(r_channel.iter()) {
-                    let r: i16 = (r * 32767.0) as i16;
-                    let l: i16 = (l * 32767.0) as i16;
-                    wavwriter.write_sample(r)?;
-                    wavwriter.write_sample(l)?;
-                }
+            for sample in samples {
+                wavwriter.write_sample(sample)?;
             }
             wavwriter.finalize()?;
         }
@@ -327,77 +1468,404 @@ pub(crate) struct AudioRecorder {
     gateway: Arc<Mutex<VoiceGateway>>,
     state: Arc<State>,
     queue: Arc<Mutex<SsrcPacketQueue>>,
+    pub options: RecordOptions,
+    started: time::Instant,
+}
+
+/// Size in the header of a WAV file whose length is not known when the
+/// header is written, which streaming readers take as "until the end".
+pub(crate) const WAV_UNKNOWN_SIZE: u32 = 0xFFFF_FFFF;
+
+/// Header of a 16-bit PCM WAV file written as a stream, whose RIFF and data
+/// sizes are `WAV_UNKNOWN_SIZE` since a writer that cannot seek back never
+/// gets to fill them in.
+pub(crate) fn wav_stream_header(channels: u16, sample_rate: u32) -> Vec<u8> {
+    let block_align = channels * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&WAV_UNKNOWN_SIZE.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&WAV_UNKNOWN_SIZE.to_le_bytes());
+    header
+}
+
+/// Hex encoded SHA-256 digest of a recording, so that it can be checked for
+/// corruption later.
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Notices when no voice packet arrived for `timeout` while recording, which
+/// tells a broken media path, or a channel where everyone is muted, from a
+/// recording that merely has quiet moments. The recorder reports the silence
+/// here and the thread of `VoiceConnection::run` calls back Python, so that
+/// the recorder never waits for the GIL.
+#[derive(Debug, Default)]
+pub(crate) struct MediaWatch {
+    pub timeout: Option<time::Duration>,
+    /// Silence reported by the recorder and not yet taken.
+    timed_out: Option<time::Duration>,
+}
+
+impl MediaWatch {
+    /// Takes the length of the silence reported since the last call, if any.
+    pub(crate) fn take_timeout(&mut self) -> Option<time::Duration> {
+        self.timed_out.take()
+    }
+}
+
+/// Read timeout of the recorder socket. The state is checked every time it
+/// expires, so this bounds how long stopping takes on a silent channel.
+const RECV_TIMEOUT: time::Duration = time::Duration::from_millis(100);
+
+/// Longest pre-roll buffer, in seconds. Each packet takes a full datagram
+/// buffer, so a minute of one speaker is about 4MB.
+pub(crate) const MAX_PREROLL_SECS: f64 = 60.0;
+
+/// Largest number of packets the receiver holds back before pushing them.
+const MAX_RECV_BATCH_SIZE: usize = 256;
+
+/// Longest time the receiver holds back a packet, so that a batch that fills
+/// slowly still reaches the queue in time for live decoding.
+const RECV_FLUSH_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Pushes the packets held back by the receiver under a single lock.
+fn push_packets(queue: &Mutex<SsrcPacketQueue>, pending: &mut Vec<(u32, Packet)>) {
+    if pending.is_empty() {
+        return;
+    }
+    let mut queue = queue.lock();
+    for (ssrc, packet) in pending.drain(..) {
+        queue.push(ssrc, packet);
+    }
+}
+
+/// Interval at which the receiver logs its packet statistics.
+const RECV_STATS_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// Jitter estimate of a stream, as described in RFC 3550 section 6.4.1.
+struct StreamJitter {
+    last_seq: u16,
+    last_transit: f64,
+    /// Interarrival jitter in RTP timestamp units.
+    jitter: f64,
+}
+
+/// Packet statistics of the receiver, aggregated over `RECV_STATS_INTERVAL`
+/// and logged at the info level so that throughput can be monitored without
+/// per-packet debug logs.
+struct ReceiveStats {
+    connection_id: usize,
+    since: time::Instant,
+    packets: usize,
+    lost: usize,
+    active: HashSet<u32>,
+    streams: HashMap<u32, StreamJitter>,
+}
+
+impl ReceiveStats {
+    fn new(connection_id: usize) -> Self {
+        Self {
+            connection_id,
+            since: time::Instant::now(),
+            packets: 0,
+            lost: 0,
+            active: HashSet::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, header: &RtpHeader, received: f64) {
+        self.packets += 1;
+        self.active.insert(header.ssrc);
+        let transit = received * SAMPLING_RATE as f64 - header.timestamp as f64;
+        match self.streams.get_mut(&header.ssrc) {
+            Some(stream) => {
+                // Reordered and duplicated packets are not counted as lost.
+                let gap = header.sequence.wrapping_sub(stream.last_seq);
+                if gap > 0 && gap < u16::MAX / 2 {
+                    self.lost += gap as usize - 1;
+                    stream.last_seq = header.sequence;
+                }
+                let delta = (transit - stream.last_transit).abs();
+                stream.jitter += (delta - stream.jitter) / 16.0;
+                stream.last_transit = transit;
+            }
+            None => {
+                self.streams.insert(
+                    header.ssrc,
+                    StreamJitter {
+                        last_seq: header.sequence,
+                        last_transit: transit,
+                        jitter: 0.0,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Logs and resets the counters once the interval has elapsed.
+    fn log_if_due(&mut self) {
+        let elapsed = self.since.elapsed();
+        if elapsed < RECV_STATS_INTERVAL {
+            return;
+        }
+        let expected = self.packets + self.lost;
+        let loss = if expected == 0 {
+            0.0
+        } else {
+            self.lost as f64 / expected as f64
+        };
+        let jitters = self
+            .active
+            .iter()
+            .filter_map(|ssrc| self.streams.get(ssrc))
+            .map(|stream| stream.jitter)
+            .collect::<Vec<_>>();
+        let jitter = if jitters.is_empty() {
+            0.0
+        } else {
+            jitters.iter().sum::<f64>() / jitters.len() as f64 / SAMPLING_RATE as f64 * 1000.0
+        };
+        info!(
+            "[conn {}] Received {} packets ({:.1}/s), {} lost ({:.1}%), {} active SSRCs, jitter {:.1}ms",
+            self.connection_id,
+            self.packets,
+            self.packets as f64 / elapsed.as_secs_f64(),
+            self.lost,
+            loss * 100.0,
+            self.active.len(),
+            jitter
+        );
+        self.since = time::Instant::now();
+        self.packets = 0;
+        self.lost = 0;
+        self.active.clear();
+    }
 }
 
 fn recv_loop(
     gateway: &Arc<Mutex<VoiceGateway>>,
     state: &Arc<State>,
     queue: &Arc<Mutex<SsrcPacketQueue>>,
+    options: &RecordOptions,
+    rtp_sink: &mut Option<RtpHeaderSink>,
+    media_watch: &Mutex<MediaWatch>,
 ) -> Result<()> {
-    let (mut decoder, socket) = {
+    let mut pending = Vec::with_capacity(options.recv_batch_size);
+    let result = receive_packets(
+        gateway,
+        state,
+        queue,
+        options,
+        rtp_sink,
+        media_watch,
+        &mut pending,
+    );
+    // The queue outlives the recorder thread, so that what was received
+    // before a failure, e.g. a connection that could not be re-established,
+    // can still be decoded by `stop_record`. That includes the packets held
+    // back when the loop failed.
+    push_packets(queue, &mut pending);
+    result
+}
+
+fn receive_packets(
+    gateway: &Arc<Mutex<VoiceGateway>>,
+    state: &Arc<State>,
+    queue: &Arc<Mutex<SsrcPacketQueue>>,
+    options: &RecordOptions,
+    rtp_sink: &mut Option<RtpHeaderSink>,
+    media_watch: &Mutex<MediaWatch>,
+    pending: &mut Vec<(u32, Packet)>,
+) -> Result<()> {
+    let (mut decoder, mut socket, generation, id) = {
         let gateway = gateway.lock();
         (
-            AudioDecoder::from_gateway(&*gateway)?,
+            AudioDecoder::from_gateway(&*gateway, options)?,
             gateway.clone_socket()?,
+            Arc::clone(&gateway.generation),
+            gateway.id,
         )
     };
+    let mut current_generation = generation.load(Ordering::Acquire);
+    // Wakes the loop up now and then so that it notices a stop or a reconnect
+    // even though nothing arrives on the socket.
+    socket.set_read_timeout(Some(RECV_TIMEOUT))?;
 
     let addr = socket.peer_addr()?;
-    info!("Socket connected to: {:?}", addr);
+    info!("[conn {}] Recorder socket connected to: {:?}", id, addr);
+
+    let mut pending_since = time::Instant::now();
+    let mut stats = ReceiveStats::new(id);
+    // Reported once per silence, however long it lasts.
+    let mut last_packet = time::Instant::now();
+    let mut silence_reported = false;
 
     use ConnectionState::*;
     loop {
         debug!("State: {:?}", state);
+        stats.log_if_due();
+        if generation.load(Ordering::Acquire) != current_generation {
+            let gateway = gateway.lock();
+            current_generation = gateway.generation.load(Ordering::Acquire);
+            decoder = AudioDecoder::from_gateway(&*gateway, options)?;
+            socket = gateway.clone_socket()?;
+            socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+            info!(
+                "[conn {}] Recorder socket reconnected to: {:?}",
+                id,
+                socket.peer_addr()?
+            );
+        }
+        if state.is_state(RecordPaused) {
+            push_packets(queue, pending);
+            let mut data = [0; BUFSIZE];
+            let _ = recv_or_timeout(&socket, &mut data)?;
+            // Nothing is expected while paused.
+            last_packet = time::Instant::now();
+            continue;
+        }
         if !state.is_state(Recording) {
-            let mut data = [0; 10_000];
-            let _ = socket.recv(&mut data)?;
+            push_packets(queue, pending);
             break;
         }
         let mut data = [0; BUFSIZE];
 
-        let mut size = socket.recv(&mut data)?;
+        let size = match recv_or_timeout(&socket, &mut data)? {
+            Some(size) => size,
+            None => {
+                push_packets(queue, pending);
+                let silence = last_packet.elapsed();
+                let mut watch = media_watch.lock();
+                if !silence_reported && watch.timeout.is_some_and(|timeout| silence >= timeout) {
+                    warn!("[conn {}] No voice packet for {:?}", id, silence);
+                    watch.timed_out = Some(silence);
+                    silence_reported = true;
+                }
+                continue;
+            }
+        };
         debug!("Datagram Received: Length: {}", size);
 
         let mut buffer = AudioBuffer::new(&mut data, size);
         if let Some(raw_header) = decoder.decrypt_from_buffer(&mut buffer)? {
-            let ssrc = {
-                (raw_header[8] as u32) << 24
-                    | (raw_header[9] as u32) << 16
-                    | (raw_header[10] as u32) << 8
-                    | raw_header[11] as u32
-            };
-            let timestamp = {
-                (raw_header[4] as u32) << 24
-                    | (raw_header[5] as u32) << 16
-                    | (raw_header[6] as u32) << 8
-                    | raw_header[7] as u32
-            };
-            let seq = Seq::from((raw_header[2] as u16) << 8 | raw_header[3] as u16);
+            let header = RtpHeader::parse(&raw_header);
+            let received = now();
+            last_packet = time::Instant::now();
+            silence_reported = false;
+            stats.record(&header, received);
+            if let Some(sink) = rtp_sink {
+                sink.push(header);
+            }
+            let ssrc = header.ssrc;
+            let timestamp = header.timestamp;
+            let seq = Seq::from(header.sequence);
 
-            size -= 12;
-            let offset = calc_offset(&data);
+            // Without the header, the MAC and the nonce.
+            let mut size = buffer.as_ref().len();
+            if header.padding {
+                size = match strip_padding(&data[..size]) {
+                    Some(size) => size,
+                    None => {
+                        warn!("[conn {}] Dropping a packet with invalid padding", id);
+                        continue;
+                    }
+                };
+            }
+            let offset = calc_offset(&data).min(size);
             data.rotate_left(offset);
             size -= offset;
 
-            let mut queue = queue.lock();
-            queue
-                .entry(ssrc)
-                .or_insert_with(PacketQueue::new)
-                .push_back((
-                    data,
-                    size,
-                    timestamp,
-                    seq,
-                    time::SystemTime::now()
-                        .duration_since(time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs_f64(),
-                ));
+            if pending.is_empty() {
+                pending_since = time::Instant::now();
+            }
+            pending.push((ssrc, (data, size, timestamp, seq, received)));
+            if pending.len() >= options.recv_batch_size
+                || pending_since.elapsed() >= RECV_FLUSH_INTERVAL
+            {
+                push_packets(queue, pending);
+            }
         }
     }
     Ok(())
 }
 
+/// Receives a datagram, returning `None` if the read timeout expired first.
+fn recv_or_timeout(socket: &UdpSocket, data: &mut [u8]) -> Result<Option<usize>> {
+    match socket.recv(data) {
+        Ok(size) => Ok(Some(size)),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn now() -> f64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+/// Decrypts a voice packet with the key of `gateway` and returns its RTP
+/// header and Opus payload, without the header extension and the padding.
+/// RTCP packets are not decrypted and yield `None`.
+pub(crate) fn decrypt_packet(
+    gateway: &VoiceGateway,
+    packet: &[u8],
+) -> Result<Option<(RtpHeader, Vec<u8>)>> {
+    let min_size = 12 + gateway.encryption.overhead();
+    if packet.len() < min_size || packet.len() > BUFSIZE {
+        return Err(DiscordError::InvalidArgument(format!(
+            "Packet must be between {} and {} bytes: {}",
+            min_size,
+            BUFSIZE,
+            packet.len()
+        )));
+    }
+    if let 200..=204 = packet[1] {
+        return Ok(None);
+    }
+    let key = GenericArray::clone_from_slice(&gateway.secret_key);
+    let cipher = XSalsa20Poly1305::new(&key);
+    let mut data = [0; BUFSIZE];
+    data[..packet.len()].copy_from_slice(packet);
+    let mut buffer = AudioBuffer::new(&mut data, packet.len());
+    let header = gateway
+        .encryption
+        .decrypt(&cipher, &mut buffer)
+        .map_err(DiscordError::EncryptionError)?;
+    let header = RtpHeader::parse(&header);
+    let mut size = buffer.as_ref().len();
+    if header.padding {
+        size = strip_padding(&data[..size]).ok_or_else(|| {
+            DiscordError::InvalidArgument("Packet has invalid RTP padding".to_string())
+        })?;
+    }
+    let offset = calc_offset(&data).min(size);
+    Ok(Some((header, data[offset..size].to_vec())))
+}
+
+/// Length of a decrypted payload without its RTP padding, whose last byte
+/// counts the padding bytes including itself. `None` if the count is zero
+/// or exceeds the payload.
+fn strip_padding(payload: &[u8]) -> Option<usize> {
+    let count = *payload.last()? as usize;
+    if count == 0 || count > payload.len() {
+        return None;
+    }
+    Some(payload.len() - count)
+}
+
 fn calc_offset(data: &[u8]) -> usize {
     if !(data[0] == 0xBE && data[1] == 0xDE && data.len() > 4) {
         return 0;
@@ -426,6 +1894,9 @@ impl AudioRecorder {
         after: After,
         gateway: Arc<Mutex<VoiceGateway>>,
         queue: Arc<Mutex<SsrcPacketQueue>>,
+        options: RecordOptions,
+        mut rtp_sink: Option<RtpHeaderSink>,
+        media_watch: Arc<Mutex<MediaWatch>>,
     ) -> Self
     where
         After: FnOnce(Option<DiscordError>) + Send + 'static,
@@ -436,16 +1907,30 @@ impl AudioRecorder {
             Arc::clone(&gateway.state)
         };
         state.set_state(Recording);
+        let active = ActiveGuard::new(&ACTIVE_RECORDERS);
 
         Self {
             gateway: Arc::clone(&gateway),
             state: Arc::clone(&state),
             queue: Arc::clone(&queue),
+            options,
+            started: time::Instant::now(),
             thread: thread::spawn(move || {
+                let _active = active;
                 let mut err = None;
-                if let Err(e) = recv_loop(&gateway, &state, &queue) {
+                if let Err(e) = recv_loop(
+                    &gateway,
+                    &state,
+                    &queue,
+                    &options,
+                    &mut rtp_sink,
+                    &media_watch,
+                ) {
                     err = Some(e);
                 }
+                if let Some(sink) = &mut rtp_sink {
+                    sink.flush();
+                }
                 after(err);
             }),
         }
@@ -455,7 +1940,492 @@ impl AudioRecorder {
         self.state.set_state(ConnectionState::RecordFinished);
     }
 
+    /// Stops recording if it is recording or paused. Returns whether it was,
+    /// so that of several callers racing to stop it exactly one sees `true`.
+    pub fn try_stop(&self) -> bool {
+        use ConnectionState::*;
+        self.state
+            .compare_and_set(&[Recording, RecordPaused], RecordFinished)
+    }
+
+    pub fn pause(&self) {
+        use ConnectionState::*;
+        if self.state.compare_and_set(&[Recording], RecordPaused) {
+            self.queue.lock().pause();
+        }
+    }
+
+    pub fn resume(&self) {
+        use ConnectionState::*;
+        if self.state.compare_and_set(&[RecordPaused], Recording) {
+            self.queue.lock().resume();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.is_state(ConnectionState::RecordPaused)
+    }
+
+    /// Stops recording and waits up to `timeout` for the receiver thread to exit.
+    /// Returns `false` if the thread is still running after the timeout.
+    pub fn stop_and_join(self, timeout: time::Duration) -> bool {
+        self.stop();
+        let deadline = time::Instant::now() + timeout;
+        while !self.thread.is_finished() {
+            if time::Instant::now() >= deadline {
+                warn!("Recorder thread did not finish within {:?}", timeout);
+                return false;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        let _ = self.thread.join();
+        true
+    }
+
     pub fn is_recording(&self) -> bool {
         self.state.is_state(ConnectionState::Recording)
     }
+
+    pub fn is_alive(&self) -> bool {
+        !self.thread.is_finished()
+    }
+
+    /// Time elapsed since the recorder was started.
+    pub fn elapsed(&self) -> time::Duration {
+        self.started.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::ws::SessionState;
+
+    const OPUS: &[u8] = &[0xF8, 0xFF, 0xFE];
+
+    fn session() -> SessionState {
+        SessionState {
+            endpoint: String::new(),
+            server_id: String::new(),
+            user_id: String::new(),
+            session_id: String::new(),
+            token: String::new(),
+            ssrc: 3,
+            endpoint_ip: "127.0.0.1".to_string(),
+            port: 9,
+            encryption: EncryptionMode::XSalsa20Poly1305Lite,
+            secret_key: [7; 32],
+        }
+    }
+
+    fn packet(seq: u16, received: f64) -> Packet {
+        ([0; BUFSIZE], 0, 0, Seq::from(seq), received)
+    }
+
+    fn queue_of(packets: Vec<Packet>) -> PacketQueue {
+        let mut queue = PacketQueue::new();
+        queue.extend(packets);
+        queue
+    }
+
+    /// Sequence numbers in the order they are played, `None` for a packet
+    /// reported as lost.
+    fn played(queue: &mut PacketQueue, max_hold: Option<f64>) -> Vec<Option<u16>> {
+        let mut played = vec![];
+        loop {
+            match queue.get_packet(max_hold) {
+                PacketResult::Find(packet) => played.push(Some(u16::from(packet.3))),
+                PacketResult::Dropped => played.push(None),
+                PacketResult::End => return played,
+            }
+        }
+    }
+
+    /// `count` packets of consecutive 20ms frames of a sine, encoded with
+    /// FEC data.
+    fn sine_packets(count: u16) -> Vec<Packet> {
+        let mut encoder = audiopus::coder::Encoder::new(
+            audiopus::SampleRate::Hz48000,
+            audiopus::Channels::Stereo,
+            audiopus::Application::Voip,
+        )
+        .unwrap();
+        encoder
+            .set_bitrate(audiopus::Bitrate::BitsPerSecond(24_000))
+            .unwrap();
+        encoder.set_inband_fec(true).unwrap();
+        encoder.set_packet_loss_perc(20).unwrap();
+        let mut packets = vec![];
+        for seq in 0..count {
+            let pcm = (0..960)
+                .flat_map(|i| {
+                    let t = (seq as usize * 960 + i) as f32 / 48000.0;
+                    let sample = ((t * 440.0 * 2.0 * std::f32::consts::PI).sin() * 8000.0) as i16;
+                    vec![sample, sample]
+                })
+                .collect::<Vec<_>>();
+            let mut data = [0; BUFSIZE];
+            let size = encoder.encode(&pcm, &mut data).unwrap();
+            let timestamp = seq as u32 * 960;
+            packets.push((data, size, timestamp, Seq::from(seq), seq as f64 * 0.02));
+        }
+        packets
+    }
+
+    #[test]
+    fn padding_is_removed_from_the_payload() {
+        let session = session();
+        let gateway = VoiceGateway::detached(&session).unwrap();
+        let cipher = XSalsa20Poly1305::new(GenericArray::from_slice(&session.secret_key));
+        // The padding bit is set and 4 bytes of padding follow the audio.
+        let header = [0xA0, 0x78, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        let mut plain = OPUS.to_vec();
+        plain.extend_from_slice(&[0, 0, 0, 4]);
+
+        let mut data = [0u8; 128];
+        data[..12].copy_from_slice(&header);
+        data[12..12 + plain.len()].copy_from_slice(&plain);
+        let mut buffer = AudioBuffer::new(&mut data[12..], plain.len());
+        session
+            .encryption
+            .encrypt(
+                &cipher,
+                1,
+                &header,
+                &mut buffer,
+                &mut StdRng::seed_from_u64(0),
+            )
+            .unwrap();
+        let size = 12 + buffer.as_ref().len();
+
+        let (header, payload) = decrypt_packet(&gateway, &data[..size]).unwrap().unwrap();
+        assert!(header.padding);
+        assert_eq!(payload, OPUS);
+    }
+
+    #[test]
+    fn invalid_padding_is_rejected() {
+        assert_eq!(strip_padding(&[1, 2, 3, 2]), Some(2));
+        assert_eq!(strip_padding(&[1, 2, 3, 0]), None);
+        assert_eq!(strip_padding(&[1, 2, 9]), None);
+        assert_eq!(strip_padding(&[]), None);
+    }
+
+    #[test]
+    fn webvtt_has_a_cue_per_utterance() {
+        let mut stats = RecordStats::default();
+        stats
+            .utterances
+            .insert(1, vec![(0.5, 2.0), (3661.25, 3662.0)]);
+        stats.utterances.insert(2, vec![(1.0, 1.5)]);
+        let mut users = HashMap::new();
+        users.insert(1, "1234".to_string());
+        assert_eq!(
+            stats.webvtt(&users),
+            "WEBVTT\n\
+             \n00:00:00.500 --> 00:00:02.000\n<v 1234>\n\
+             \n00:00:01.000 --> 00:00:01.500\n<v 2>\n\
+             \n01:01:01.250 --> 01:01:02.000\n<v 1234>\n"
+        );
+    }
+
+    #[test]
+    fn timestamp_gap_spans_the_wrap() {
+        assert_eq!(timestamp_elapsed(u32::MAX - 479, 480), Some(0.02));
+        assert_eq!(timestamp_elapsed(u32::MAX, 0), Some(1.0 / 48000.0));
+        assert_eq!(timestamp_elapsed(0, 960), Some(0.02));
+        assert_eq!(timestamp_elapsed(480, u32::MAX - 479), None);
+        assert_eq!(timestamp_elapsed(960, 0), None);
+    }
+
+    #[test]
+    fn stream_header_matches_hound_but_for_the_sizes() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut buffer = vec![];
+        WavWriter::new(Cursor::new(&mut buffer), spec)
+            .unwrap()
+            .finalize()
+            .unwrap();
+        let header = wav_stream_header(2, 48000);
+        assert_eq!(header.len(), buffer.len());
+        assert_eq!(header[4..8], WAV_UNKNOWN_SIZE.to_le_bytes());
+        assert_eq!(header[40..44], WAV_UNKNOWN_SIZE.to_le_bytes());
+        assert_eq!(header[8..40], buffer[8..40]);
+    }
+
+    #[test]
+    fn level_is_rms_and_peak_of_the_samples() {
+        assert_eq!(Level::of(&[]), Level::default());
+        let level = Level::of(&[0.5, -0.5, 0.5, -1.0]);
+        assert!((level.rms - 0.661_437_8).abs() < 1e-6);
+        assert_eq!(level.peak, 1.0);
+    }
+
+    #[test]
+    fn reordered_packets_are_played_in_sequence() {
+        let mut queue = queue_of(vec![
+            packet(1, 0.0),
+            packet(3, 0.02),
+            packet(2, 0.03),
+            packet(4, 0.06),
+        ]);
+        assert_eq!(
+            played(&mut queue, None),
+            vec![Some(1), Some(2), Some(3), Some(4)]
+        );
+    }
+
+    #[test]
+    fn late_and_duplicate_packets_are_skipped() {
+        let mut queue = queue_of(vec![
+            packet(65535, 0.0),
+            packet(0, 0.02),
+            packet(0, 0.03),
+            packet(1, 0.04),
+            packet(65535, 0.05),
+            packet(2, 0.06),
+        ]);
+        assert_eq!(
+            played(&mut queue, None),
+            vec![Some(65535), Some(0), Some(1), Some(2)]
+        );
+    }
+
+    #[test]
+    fn packets_held_too_long_are_lost() {
+        let mut queue = queue_of(vec![packet(1, 0.0), packet(3, 0.02), packet(2, 0.5)]);
+        assert_eq!(played(&mut queue, Some(0.1)), vec![Some(1), None, Some(3)]);
+    }
+
+    #[test]
+    fn only_short_gaps_are_concealed() {
+        let last = 1 + MAX_LOST_PACKETS as u16;
+        let mut queue = queue_of(vec![packet(0, 0.0), packet(last, 1.0)]);
+        let mut expected = vec![Some(0)];
+        expected.extend(vec![None; MAX_LOST_PACKETS]);
+        expected.push(Some(last));
+        assert_eq!(played(&mut queue, None), expected);
+
+        let mut queue = queue_of(vec![packet(0, 0.0), packet(last + 1, 1.0)]);
+        assert_eq!(played(&mut queue, None), vec![Some(0), Some(last + 1)]);
+    }
+
+    #[test]
+    fn loss_modes_fill_a_lost_packet_differently() {
+        let mut packets = sine_packets(10);
+        packets.retain(|packet| u16::from(packet.3) != 5);
+
+        let gateway = VoiceGateway::detached(&session()).unwrap();
+        let mut lost = vec![];
+        for &loss_mode in &[LossMode::Plc, LossMode::Silence, LossMode::Fec] {
+            let options = RecordOptions {
+                loss_mode,
+                ..Default::default()
+            };
+            let mut decoder = AudioDecoder::from_gateway(&gateway, &options).unwrap();
+            let (_, pcm, stats, _) = decoder.decode_packets(&mut queue_of(packets.clone()), &[]);
+            assert_eq!(stats.concealed, 960);
+            assert_eq!(pcm.len(), 10 * 960 * 2);
+            lost.push(pcm[5 * 960 * 2..6 * 960 * 2].to_vec());
+        }
+        let (plc, silence, fec) = (&lost[0], &lost[1], &lost[2]);
+        assert!(silence.iter().all(|&sample| sample == 0.0));
+        assert!(plc.iter().any(|&sample| sample != 0.0));
+        assert!(fec.iter().any(|&sample| sample != 0.0));
+        assert_ne!(plc, fec);
+    }
+
+    fn received(queue: &SsrcPacketQueue, ssrc: u32) -> Vec<f64> {
+        queue.queue[&ssrc].iter().map(|packet| packet.4).collect()
+    }
+
+    #[test]
+    fn windowed_push_drops_old_packets_of_the_same_ssrc() {
+        let mut queue = SsrcPacketQueue::new();
+        queue.set_window(Some(1.0));
+        queue.push(2, packet(0, 0.0));
+        for (seq, &time) in [0.0, 0.5, 1.0, 1.5, 2.2].iter().enumerate() {
+            queue.push(1, packet(seq as u16, time));
+        }
+        assert_eq!(received(&queue, 1), vec![1.5, 2.2]);
+        // Only a push prunes a stream.
+        assert_eq!(received(&queue, 2), vec![0.0]);
+
+        queue.set_window(None);
+        queue.push(1, packet(5, 10.0));
+        assert_eq!(received(&queue, 1), vec![1.5, 2.2, 10.0]);
+    }
+
+    #[test]
+    fn snapshot_keeps_the_last_seconds() {
+        let now = now();
+        let mut queue = SsrcPacketQueue::new();
+        for (seq, &ago) in [3.0, 2.0, 1.5, 0.5, 0.0].iter().enumerate() {
+            queue.push(1, packet(seq as u16, now - ago));
+        }
+        queue.push(2, packet(0, now - 5.0));
+
+        let snapshot = queue.snapshot(Some(1.0));
+        assert_eq!(received(&snapshot, 1), vec![now - 0.5, now]);
+        assert!(!snapshot.queue.contains_key(&2));
+        assert_eq!(queue.queue[&1].len(), 5);
+
+        let snapshot = queue.snapshot(None);
+        assert_eq!(snapshot.queue[&1].len(), 5);
+        assert_eq!(snapshot.queue[&2].len(), 1);
+    }
+
+    /// A queue with `len` packets for each SSRC.
+    fn queue_with(lengths: &[(u32, usize)]) -> SsrcPacketQueue {
+        let mut queue = SsrcPacketQueue::new();
+        for &(ssrc, len) in lengths {
+            for seq in 0..len {
+                queue.push(ssrc, packet(seq as u16, seq as f64 * 0.02));
+            }
+        }
+        queue
+    }
+
+    #[test]
+    fn busiest_speakers_are_kept() {
+        let mut queue = queue_with(&[(1, 2), (2, 5), (3, 2), (4, 3)]);
+        assert_eq!(queue.keep_busiest(2), vec![1, 3]);
+        assert_eq!(queue.queue[&1].len(), 0);
+        assert_eq!(queue.queue[&2].len(), 5);
+        assert_eq!(queue.queue[&3].len(), 0);
+        assert_eq!(queue.queue[&4].len(), 3);
+
+        // The lower SSRC wins a tie.
+        let mut queue = queue_with(&[(1, 2), (2, 5), (3, 2), (4, 3)]);
+        assert_eq!(queue.keep_busiest(3), vec![3]);
+        assert_eq!(queue.keep_busiest(4), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn merged_parts_skip_only_speakers_skipped_in_every_part() {
+        // The stats of a part decoded with `max_speakers` of 2.
+        let part = |lengths: &[(u32, usize)]| {
+            let mut queue = queue_with(lengths);
+            let mut stats = RecordStats {
+                skipped: queue.keep_busiest(2),
+                ..Default::default()
+            };
+            for (&ssrc, packets) in &queue.queue {
+                let decoded = packets.len() * 960;
+                stats.streams.insert(
+                    ssrc,
+                    DecodeStats {
+                        decoded,
+                        concealed: 0,
+                    },
+                );
+            }
+            stats
+        };
+        let first = part(&[(1, 3), (2, 1), (3, 1), (4, 1)]);
+        assert_eq!(first.skipped, vec![3, 4]);
+        let second = part(&[(1, 1), (2, 1), (3, 4)]);
+        assert_eq!(second.skipped, vec![2]);
+        let third = part(&[(1, 2), (2, 2), (3, 1)]);
+        assert_eq!(third.skipped, vec![3]);
+
+        let mut merged = first.clone();
+        merged.merge(&second);
+        assert_eq!(merged.skipped, vec![4]);
+
+        let mut merged = first;
+        merged.merge(&third);
+        assert_eq!(merged.skipped, vec![3, 4]);
+    }
+
+    #[test]
+    fn open_pause_spans_the_checkpoint() {
+        let mut queue = SsrcPacketQueue::new();
+        queue.pause();
+        let part = queue.take();
+        let (start, end) = part.pauses[0];
+        assert_eq!(part.pauses.len(), 1);
+        assert!(start <= end && end < std::f64::MAX);
+        assert_eq!(queue.pauses, vec![(start, std::f64::MAX)]);
+
+        queue.resume();
+        assert_eq!(queue.pauses.len(), 1);
+        assert_eq!(queue.pauses[0].0, start);
+        assert!(queue.pauses[0].1 >= end && queue.pauses[0].1 < std::f64::MAX);
+    }
+
+    #[test]
+    fn speakers_have_decoded_audio() {
+        let mut queue = SsrcPacketQueue::new();
+        let streams = &mut queue.stats.streams;
+        streams.insert(
+            1,
+            DecodeStats {
+                decoded: 960,
+                concealed: 0,
+            },
+        );
+        // Only lost packets were concealed, the speaker was never heard.
+        streams.insert(
+            2,
+            DecodeStats {
+                decoded: 0,
+                concealed: 960,
+            },
+        );
+        streams.insert(3, DecodeStats::default());
+        assert_eq!(queue.speakers(), vec![1]);
+    }
+
+    #[test]
+    fn long_pauses_are_shortened_with_silence_mode() {
+        let mut packets = sine_packets(2);
+        packets[0].4 = 0.0;
+        packets[1].4 = 3600.0;
+        let gateway = VoiceGateway::detached(&session()).unwrap();
+        let options = RecordOptions {
+            pause_mode: PauseMode::Silence,
+            ..Default::default()
+        };
+        let mut decoder = AudioDecoder::from_gateway(&gateway, &options).unwrap();
+        let (_, pcm, _, _) = decoder.decode_packets(&mut queue_of(packets), &[(1.0, 2.0)]);
+        let silence = (MAX_PAUSE_SILENCE * 48000.0) as usize * 2;
+        assert_eq!(pcm.len(), 2 * 960 * 2 + silence);
+        assert!(pcm[960 * 2..960 * 2 + silence]
+            .iter()
+            .all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn timestamp_gaps_are_filled_with_comfort_noise() {
+        // Half a second passes between the two packets.
+        let mut packets = sine_packets(2);
+        packets[1].2 = 26 * 960;
+        let gateway = VoiceGateway::detached(&session()).unwrap();
+        let gap = |comfort_noise_db| {
+            let options = RecordOptions {
+                comfort_noise_db,
+                ..Default::default()
+            };
+            let mut decoder = AudioDecoder::from_gateway(&gateway, &options).unwrap();
+            let (_, pcm, _, _) = decoder.decode_packets(&mut queue_of(packets.clone()), &[]);
+            assert!(pcm.len() > 4 * 960 * 2);
+            pcm[960 * 2..pcm.len() - 960 * 2].to_vec()
+        };
+
+        let noise = gap(Some(-40.0));
+        assert!(noise.iter().all(|&sample| sample != 0.0));
+        let level = Level::of(&noise);
+        assert!((level.rms - 0.01).abs() < 0.001, "{:?}", level);
+        assert!(level.peak <= 0.01 * 3f32.sqrt());
+
+        assert!(gap(None).iter().all(|&sample| sample == 0.0));
+    }
 }
@@ -0,0 +1,129 @@
+use rubato::{InterpolationParameters, InterpolationType, Resampler, SincFixedIn, WindowFunction};
+
+use crate::error::{DiscordError, Result};
+
+/// Length of the sinc filter in input samples. Longer filters have a
+/// sharper cutoff at the cost of more work per sample.
+const SINC_LEN: usize = 256;
+/// Frames fed to the resampler at a time.
+const CHUNK_SIZE: usize = 1024;
+
+/// Lowest and highest output rates accepted by `RecordOptions`.
+pub(crate) const MIN_OUTPUT_RATE: u32 = 8000;
+pub(crate) const MAX_OUTPUT_RATE: u32 = 192_000;
+
+/// Converts interleaved `pcm` from `from` Hz to `to` Hz with band-limited
+/// sinc interpolation. When downsampling, everything above the new Nyquist
+/// frequency is filtered out instead of folding back into the audible band.
+/// The output is aligned with the input and has the length of the input
+/// scaled by the ratio of the rates.
+pub(crate) fn resample(pcm: &[f32], channels: usize, from: u32, to: u32) -> Result<Vec<f32>> {
+    if from == to || pcm.is_empty() {
+        return Ok(pcm.to_vec());
+    }
+    let ratio = to as f64 / from as f64;
+    let parameters = InterpolationParameters {
+        sinc_len: SINC_LEN,
+        f_cutoff: 0.95,
+        interpolation: InterpolationType::Linear,
+        oversampling_factor: 256,
+        window: WindowFunction::BlackmanHarris2,
+    };
+    let mut resampler = SincFixedIn::<f32>::new(ratio, parameters, CHUNK_SIZE, channels);
+
+    let frames = pcm.len() / channels;
+    let expected = (frames as f64 * ratio).round() as usize;
+    // The filter delays the output by half its length.
+    let delay = (SINC_LEN as f64 / 2.0 * ratio).round() as usize;
+    let mut waves = vec![Vec::with_capacity(expected + delay + CHUNK_SIZE); channels];
+    let mut chunk = vec![vec![0f32; CHUNK_SIZE]; channels];
+    let mut position = 0;
+    while waves[0].len() < expected + delay {
+        for (c, wave) in chunk.iter_mut().enumerate() {
+            for (i, sample) in wave.iter_mut().enumerate() {
+                let frame = position + i;
+                *sample = if frame < frames {
+                    pcm[frame * channels + c]
+                } else {
+                    0.0
+                };
+            }
+        }
+        position += CHUNK_SIZE;
+        let resampled = resampler
+            .process(&chunk)
+            .map_err(|e| DiscordError::ResampleFailed(e.to_string()))?;
+        for (wave, resampled) in waves.iter_mut().zip(resampled) {
+            wave.extend(resampled);
+        }
+    }
+
+    let mut output = Vec::with_capacity(expected * channels);
+    for frame in delay..delay + expected {
+        for wave in &waves {
+            output.push(wave[frame]);
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Power of `pcm` at `frequency`, normalized so that a full scale sine
+    /// has a power of about 1.
+    fn power_at(pcm: &[f32], rate: u32, frequency: f64) -> f64 {
+        let omega = 2.0 * std::f64::consts::PI * frequency / rate as f64;
+        let coeff = 2.0 * omega.cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for &sample in pcm {
+            let s0 = sample as f64 + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+        let power = s1 * s1 + s2 * s2 - coeff * s1 * s2;
+        power * 4.0 / (pcm.len() as f64).powi(2)
+    }
+
+    fn tones(rate: u32, seconds: usize, frequencies: &[f64]) -> Vec<f32> {
+        (0..rate as usize * seconds)
+            .map(|n| {
+                let t = n as f64 / rate as f64;
+                frequencies
+                    .iter()
+                    .map(|f| 0.4 * (2.0 * std::f64::consts::PI * f * t).sin())
+                    .sum::<f64>() as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn downsampling_does_not_alias() {
+        // 12kHz is above the Nyquist frequency of 16kHz and would fold back
+        // to 4kHz if the signal was just decimated.
+        let input = tones(48000, 1, &[1000.0, 12000.0]);
+        let output = resample(&input, 1, 48000, 16000).unwrap();
+        assert_eq!(output.len(), 16000);
+
+        // Skip the edges, where the filter sees the zero padding.
+        let steady = &output[1600..14400];
+        let passband = power_at(steady, 16000, 1000.0);
+        let alias = power_at(steady, 16000, 4000.0);
+        assert!(
+            (passband - 0.16).abs() < 0.02,
+            "passband power changed: {}",
+            passband
+        );
+        assert!(alias < passband * 1e-4, "aliased power: {}", alias);
+    }
+
+    #[test]
+    fn output_length_follows_the_ratio() {
+        let input = tones(48000, 1, &[440.0]);
+        let stereo = input.iter().flat_map(|&s| vec![s, s]).collect::<Vec<_>>();
+        let output = resample(&stereo, 2, 48000, 44100).unwrap();
+        assert_eq!(output.len(), 44100 * 2);
+        assert_eq!(resample(&stereo, 2, 48000, 48000).unwrap(), stereo);
+    }
+}
@@ -1,4 +1,29 @@
 use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of player threads running, across every connection.
+pub(crate) static ACTIVE_PLAYERS: AtomicUsize = AtomicUsize::new(0);
+/// Number of recorder threads running, across every connection.
+pub(crate) static ACTIVE_RECORDERS: AtomicUsize = AtomicUsize::new(0);
+/// Largest number of players allowed to run at once, or 0 for no limit.
+pub(crate) static MAX_PLAYERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a running thread in one of the counters above until it is dropped,
+/// so that the thread is uncounted even if it panics.
+pub(crate) struct ActiveGuard(&'static AtomicUsize);
+
+impl ActiveGuard {
+    pub(crate) fn new(counter: &'static AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::AcqRel);
+        ActiveGuard(counter)
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
 
 #[derive(Debug)]
 pub struct State(Mutex<ConnectionState>, Condvar);
@@ -29,6 +54,22 @@ impl State {
         *state == condition
     }
 
+    pub fn get_state(&self) -> ConnectionState {
+        *self.0.lock()
+    }
+
+    /// Sets the state to `new` if it is one of `current`, under the same lock
+    /// as the check. Returns whether it was set.
+    pub fn compare_and_set(&self, current: &[ConnectionState], new: ConnectionState) -> bool {
+        let mut state = self.0.lock();
+        if !current.contains(&*state) {
+            return false;
+        }
+        *state = new;
+        self.1.notify_all();
+        true
+    }
+
     pub fn set_state(&self, condition: ConnectionState) {
         let mut state = self.0.lock();
         *state = condition;
@@ -42,7 +83,23 @@ pub enum ConnectionState {
     Connected,
     Playing,
     Recording,
+    RecordPaused,
     RecordFinished,
     Paused,
     Finished,
 }
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Playing => "playing",
+            ConnectionState::Recording => "recording",
+            ConnectionState::RecordPaused => "record_paused",
+            ConnectionState::RecordFinished => "record_finished",
+            ConnectionState::Paused => "paused",
+            ConnectionState::Finished => "finished",
+        }
+    }
+}
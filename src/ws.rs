@@ -1,32 +1,295 @@
 use crate::{
-    error::{DiscordError, Result},
+    error::{DiscordError, Result, SESSION_INVALID_CODES},
     payload::*,
+    proxy::Proxy,
     state::{ConnectionState, State},
 };
-use rustls::{ClientConfig, ClientSession, StreamOwned};
+use parking_lot::Mutex;
+use rustls::{ClientConfig, ClientSession, Session, StreamOwned};
 use std::{
     borrow::Cow,
-    collections::VecDeque,
-    io,
-    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
-    sync::Arc,
-    time,
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread, time,
 };
 use tungstenite::{
     client::client as create_gateway,
+    handshake::HandshakeError,
     protocol::{frame::coding::CloseCode, CloseFrame},
     Message, WebSocket,
 };
 
+/// Default port of the voice gateway.
+pub(crate) const GATEWAY_PORT: u16 = 443;
+
+/// The host of the `endpoint` of a VOICE_SERVER_UPDATE, which may carry a
+/// `wss://` scheme, a trailing slash and a port. The port is dropped: Discord
+/// has sent ports such as `:80` that do not serve TLS, while the gateway is
+/// always reached on `GATEWAY_PORT`.
+pub(crate) fn gateway_host(endpoint: &str) -> Result<String> {
+    let invalid = || DiscordError::InvalidArgument(format!("Invalid endpoint: {:?}", endpoint));
+    let address = endpoint.trim();
+    let address = address.strip_prefix("wss://").unwrap_or(address);
+    let address = address.trim_end_matches('/');
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) => {
+            port.parse::<u16>().map_err(|_| invalid())?;
+            host
+        }
+        None => address,
+    };
+    let valid = !host.is_empty()
+        && !host.starts_with('.')
+        && !host.ends_with('.')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if valid {
+        Ok(host.to_string())
+    } else {
+        Err(invalid())
+    }
+}
+
+/// How long `poll` waits on a detached gateway, which has nothing to poll.
+const DETACHED_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+/// Source of `VoiceGateway::id`.
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// How often `ConnectCancel::sleep` checks whether it was cancelled.
+const CANCEL_POLL_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+/// How long each IP discovery attempt waits for the response.
+const DISCOVERY_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// Default of `VoiceGateway::send_timeout`, well within a frame so that a
+/// stalled send costs the player a dropped frame rather than its pacing.
+pub(crate) const DEFAULT_SEND_TIMEOUT: time::Duration = time::Duration::from_millis(5);
+
+/// Largest DSCP code point, which takes the upper 6 bits of the IP TOS byte.
+pub(crate) const MAX_DSCP: u8 = 63;
+
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos"
+)))]
+fn set_dscp(socket: &UdpSocket, dscp: u8) {
+    let tos = u32::from(dscp) << 2;
+    if let Err(e) = socket2::SockRef::from(socket).set_tos(tos) {
+        warn!("Failed to set DSCP {} on the voice socket: {}", dscp, e);
+    }
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos"
+))]
+fn set_dscp(_socket: &UdpSocket, dscp: u8) {
+    warn!("DSCP {} is not supported on this platform", dscp);
+}
+
+/// Time left until `deadline`, or `ConnectTimeout` once it has passed.
+pub(crate) fn remaining(deadline: Option<time::Instant>) -> Result<Option<time::Duration>> {
+    match deadline {
+        Some(deadline) => {
+            let now = time::Instant::now();
+            if now >= deadline {
+                Err(DiscordError::ConnectTimeout)
+            } else {
+                Ok(Some(deadline - now))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Fails once the connection attempt was cancelled or `deadline` has passed.
+fn check_abort(deadline: Option<time::Instant>, cancel: Option<&ConnectCancel>) -> Result<()> {
+    if cancel.map_or(false, ConnectCancel::is_cancelled) {
+        return Err(DiscordError::ConnectCancelled);
+    }
+    remaining(deadline).map(drop)
+}
+
+/// Reports any error that happens after the attempt was cancelled or after
+/// `deadline` as such, since blocking reads and writes fail with whatever the
+/// OS returns when the socket is shut down or the timeout expires.
+fn or_abort<T>(
+    result: Result<T>,
+    deadline: Option<time::Instant>,
+    cancel: Option<&ConnectCancel>,
+) -> Result<T> {
+    result.map_err(|e| match check_abort(deadline, cancel) {
+        Err(abort) => abort,
+        Ok(()) => e,
+    })
+}
+
+/// Lets another thread abort a connection attempt. Cancelling shuts down the
+/// socket being connected so that blocking reads return immediately.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConnectCancel(Arc<CancelState>);
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl ConnectCancel {
+    pub(crate) fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Release);
+        if let Some(stream) = self.0.stream.lock().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Keeps a handle to `stream` so that `cancel` can shut it down.
+    fn watch(&self, stream: &TcpStream) -> Result<()> {
+        let mut slot = self.0.stream.lock();
+        // Checked under the lock so that a concurrent `cancel` either sees
+        // the stream or is seen here.
+        check_abort(None, Some(self))?;
+        *slot = Some(stream.try_clone()?);
+        Ok(())
+    }
+
+    /// Waits for `duration`, failing with `ConnectCancelled` as soon as the
+    /// attempt is cancelled.
+    pub(crate) fn sleep(&self, duration: time::Duration) -> Result<()> {
+        let until = time::Instant::now() + duration;
+        loop {
+            check_abort(None, Some(self))?;
+            let now = time::Instant::now();
+            if now >= until {
+                return Ok(());
+            }
+            thread::sleep((until - now).min(CANCEL_POLL_INTERVAL));
+        }
+    }
+
+    /// Drops the handle once the connection is established, so that a late
+    /// `cancel` cannot shut down a working connection.
+    fn release(&self) {
+        self.0.stream.lock().take();
+    }
+}
+
+/// Connects to the first reachable address of `addr`, giving up at `deadline`.
+/// The read and write timeouts of the stream are set to the remaining time so
+/// that the handshakes that follow are bounded too.
+pub(crate) fn connect_tcp<A: ToSocketAddrs>(
+    name: &str,
+    addr: A,
+    deadline: Option<time::Instant>,
+) -> Result<TcpStream> {
+    let addrs = addr
+        .to_socket_addrs()
+        .map_err(|e| DiscordError::DnsResolutionFailed(name.to_string(), e))?;
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect");
+    for addr in addrs {
+        let connected = match remaining(deadline)? {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match connected {
+            Ok(stream) => {
+                if let Some(timeout) = remaining(deadline)? {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                }
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(DiscordError::TcpConnectFailed(name.to_string(), last_error))
+}
+
+/// Transport of the gateway websocket.
+pub(crate) enum GatewayStream {
+    Tls(Box<StreamOwned<ClientSession, TcpStream>>),
+    Plain(TcpStream),
+}
+
+impl GatewayStream {
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            GatewayStream::Tls(stream) => stream.get_ref(),
+            GatewayStream::Plain(stream) => stream,
+        }
+    }
+}
+
+impl Read for GatewayStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            GatewayStream::Tls(stream) => stream.read(buf),
+            GatewayStream::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for GatewayStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            GatewayStream::Tls(stream) => stream.write(buf),
+            GatewayStream::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            GatewayStream::Tls(stream) => stream.flush(),
+            GatewayStream::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Everything needed to resume a voice session, e.g. after a process restart.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionState {
+    pub endpoint: String,
+    pub server_id: String,
+    pub user_id: String,
+    pub session_id: String,
+    pub token: String,
+    pub ssrc: u32,
+    pub endpoint_ip: String,
+    pub port: u16,
+    pub encryption: EncryptionMode,
+    pub secret_key: [u8; 32],
+}
+
 pub(crate) struct VoiceGateway {
+    /// Identifies the connection in the logs. Kept across reconnects.
+    pub id: usize,
     pub endpoint: String,
     user_id: String,
     server_id: String,
     pub session_id: String,
     pub token: String,
-    ws: WebSocket<StreamOwned<ClientSession, TcpStream>>,
+    /// `None` for a gateway made with `detached`, which only has the media
+    /// session.
+    ws: Option<WebSocket<GatewayStream>>,
     heartbeat_interval: u64,
+    next_heartbeat: Option<time::Instant>,
     pub last_heartbeat: time::Instant,
+    pub last_ack: Option<time::Instant>,
     pub ssrc: u32,
     pub port: u16,
     pub encryption: EncryptionMode,
@@ -34,16 +297,97 @@ pub(crate) struct VoiceGateway {
     socket: Option<UdpSocket>,
     pub recent_acks: VecDeque<f64>,
     pub secret_key: [u8; 32],
+    pub ssrc_map: HashMap<u32, String>,
+    speaking: (SpeakingType, u32),
     pub state: Arc<State>,
-    close_code: u16,
+    pub close_code: u16,
+    resumed: bool,
+    deadline: Option<time::Instant>,
+    cancel: Option<ConnectCancel>,
+    /// Bumped by `reconnect`, so that the player and the recorder know to
+    /// pick up the new UDP socket and key. Shared across reconnects like
+    /// `state`.
+    pub generation: Arc<AtomicUsize>,
+    /// The builder this gateway was connected with, reused by `reconnect`.
+    origin: VoiceGatewayBuilder,
+    /// Round trip time in seconds above which a heartbeat ack is reported
+    /// by `take_latency_spike`.
+    pub latency_threshold: Option<f64>,
+    latency_spike: Option<f64>,
+    /// How long sending a voice packet may block before it is dropped.
+    send_timeout: time::Duration,
 }
 
 impl VoiceGateway {
+    /// A gateway that has not negotiated anything yet.
+    fn new(ws: Option<WebSocket<GatewayStream>>, origin: VoiceGatewayBuilder) -> Self {
+        VoiceGateway {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            endpoint: String::new(),
+            user_id: String::new(),
+            server_id: String::new(),
+            session_id: String::new(),
+            token: String::new(),
+            ws,
+            heartbeat_interval: std::u64::MAX,
+            next_heartbeat: None,
+            last_heartbeat: time::Instant::now(),
+            last_ack: None,
+            ssrc: 0,
+            port: 0,
+            encryption: EncryptionMode::default(),
+            endpoint_ip: String::new(),
+            socket: None,
+            recent_acks: VecDeque::with_capacity(20),
+            secret_key: [0; 32],
+            ssrc_map: HashMap::new(),
+            speaking: (SpeakingType::empty(), 0),
+            state: Arc::new(State::default()),
+            close_code: 0,
+            resumed: false,
+            deadline: None,
+            cancel: origin.cancel.clone(),
+            generation: Arc::new(AtomicUsize::new(0)),
+            origin,
+            latency_threshold: None,
+            latency_spike: None,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+        }
+    }
+
+    /// A gateway for a media session negotiated elsewhere, e.g. by another
+    /// voice library. Only the UDP socket is set up: speaking updates are
+    /// left to whoever owns the websocket and the gateway cannot reconnect.
+    pub fn detached(session: &SessionState) -> Result<Self> {
+        let mut gateway = VoiceGateway::new(None, VoiceGatewayBuilder::default());
+        gateway.restore_session(session)?;
+        gateway.state.set_state(ConnectionState::Connected);
+        Ok(gateway)
+    }
+
+    fn ws(&mut self) -> Result<&mut WebSocket<GatewayStream>> {
+        match &mut self.ws {
+            Some(ws) => Ok(ws),
+            None => Err(DiscordError::from(io::Error::new(
+                io::ErrorKind::Other,
+                "No gateway connection",
+            ))),
+        }
+    }
+
     pub(crate) fn poll(&mut self) -> Result<()> {
-        if self.last_heartbeat.elapsed().as_millis() as u64 >= self.heartbeat_interval {
+        if self.ws.is_none() {
+            // Nothing to receive; keep `run` from spinning.
+            std::thread::sleep(DETACHED_POLL_INTERVAL);
+            return Ok(());
+        }
+        if self
+            .next_heartbeat
+            .map_or(false, |next| time::Instant::now() >= next)
+        {
             self.handle_heartbeat()?;
         }
-        let msg = match self.ws.read_message() {
+        let msg = match self.ws()?.read_message() {
             Ok(msg) => msg,
             Err(tungstenite::Error::Io(inner)) => {
                 use std::io::ErrorKind;
@@ -65,9 +409,16 @@ impl VoiceGateway {
                         info!("Payload received: {:?}", hello);
                         let interval = hello.heartbeat_interval as u64;
                         self.heartbeat_interval = interval;
-                        let socket = self.ws.get_ref().get_ref();
+                        let socket = self.ws()?.get_ref().tcp();
                         socket.set_read_timeout(Some(time::Duration::from_millis(1000)))?;
                         self.last_heartbeat = time::Instant::now();
+                        // Jitter the first heartbeat so that connections
+                        // opened together do not heartbeat in lockstep.
+                        let jitter = rand::random::<f64>();
+                        self.next_heartbeat = Some(
+                            self.last_heartbeat
+                                + time::Duration::from_millis((interval as f64 * jitter) as u64),
+                        );
                     }
                     OpCode::Ready(ready) => {
                         info!("Payload received: {:?}", ready);
@@ -84,11 +435,22 @@ impl VoiceGateway {
                         info!("Payload received: {:?}", sd);
                         self.handle_session_description(sd)?;
                     }
+                    OpCode::Resumed(_) => {
+                        info!("[conn {}] Session resumed", self.id);
+                        self.resumed = true;
+                        self.state.set_state(ConnectionState::Connected);
+                    }
+                    OpCode::Speaking(speaking) => {
+                        debug!("Payload received: {:?}", speaking);
+                        if let (Some(ssrc), Some(user_id)) = (speaking.ssrc, speaking.user_id) {
+                            self.ssrc_map.insert(ssrc, user_id);
+                        }
+                    }
                     _ => {}
                 }
             }
             Message::Close(msg) => {
-                info!("Close message received: {:?}", &msg);
+                info!("[conn {}] Close message received: {:?}", self.id, &msg);
                 if let Some(frame) = msg {
                     self.close_code = u16::from(frame.code);
                 }
@@ -111,6 +473,24 @@ impl VoiceGateway {
             .unwrap_or(std::f64::NAN)
     }
 
+    /// Sends a heartbeat now instead of at the next interval, which then
+    /// counts from this one. Its round trip time is in `latency` once the
+    /// ack arrives.
+    pub fn send_heartbeat(&mut self) -> Result<()> {
+        // Without the interval from HELLO, the next automatic heartbeat
+        // could not be scheduled.
+        if self.heartbeat_interval == std::u64::MAX {
+            return Err(DiscordError::ConnectionNotReady);
+        }
+        self.handle_heartbeat()
+    }
+
+    /// The round trip time of the last heartbeat that exceeded
+    /// `latency_threshold`, if it has not been taken yet.
+    pub fn take_latency_spike(&mut self) -> Option<f64> {
+        self.latency_spike.take()
+    }
+
     pub fn average_latency(&self) -> f64 {
         if self.recent_acks.is_empty() {
             std::f64::NAN
@@ -119,32 +499,201 @@ impl VoiceGateway {
         }
     }
 
-    pub fn connection_flow(&mut self, resume: bool) -> Result<()> {
-        self.poll()?; // Hello
-        if resume {
+    pub fn connection_flow(&mut self, resume: bool, progress: &dyn Fn(&str)) -> Result<()> {
+        let deadline = self.deadline.take();
+        let cancel = self.cancel.take();
+        let result = self
+            .handshake(resume, deadline, cancel.as_ref(), progress)
+            .and_then(|()| {
+                // `connect_tcp` bounded writes by the deadline, which only
+                // applies to connecting. Later writes block as usual.
+                if deadline.is_some() {
+                    self.ws()?.get_ref().tcp().set_write_timeout(None)?;
+                }
+                Ok(())
+            });
+        if let Some(cancel) = &cancel {
+            cancel.release();
+        }
+        or_abort(result, deadline, cancel.as_ref())
+    }
+
+    fn handshake(
+        &mut self,
+        resume: bool,
+        deadline: Option<time::Instant>,
+        cancel: Option<&ConnectCancel>,
+        progress: &dyn Fn(&str),
+    ) -> Result<()> {
+        while self.heartbeat_interval == std::u64::MAX {
+            check_abort(deadline, cancel)?;
+            self.poll()?; // Hello
+        }
+        progress("hello");
+        // Resuming needs the UDP session of the previous connection, which is
+        // either still open or restored with `restore_session`.
+        if resume && self.can_resume() && self.has_socket() {
             self.resume()?;
-        } else {
-            self.identify()?;
+            while !self.resumed {
+                check_abort(deadline, cancel)?;
+                self.poll()?;
+            }
+            progress("resumed");
+            return Ok(());
         }
-        while self.secret_key.iter().all(|&b| b == 0) {
+        self.identify()?;
+        let mut ready = false;
+        while !self.has_secret_key() {
+            check_abort(deadline, cancel)?;
             self.poll()?;
+            if !ready && self.socket.is_some() {
+                ready = true;
+                progress("ready");
+            }
         }
+        progress("session_description");
         Ok(())
     }
 
     pub fn close(&mut self, code: u16) -> Result<()> {
         self.state.set_state(ConnectionState::Disconnected);
         self.close_code = code;
-        self.ws.close(Some(CloseFrame {
-            code: CloseCode::from(code),
-            reason: Cow::Owned(String::from("Closing Connection")),
-        }))?;
+        if let Some(ws) = &mut self.ws {
+            ws.close(Some(CloseFrame {
+                code: CloseCode::from(code),
+                reason: Cow::Owned(String::from("Closing Connection")),
+            }))?;
+        }
+        Ok(())
+    }
+
+    pub fn session(&self) -> SessionState {
+        SessionState {
+            endpoint: self.endpoint.clone(),
+            server_id: self.server_id.clone(),
+            user_id: self.user_id.clone(),
+            session_id: self.session_id.clone(),
+            token: self.token.clone(),
+            ssrc: self.ssrc,
+            endpoint_ip: self.endpoint_ip.clone(),
+            port: self.port,
+            encryption: self.encryption,
+            secret_key: self.secret_key,
+        }
+    }
+
+    /// Restores the UDP session of `session` so that `connection_flow` can
+    /// resume it instead of identifying again.
+    pub fn restore_session(&mut self, session: &SessionState) -> Result<()> {
+        self.ssrc = session.ssrc;
+        self.endpoint_ip = session.endpoint_ip.clone();
+        self.port = session.port;
+        self.encryption = session.encryption;
+        self.secret_key = session.secret_key;
+        let addr = SocketAddr::new(IpAddr::V4(self.endpoint_ip.as_str().parse()?), self.port);
+        self.socket = Some(self.bind_socket(&addr)?);
+        Ok(())
+    }
+
+    /// Replaces the connection with a new one to the same endpoint, resuming
+    /// the session if Discord allows it and identifying again otherwise.
+    /// The current connection is only closed once the new one is ready, so
+    /// nothing changes if this fails.
+    pub fn reconnect(&mut self, timeout: Option<time::Duration>) -> Result<()> {
+        self.ws()?;
+        let session = self.session();
+        let mut builder = self.origin.clone();
+        builder.cancel = None;
+        builder.timeout(timeout);
+
+        let mut next = builder.connect(&|_| {})?;
+        next.restore_session(&session)?;
+        match next.connection_flow(true, &|_| {}) {
+            Ok(()) => {}
+            Err(DiscordError::ConnectionClosed(code)) if SESSION_INVALID_CODES.contains(&code) => {
+                info!(
+                    "[conn {}] Session can not be resumed ({}); identifying again",
+                    self.id, code
+                );
+                next = builder.connect(&|_| {})?;
+                next.connection_flow(false, &|_| {})?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        let mut previous = std::mem::replace(self, next);
+        if let Some(ws) = &mut previous.ws {
+            let _ = ws.close(Some(CloseFrame {
+                code: CloseCode::from(4000),
+                reason: Cow::Owned(String::from("Reconnecting")),
+            }));
+        }
+        // The player and the recorder hold the previous state, which the
+        // handshake of the new connection must not overwrite.
+        self.state = previous.state;
+        self.speaking = previous.speaking;
+        for (ssrc, user_id) in previous.ssrc_map {
+            self.ssrc_map.entry(ssrc).or_insert(user_id);
+        }
+        self.id = previous.id;
+        self.latency_threshold = previous.latency_threshold;
+        self.send_timeout = previous.send_timeout;
+        self.generation = previous.generation;
+        self.generation.fetch_add(1, Ordering::AcqRel);
         Ok(())
     }
 
+    /// A UDP socket connected to the voice server at `addr`, with the DSCP
+    /// of the builder. A platform that cannot mark packets sends them
+    /// unmarked.
+    fn bind_socket(&self, addr: &SocketAddr) -> Result<UdpSocket> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        if let Some(dscp) = self.origin.dscp {
+            set_dscp(&socket, dscp);
+        }
+        Ok(socket)
+    }
+
+    /// Sets how long sending a voice packet may block. The clones of the
+    /// socket share its options, so a running player uses it immediately.
+    pub fn set_send_timeout(&mut self, timeout: time::Duration) -> Result<()> {
+        if let Some(socket) = &self.socket {
+            socket.set_write_timeout(Some(timeout))?;
+        }
+        self.send_timeout = timeout;
+        Ok(())
+    }
+
+    /// Whether the session can be resumed after the last close.
+    pub fn can_resume(&self) -> bool {
+        !SESSION_INVALID_CODES.contains(&self.close_code)
+    }
+
+    pub fn has_socket(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    pub fn has_secret_key(&self) -> bool {
+        self.secret_key.iter().any(|&b| b != 0)
+    }
+
+    /// Whether voice can be sent and received: the handshake has completed
+    /// and the connection has not been closed since.
+    pub fn is_ready(&self) -> bool {
+        self.has_socket()
+            && self.has_secret_key()
+            && !self.state.is_state(ConnectionState::Disconnected)
+    }
+
+    /// A handle to the UDP socket, with `send_timeout` applied so that the
+    /// player never blocks on a send for longer.
     pub fn clone_socket(&self) -> Result<UdpSocket> {
         match &self.socket {
-            Some(ref socket) => Ok(socket.try_clone()?),
+            Some(ref socket) => {
+                socket.set_write_timeout(Some(self.send_timeout))?;
+                Ok(socket.try_clone()?)
+            }
             None => Err(DiscordError::from(io::Error::new(
                 io::ErrorKind::Other,
                 "No socket found",
@@ -155,16 +704,15 @@ impl VoiceGateway {
     fn handle_ready(&mut self, ready: Ready) -> Result<()> {
         self.ssrc = ready.ssrc;
         self.port = ready.port;
-        self.encryption = ready
-            .get_encryption_mode()
-            .first()
-            .copied()
-            .unwrap_or_default();
+        self.encryption = match ready.get_encryption_mode().first() {
+            Some(&mode) => mode,
+            None => return Err(DiscordError::NoSupportedEncryptionMode(ready.modes)),
+        };
         self.endpoint_ip = ready.ip;
         let addr = SocketAddr::new(IpAddr::V4(self.endpoint_ip.as_str().parse()?), self.port);
         info!("UDP Addr Found: {:?}", &addr);
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.connect(&addr)?;
+        let socket = self.bind_socket(&addr)?;
+        socket.set_read_timeout(Some(DISCOVERY_TIMEOUT))?;
         self.socket = Some(socket);
         let mut retry = 0;
         let (ip, port) = loop {
@@ -172,15 +720,26 @@ impl VoiceGateway {
             match (result, retry) {
                 (Ok(data), _) => break data,
                 (Err(e), 4) => return Err(e),
-                _ => retry += 1,
+                (Err(e), _) => {
+                    warn!(
+                        "[conn {}] UDP discovery attempt {} failed: {}",
+                        self.id,
+                        retry + 1,
+                        e
+                    );
+                    retry += 1;
+                }
             }
         };
+        if let Some(socket) = &self.socket {
+            socket.set_read_timeout(None)?;
+        }
 
-        info!("UDP Discovery Found {}:{}", &ip, &port);
+        info!("[conn {}] UDP Discovery Found {}:{}", self.id, &ip, &port);
 
         let packet = OpCode::select_protocol(SelectProtocol::new(&ip, port, self.encryption))?;
 
-        self.ws.write_message(Message::text(packet))?;
+        self.ws()?.write_message(Message::text(packet))?;
 
         Ok(())
     }
@@ -188,8 +747,11 @@ impl VoiceGateway {
     fn handle_heartbeat(&mut self) -> Result<()> {
         let packet = OpCode::heartbeat(Heartbeat::now())?;
         info!("Heartbeating... {:?}", packet);
-        self.ws.write_message(Message::text(packet))?;
+        self.ws()?.write_message(Message::text(packet))?;
         self.last_heartbeat = time::Instant::now();
+        self.next_heartbeat = self
+            .last_heartbeat
+            .checked_add(time::Duration::from_millis(self.heartbeat_interval));
         Ok(())
     }
 
@@ -199,7 +761,20 @@ impl VoiceGateway {
         if self.recent_acks.len() == 20 {
             self.recent_acks.pop_front();
         }
-        self.recent_acks.push_back(delta.as_secs_f64());
+        let rtt = delta.as_secs_f64();
+        self.recent_acks.push_back(rtt);
+        self.last_ack = Some(now);
+        if self
+            .latency_threshold
+            .map_or(false, |threshold| rtt > threshold)
+        {
+            warn!(
+                "[conn {}] Heartbeat latency spike: {:.0}ms",
+                self.id,
+                rtt * 1000.0
+            );
+            self.latency_spike = Some(rtt);
+        }
     }
 
     fn handle_session_description(&mut self, description: SessionDescription) -> Result<()> {
@@ -216,8 +791,8 @@ impl VoiceGateway {
             session_id: self.session_id.clone(),
             token: self.token.clone(),
         })?;
-        info!("Identifying: {:?}", packet);
-        self.ws.write_message(Message::text(packet))?;
+        info!("[conn {}] Identifying: {:?}", self.id, packet);
+        self.ws()?.write_message(Message::text(packet))?;
         Ok(())
     }
 
@@ -227,23 +802,42 @@ impl VoiceGateway {
             session_id: self.session_id.clone(),
             token: self.token.clone(),
         })?;
-        info!("Resuming: {:?}", packet);
-        self.ws.write_message(Message::text(packet))?;
+        info!("[conn {}] Resuming: {:?}", self.id, packet);
+        self.ws()?.write_message(Message::text(packet))?;
         Ok(())
     }
 
-    pub fn speaking(&mut self, flag: SpeakingType) -> Result<()> {
-        let flag = flag.bits();
+    pub fn speaking(&mut self, flag: SpeakingType, delay: u32) -> Result<()> {
+        self.speaking = (flag, delay);
+        self.send_speaking(flag, delay)
+    }
+
+    /// Clears the speaking indicator, e.g. while paused, keeping the state
+    /// that `resend_speaking` restores.
+    pub fn clear_speaking(&mut self) -> Result<()> {
+        self.send_speaking(SpeakingType::empty(), 0)
+    }
+
+    /// Sends the last speaking state again, e.g. after reconnecting.
+    pub fn resend_speaking(&mut self) -> Result<()> {
+        let (flag, delay) = self.speaking;
+        self.send_speaking(flag, delay)
+    }
+
+    fn send_speaking(&mut self, flag: SpeakingType, delay: u32) -> Result<()> {
         let packet = serde_json::json!({
             "op": 5,
             "d": {
-                "speaking": flag,
-                "delay": 0,
+                "speaking": flag.bits(),
+                "delay": delay,
                 "ssrc": self.ssrc
             }
         });
-        self.ws
-            .write_message(Message::text(serde_json::to_string(&packet)?))?;
+        // A detached gateway leaves speaking updates to the owner of the
+        // websocket.
+        if let Some(ws) = &mut self.ws {
+            ws.write_message(Message::text(serde_json::to_string(&packet)?))?;
+        }
         Ok(())
     }
 
@@ -251,28 +845,38 @@ impl VoiceGateway {
         let socket = match &self.socket {
             Some(s) => s,
             None => {
-                return Err(DiscordError::IoError(io::Error::new(
-                    io::ErrorKind::Other,
-                    "No socket found",
-                )))
+                return Err(DiscordError::DiscoveryFailed(
+                    "No UDP socket to discover the address with".to_string(),
+                ))
             }
         };
         let mut buff = [0_u8; 70];
         buff[0..2].copy_from_slice(&1u16.to_be_bytes());
         buff[2..4].copy_from_slice(&70u16.to_be_bytes());
         buff[4..8].copy_from_slice(&self.ssrc.to_be_bytes());
-        socket.send(&buff)?;
+        socket.send(&buff).map_err(|e| {
+            DiscordError::DiscoveryFailed(format!("Could not send the request: {}", e))
+        })?;
         let mut buff = [0_u8; 70];
-        socket.recv(&mut buff)?;
-        info!("UDP Packet Received: {:?}", &buff);
-        let ip_end = &buff[4..].iter().position(|&b| b == 0).ok_or_else(|| {
-            DiscordError::IoError(io::Error::new(io::ErrorKind::Other, "invalid IP found"))
+        let size = socket.recv(&mut buff).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => DiscordError::DiscoveryTimeout,
+            _ => DiscordError::DiscoveryFailed(format!("Could not receive the response: {}", e)),
         })?;
+        info!("UDP Packet Received: {:?}", &buff);
+        if size < buff.len() {
+            return Err(DiscordError::DiscoveryFailed(format!(
+                "Response too short: {} bytes",
+                size
+            )));
+        }
+        let invalid_ip = || DiscordError::DiscoveryFailed("Invalid IP in the response".to_string());
+        let ip_end = &buff[4..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(invalid_ip)?;
         let ip = {
             let ip_slice = &buff[4..4 + ip_end];
-            let as_str = std::str::from_utf8(ip_slice).map_err(|_| {
-                DiscordError::IoError(io::Error::new(io::ErrorKind::Other, "invalid IP found"))
-            })?;
+            let as_str = std::str::from_utf8(ip_slice).map_err(|_| invalid_ip())?;
             String::from(as_str)
         };
         let port = u16::from_be_bytes([buff[68], buff[69]]);
@@ -280,22 +884,68 @@ impl VoiceGateway {
     }
 }
 
-#[derive(Debug, Default)]
+type GatewayHandshakeError = HandshakeError<tungstenite::ClientHandshake<GatewayStream>>;
+
+fn handshake_error(err: GatewayHandshakeError) -> DiscordError {
+    match err {
+        HandshakeError::Failure(tungstenite::Error::Http(resp)) => {
+            DiscordError::WebsocketUpgradeRejected(format!("{:?}", resp))
+        }
+        HandshakeError::Failure(tungstenite::Error::Io(e)) => DiscordError::IoError(e),
+        err => DiscordError::WebsocketHandshakeFailed(err),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub(crate) struct VoiceGatewayBuilder {
     endpoint: Option<String>,
     user_id: Option<String>,
     server_id: Option<String>,
     session_id: Option<String>,
     token: Option<String>,
+    proxy: Option<Proxy>,
+    port: Option<u16>,
+    plain: bool,
+    timeout: Option<time::Duration>,
+    cancel: Option<ConnectCancel>,
+    dscp: Option<u8>,
 }
 
 #[allow(dead_code)]
 impl VoiceGatewayBuilder {
+    /// Sets the gateway host. With the `dev-gateway` feature the endpoint may
+    /// also be a `ws://host:port` or `wss://host:port` URL, e.g. to point the
+    /// connection at a local test server.
     pub(crate) fn endpoint(&mut self, endpoint: &str) -> &mut Self {
+        #[cfg(feature = "dev-gateway")]
+        let endpoint = self.dev_endpoint(endpoint);
         self.endpoint = Some(endpoint.to_string());
         self
     }
 
+    #[cfg(feature = "dev-gateway")]
+    fn dev_endpoint<'a>(&mut self, endpoint: &'a str) -> &'a str {
+        let (plain, address) = if let Some(address) = endpoint.strip_prefix("ws://") {
+            (true, address)
+        } else if let Some(address) = endpoint.strip_prefix("wss://") {
+            (false, address)
+        } else {
+            return endpoint;
+        };
+        self.plain(plain);
+        let address = address.trim_end_matches('/');
+        match address.rsplit_once(':') {
+            Some((host, port)) => match port.parse() {
+                Ok(port) => {
+                    self.port(port);
+                    host
+                }
+                Err(_) => address,
+            },
+            None => address,
+        }
+    }
+
     pub(crate) fn user_id(&mut self, user_id: &str) -> &mut Self {
         self.user_id = Some(user_id.to_string());
         self
@@ -316,7 +966,57 @@ impl VoiceGatewayBuilder {
         self
     }
 
-    pub(crate) fn connect(&mut self) -> Result<VoiceGateway> {
+    pub(crate) fn proxy(&mut self, proxy: Option<&str>) -> Result<&mut Self> {
+        self.proxy = proxy.map(str::parse).transpose()?;
+        Ok(self)
+    }
+
+    /// Marks the voice packets with a DSCP code point, e.g. 46 for expedited
+    /// forwarding, so that networks with QoS can prioritize them.
+    pub(crate) fn dscp(&mut self, dscp: Option<u8>) -> Result<&mut Self> {
+        if let Some(dscp) = dscp {
+            if dscp > MAX_DSCP {
+                return Err(DiscordError::InvalidArgument(format!(
+                    "DSCP must be between 0 and {}: {}",
+                    MAX_DSCP, dscp
+                )));
+            }
+        }
+        self.dscp = dscp;
+        Ok(self)
+    }
+
+    /// Bounds the time spent in `connect` and the `connection_flow` of the
+    /// returned gateway.
+    pub(crate) fn timeout(&mut self, timeout: Option<time::Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Lets `cancel` abort `connect` and the `connection_flow` of the
+    /// returned gateway.
+    pub(crate) fn cancel(&mut self, cancel: ConnectCancel) -> &mut Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Connects to `port` instead of the default gateway port.
+    /// Only available for tests and with the `dev-gateway` feature,
+    /// so that release builds always use TLS on port 443.
+    #[cfg(any(test, feature = "dev-gateway"))]
+    pub(crate) fn port(&mut self, port: u16) -> &mut Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Speaks plain `ws://` instead of `wss://`.
+    #[cfg(any(test, feature = "dev-gateway"))]
+    pub(crate) fn plain(&mut self, plain: bool) -> &mut Self {
+        self.plain = plain;
+        self
+    }
+
+    pub(crate) fn connect(&mut self, progress: &dyn Fn(&str)) -> Result<VoiceGateway> {
         let endpoint = self
             .endpoint
             .clone()
@@ -338,47 +1038,102 @@ impl VoiceGatewayBuilder {
             .clone()
             .ok_or_else(|| DiscordError::BuilderMissingRequiredField("token".to_string()))?;
 
-        let ws = {
-            // let connector = TlsConnector::new()?;
-            // let stream = connector.connect(&endpoint, stream)?;
-            // let (ws, resp) = create_gateway(&url, stream)?;
-            // info!("Get Response: {:?}", resp);
+        let deadline = self.timeout.map(|timeout| time::Instant::now() + timeout);
+        let cancel = self.cancel.as_ref();
+        let ws = or_abort(
+            self.connect_ws(&endpoint, deadline, progress),
+            deadline,
+            cancel,
+        )?;
+        info!("Esatblish Connection to {}", endpoint);
+
+        let mut gateway = VoiceGateway::new(Some(ws), self.clone());
+        gateway.endpoint = endpoint;
+        gateway.user_id = user_id;
+        gateway.server_id = server_id;
+        gateway.session_id = session_id;
+        gateway.token = token;
+        gateway.deadline = deadline;
+        Ok(gateway)
+    }
+
+    fn connect_ws(
+        &self,
+        endpoint: &str,
+        deadline: Option<time::Instant>,
+        progress: &dyn Fn(&str),
+    ) -> Result<WebSocket<GatewayStream>> {
+        let port = self.port.unwrap_or(GATEWAY_PORT);
+        let mut stream = match &self.proxy {
+            Some(proxy) => proxy.connect(endpoint, port, deadline)?,
+            None => connect_tcp(endpoint, (endpoint, port), deadline)?,
+        };
+        if let Some(cancel) = &self.cancel {
+            cancel.watch(&stream)?;
+        }
+        progress("tcp_connected");
+        let (stream, scheme) = if self.plain {
+            (GatewayStream::Plain(stream), "ws")
+        } else {
             let mut config = ClientConfig::new();
             config
                 .root_store
                 .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
             let config = Arc::new(config);
-            let domain = webpki::DNSNameRef::try_from_ascii_str(&endpoint)?;
-            let client = ClientSession::new(&config, domain);
-            let stream = TcpStream::connect((endpoint.as_str(), 443))?;
+            let domain = webpki::DNSNameRef::try_from_ascii_str(endpoint)?;
+            let mut client = ClientSession::new(&config, domain);
+            // Drive the TLS handshake to completion here so that a failure is
+            // reported as such instead of surfacing from the websocket upgrade.
+            while client.is_handshaking() {
+                client
+                    .complete_io(&mut stream)
+                    .map_err(DiscordError::TlsHandshakeFailed)?;
+            }
             let stream = StreamOwned::new(client, stream);
-            let url = format!("wss://{}/?v=4", endpoint);
-            info!("Connecting to {}", url);
-            let (ws, resp) = create_gateway(&url, stream)?;
-            info!("Get Response: {:?}", resp);
-            ws
+            (GatewayStream::Tls(Box::new(stream)), "wss")
         };
-        info!("Esatblish Connection to {}", endpoint);
-
-        let gateway = VoiceGateway {
-            endpoint,
-            user_id,
-            server_id,
-            session_id,
-            token,
-            ws,
-            heartbeat_interval: std::u64::MAX,
-            last_heartbeat: time::Instant::now(),
-            ssrc: 0,
-            port: 0,
-            encryption: EncryptionMode::default(),
-            endpoint_ip: String::new(),
-            socket: None,
-            recent_acks: VecDeque::with_capacity(20),
-            secret_key: [0; 32],
-            state: Arc::new(State::default()),
-            close_code: 0,
+        let url = if port == GATEWAY_PORT {
+            format!("{}://{}/?v=4", scheme, endpoint)
+        } else {
+            format!("{}://{}:{}/?v=4", scheme, endpoint, port)
         };
-        Ok(gateway)
+        info!("Connecting to {}", url);
+        let (ws, resp) = create_gateway(&url, stream).map_err(handshake_error)?;
+        info!("Get Response: {:?}", resp);
+        progress("ws_upgraded");
+        Ok(ws)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_ports_are_dropped() {
+        for endpoint in &[
+            "example.discord.media",
+            "example.discord.media:443",
+            "example.discord.media:80",
+            "wss://example.discord.media:443/",
+        ] {
+            assert_eq!(gateway_host(endpoint).unwrap(), "example.discord.media");
+        }
+    }
+
+    #[test]
+    fn malformed_endpoints_are_rejected() {
+        for endpoint in &[
+            "",
+            ":443",
+            "example.discord.media:",
+            "example.discord.media:https",
+            "example.discord.media:443:443",
+            "example.discord.media:70000",
+            "https://example.discord.media",
+            "example discord media",
+        ] {
+            assert!(gateway_host(endpoint).is_err(), "{}", endpoint);
+        }
     }
 }